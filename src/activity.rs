@@ -0,0 +1,183 @@
+//! Hellos in each of the last day's hours, for the sparklines on the web pages and the display
+
+use std::collections::VecDeque;
+
+use crate::history::Record;
+
+/// Number of hours kept
+pub const HOURS: usize = 24;
+const HOUR: u64 = 60 * 60;
+
+const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Activity {
+    /// Hours since the Unix epoch of the last bucket
+    hour: u64,
+    /// Hellos in each of the `HOURS` hours up to and including `hour`, oldest first
+    buckets: VecDeque<usize>,
+}
+
+/// A bar of the SVG sparkline
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bar {
+    pub x: usize,
+    pub y: usize,
+    pub height: usize,
+    pub count: usize,
+}
+
+impl Activity {
+    /// No hellos as of `now`, in seconds since the Unix epoch
+    pub fn new(now: u64) -> Self {
+        Activity {
+            hour: now / HOUR,
+            buckets: vec![0; HOURS].into(),
+        }
+    }
+
+    /// The hellos in `records` from the last `HOURS` hours before `now`
+    pub fn from_history(records: &[Record], now: u64) -> Self {
+        let mut activity = Activity::new(now);
+        for record in records {
+            activity.record(record.time);
+        }
+        activity
+    }
+
+    /// Count a hello at `time`. Hellos older than the buckets are ignored.
+    pub fn record(&mut self, time: u64) {
+        let hour = time / HOUR;
+        self.advance(hour);
+        let age = self.hour - hour;
+        if age < HOURS as u64 {
+            self.buckets[HOURS - 1 - age as usize] += 1;
+        }
+    }
+
+    /// Move the buckets on to end at `hour`
+    fn advance(&mut self, hour: u64) {
+        let hours = hour.saturating_sub(self.hour).min(HOURS as u64);
+        for _ in 0..hours {
+            self.buckets.pop_front();
+            self.buckets.push_back(0);
+        }
+        self.hour = self.hour.max(hour);
+    }
+
+    /// Hellos in each of the last `HOURS` hours as of `now`, oldest first
+    pub fn buckets(&self, now: u64) -> Vec<usize> {
+        let mut activity = self.clone();
+        activity.advance(now / HOUR);
+        activity.buckets.into()
+    }
+}
+
+/// `buckets` as a line of block characters, blank for none
+pub fn sparkline(buckets: &[usize]) -> String {
+    let max = buckets.iter().cloned().max().unwrap_or(0);
+    buckets
+        .iter()
+        .map(|&count| match count {
+            0 => ' ',
+            _ => BLOCKS[(count * BLOCKS.len()).div_ceil(max) - 1],
+        })
+        .collect()
+}
+
+/// Bars `width` wide, with a gap of one, reaching up to `height` for the busiest hour
+pub fn bars(buckets: &[usize], width: usize, height: usize) -> Vec<Bar> {
+    let max = buckets.iter().cloned().max().unwrap_or(0);
+    buckets
+        .iter()
+        .enumerate()
+        .map(|(i, &count)| {
+            let bar_height = match count {
+                0 => 0,
+                _ => (count * height).div_ceil(max),
+            };
+            Bar {
+                x: i * (width + 1),
+                y: height - bar_height,
+                height: bar_height,
+                count,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hellos::Source;
+
+    // 2019-01-22T09:30:00Z
+    const NOW: u64 = 1_548_149_400;
+
+    #[test]
+    fn test_record() {
+        let mut activity = Activity::new(NOW);
+        activity.record(NOW);
+        activity.record(NOW - 2 * HOUR);
+        activity.record(NOW - 24 * HOUR);
+        let buckets = activity.buckets(NOW);
+        assert_eq!(buckets.len(), HOURS);
+        assert_eq!(&buckets[HOURS - 3..], &[1, 0, 1]);
+        assert_eq!(buckets.iter().sum::<usize>(), 2);
+
+        // A hello in a later hour moves the buckets on
+        activity.record(NOW + HOUR);
+        assert_eq!(&activity.buckets(NOW + HOUR)[HOURS - 4..], &[1, 0, 1, 1]);
+        // As does time passing
+        assert_eq!(&activity.buckets(NOW + 3 * HOUR)[HOURS - 4..], &[1, 1, 0, 0]);
+        assert_eq!(activity.buckets(NOW + 48 * HOUR), vec![0; HOURS]);
+    }
+
+    #[test]
+    fn test_from_history() {
+        let records = [NOW - 30 * HOUR, NOW - HOUR, NOW]
+            .iter()
+            .map(|&time| Record {
+                time,
+                source: Source::Web,
+                address: None,
+            })
+            .collect::<Vec<_>>();
+        let activity = Activity::from_history(&records, NOW);
+        assert_eq!(&activity.buckets(NOW)[HOURS - 2..], &[1, 1]);
+        assert_eq!(activity.buckets(NOW).iter().sum::<usize>(), 2);
+    }
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[0, 1, 4, 8, 3]), " ▁▄█▃");
+        assert_eq!(sparkline(&[0, 0]), "  ");
+    }
+
+    #[test]
+    fn test_bars() {
+        assert_eq!(
+            bars(&[0, 2, 4], 3, 10),
+            vec![
+                Bar {
+                    x: 0,
+                    y: 10,
+                    height: 0,
+                    count: 0
+                },
+                Bar {
+                    x: 4,
+                    y: 5,
+                    height: 5,
+                    count: 2
+                },
+                Bar {
+                    x: 8,
+                    y: 0,
+                    height: 10,
+                    count: 4
+                },
+            ]
+        );
+    }
+}
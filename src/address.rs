@@ -0,0 +1,175 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use systemstat::{Platform, System};
+
+/// Interfaces tried in order: wifi, then wired, then the USB gadget
+pub const DEFAULT_INTERFACES: &str = "wlan,eth,usb0";
+
+/// An address of one of the network interfaces
+#[derive(Debug, Clone, PartialEq)]
+pub struct Address {
+    pub interface: String,
+    pub ip: IpAddr,
+}
+
+/// How the badge is reached in the URL
+#[derive(Debug, Clone, PartialEq)]
+pub enum Host {
+    Ip(IpAddr),
+    /// Hostname, E.g. badge.local
+    Name(String),
+}
+
+impl Host {
+    pub fn url(&self) -> String {
+        format!("http://{}/", self)
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Host::Ip(IpAddr::V4(ip)) => ip.fmt(f),
+            Host::Ip(IpAddr::V6(ip)) => write!(f, "[{}]", ip),
+            Host::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// Find the address of the most preferred interface that has one, IPv4 only unless `ipv6` is set
+pub fn discover(system: &System, preference: &[String], ipv6: bool) -> Option<Address> {
+    let interfaces = system
+        .networks()
+        .ok()?
+        .into_iter()
+        .map(|(name, network)| {
+            let ips = network
+                .addrs
+                .into_iter()
+                .filter_map(|addr| match addr.addr {
+                    systemstat::IpAddr::V4(ip) => Some(IpAddr::V4(ip)),
+                    systemstat::IpAddr::V6(ip) => Some(IpAddr::V6(ip)),
+                    _ => None,
+                })
+                .collect();
+            (name, ips)
+        })
+        .collect::<Vec<_>>();
+
+    select(&interfaces, preference, ipv6)
+}
+
+/// Choose an address from `interfaces`.
+///
+/// Each entry in `preference` matches interfaces whose names start with it, so "wlan" matches
+/// wlan0 and wlan1. IPv4 addresses are used in preference to IPv6 ones on the same interface,
+/// and only global IPv6 addresses are used, if `ipv6` is set.
+pub fn select(
+    interfaces: &[(String, Vec<IpAddr>)],
+    preference: &[String],
+    ipv6: bool,
+) -> Option<Address> {
+    preference.iter().find_map(|prefix| {
+        interfaces
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix.as_str()))
+            .find_map(|(name, ips)| {
+                let ipv4 = ips.iter().find(|ip| match ip {
+                    IpAddr::V4(ip) => usable_ipv4(ip),
+                    IpAddr::V6(_) => false,
+                });
+                let ipv6 = ips.iter().find(|ip| match ip {
+                    IpAddr::V4(_) => false,
+                    IpAddr::V6(ip) => ipv6 && global_ipv6(ip),
+                });
+
+                ipv4.or(ipv6).map(|&ip| Address {
+                    interface: name.clone(),
+                    ip,
+                })
+            })
+    })
+}
+
+fn usable_ipv4(ip: &Ipv4Addr) -> bool {
+    !(ip.is_loopback() || ip.is_link_local() || ip.is_unspecified())
+}
+
+fn global_ipv6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    let link_local = first & 0xffc0 == 0xfe80;
+    let unique_local = first & 0xfe00 == 0xfc00;
+
+    !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || link_local || unique_local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn preference() -> Vec<String> {
+        DEFAULT_INTERFACES.split(',').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_select_by_preference() {
+        let interfaces = vec![
+            ("eth0".to_string(), vec![ip("192.168.1.20")]),
+            ("lo".to_string(), vec![ip("127.0.0.1")]),
+            ("usb0".to_string(), vec![ip("10.55.0.1")]),
+            ("wlan0".to_string(), vec![ip("fe80::1"), ip("10.0.0.18")]),
+        ];
+
+        assert_eq!(
+            select(&interfaces, &preference(), true),
+            Some(Address {
+                interface: "wlan0".to_string(),
+                ip: ip("10.0.0.18")
+            })
+        );
+        assert_eq!(
+            select(&interfaces[..3], &preference(), true).map(|address| address.interface),
+            Some("eth0".to_string())
+        );
+        assert_eq!(select(&interfaces[1..2], &preference(), true), None);
+    }
+
+    #[test]
+    fn test_select_global_ipv6() {
+        let interfaces = vec![(
+            "wlan0".to_string(),
+            vec![ip("fe80::1"), ip("fd00::5"), ip("2001:db8::18")],
+        )];
+
+        assert_eq!(
+            select(&interfaces, &preference(), true).map(|address| address.ip),
+            Some(ip("2001:db8::18"))
+        );
+        // Not when the server is only listening on IPv4
+        assert_eq!(select(&interfaces, &preference(), false), None);
+
+        let both = vec![
+            ("wlan0".to_string(), vec![ip("2001:db8::18")]),
+            ("eth0".to_string(), vec![ip("192.168.1.20")]),
+        ];
+        assert_eq!(
+            select(&both, &preference(), false).map(|address| address.interface),
+            Some("eth0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_url() {
+        assert_eq!(Host::Ip(ip("10.0.0.18")).url(), "http://10.0.0.18/");
+        assert_eq!(Host::Ip(ip("2001:db8::18")).url(), "http://[2001:db8::18]/");
+        assert_eq!(
+            Host::Name("badge.local".to_string()).url(),
+            "http://badge.local/"
+        );
+    }
+}
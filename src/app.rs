@@ -1,193 +1,444 @@
 use nix::sys::utsname::UtsName;
 use std::collections::HashMap;
-use std::fs;
-use std::io;
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::net::IpAddr;
-use std::path::Path;
-use std::str::FromStr;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::{Instant, SystemTime};
 
 use systemstat::{Memory, System, Platform};
 use nix::sys::utsname::uname;
+use log::info;
 use rs_release::get_os_release;
 
-use crate::system::Uptime;
+use crate::activity::{self, Activity};
+use crate::address::{self, Address, Host};
+use crate::config::Config;
+use crate::display::{self, DisplayState, Screen};
+use crate::hellos::{HelloCounts, Source};
+use crate::history::{History, Record};
+use crate::leaderboard::Leaderboard;
+use crate::peers::Peers;
+use crate::system::{self, Battery, DiskUsage, LoadAverage, NetworkTraffic, Uptime, WifiQuality};
+use crate::temperature::{self, Celsius};
+use crate::wear::RefreshCount;
+use crate::webhooks::Hello;
+
+/// Files the state is saved in, in the working directory
+pub const HI_COUNT_PATH: &str = "hi_count.txt";
+pub const REFRESH_COUNT_PATH: &str = "refresh_count.txt";
+pub const PEERS_PATH: &str = "peers.txt";
+pub const WEBHOOK_QUEUE_PATH: &str = "webhook_queue.jsonl";
+pub const HISTORY_PATH: &str = "hello_history.jsonl";
 
 pub struct State {
-    hi_count: usize,
-    interface: String,
-    pub ip: Option<systemstat::Ipv4Addr>,
+    hello_counts: HelloCounts,
+    /// Every hello that's been counted
+    pub history: History,
+    /// Hellos in each of the last few hours
+    pub activity: Activity,
+    /// Settings that can be reloaded, see `--config`
+    pub config: Config,
+    pub address: Option<Address>,
+    /// Whether the server can be reached over IPv6, IPv6 addresses are only shown if so
+    ipv6: bool,
     pub os_name: String,
     pub uname: UtsName,
     pub memory: Option<Memory>,
     pub uptime: Uptime,
+    pub soc_temperature: Option<Celsius>,
+    pub load: Option<LoadAverage>,
+    pub disk: Option<DiskUsage>,
+    pub network: Option<NetworkTraffic>,
+    pub wifi: Option<WifiQuality>,
+    pub battery: Option<Battery>,
+    battery_path: Option<PathBuf>,
+    /// Levels shown in the status icons, see `display::level`
+    wifi_level: Option<u8>,
+    battery_level: Option<u8>,
     pub hellos: HashMap<IpAddr, Instant>,
-    max_age: Duration,
+    /// Hellos not counted because they were from an address seen recently
+    pub dedup_rejections: u64,
+    pub refreshes: RefreshCount,
+    /// Other badges we've exchanged hellos with
+    pub peers: Peers,
+    /// Hello counts of the team, see `--team`
+    pub leaderboard: Leaderboard,
+    pub screen: Screen,
+    /// Shown on the display instead of the screen, set over MQTT
+    pub message: Option<String>,
+    /// Told about each hello that's counted, for the webhooks
+    pub hello_tx: Option<Sender<Hello>>,
+    /// Told when the hello count changes, so it's saved
+    pub changed_tx: Option<Sender<()>>,
+    /// Set to ask the display thread for a full refresh
+    pub force_refresh: bool,
     system: System,
 }
 
 impl State {
-    pub fn new(
-        hi_count: usize,
-        interface: String,
-        max_age: Duration
-    ) -> Self {
+    pub fn new(hello_counts: HelloCounts, config: Config, battery_path: Option<PathBuf>) -> Self {
         let system = System::new();
         let os_name = get_os_release()
             .ok()
             .and_then(|mut hash| hash.remove("NAME"))
             .unwrap_or_else(|| "Unknown".to_string());
 
-        State {
-            hi_count,
-            ip: get_interface_ip(&system, &interface),
+        let mut state = State {
+            hello_counts,
+            history: History::default(),
+            activity: Activity::new(system::unix_time(SystemTime::now())),
+            address: None,
+            ipv6: true,
             os_name,
             uname: uname(),
-            memory: system.memory().ok(),
-            uptime: system
-                .uptime()
-                .ok()
-                .map(|uptime| Uptime::new(uptime.as_secs()))
-                .unwrap_or_default(),
+            memory: None,
+            uptime: Uptime::default(),
+            soc_temperature: None,
+            load: None,
+            disk: None,
+            network: None,
+            wifi: None,
+            battery: None,
+            battery_path,
+            wifi_level: None,
+            battery_level: None,
             hellos: HashMap::new(),
-            max_age,
+            dedup_rejections: 0,
+            refreshes: RefreshCount::default(),
+            peers: Peers::default(),
+            leaderboard: Leaderboard::default(),
+            hello_tx: None,
+            changed_tx: None,
+            screen: Screen::Hello,
+            message: None,
+            force_refresh: false,
             system,
-            interface,
-        }
+            config,
+        };
+        state.refresh();
+        state
     }
 
     pub fn load(
         save_path: &Path,
-        interface: String,
-        max_age: Duration,
+        config: Config,
+        battery_path: Option<PathBuf>,
     ) -> io::Result<Self> {
-        let hi_count = match Self::load_hi_count(save_path) {
-            Ok(hi_count) => hi_count,
-            Err(err) => match err.kind() {
-                io::ErrorKind::NotFound => 0,
-                _ => return Err(err),
-            },
-        };
+        let hello_counts = HelloCounts::load(save_path)?;
 
-        println!("Loaded state with hi count {}", hi_count);
-        Ok(Self::new(
-            hi_count, interface, max_age,
-        ))
+        info!("Loaded state with hi count {}", hello_counts.total());
+        Ok(Self::new(hello_counts, config, battery_path))
     }
 
-    pub fn inc_hi_count(&mut self, from: IpAddr) {
+    pub fn inc_hi_count(&mut self, from: IpAddr, source: Source) {
         let now = Instant::now();
 
         match self.hellos.get(&from).map(|instant| now - *instant) {
-            Some(age) if age > self.max_age => self.inc_hi_count_impl(from, now),
-            None => self.inc_hi_count_impl(from, now),
-            _ => (),
+            Some(age) if age > self.config.dedup_window => {
+                self.inc_hi_count_impl(from, source, now)
+            }
+            None => self.inc_hi_count_impl(from, source, now),
+            _ => self.dedup_rejections += 1,
         }
     }
 
-    fn inc_hi_count_impl(&mut self, from: IpAddr, now: Instant) {
+    /// Count a hello from someone in front of the badge. These aren't deduplicated.
+    pub fn inc_hi_count_in_person(&mut self) {
+        self.hello_counts.inc(Source::InPerson);
+        self.record_hello(Source::InPerson, None);
+        self.notify_hello(Source::InPerson);
+    }
+
+    fn inc_hi_count_impl(&mut self, from: IpAddr, source: Source, now: Instant) {
         self.hellos.insert(from, now);
-        self.hi_count += 1;
+        self.hello_counts.inc(source);
+        self.record_hello(source, Some(from));
+        self.notify_hello(source);
+    }
+
+    fn record_hello(&mut self, source: Source, address: Option<IpAddr>) {
+        let time = system::unix_time(SystemTime::now());
+        self.history.record(Record {
+            time,
+            source,
+            address,
+        });
+        self.activity.record(time);
+    }
+
+    /// Replace the history, E.g. with the saved one, and the activity with that from it
+    pub fn set_history(&mut self, history: History) {
+        self.activity =
+            Activity::from_history(history.records(), system::unix_time(SystemTime::now()));
+        self.history = history;
+    }
+
+    /// Replace the counts, E.g. with `lca2019 count set`. Not sent to the webhooks. The counts are
+    /// the source of truth and the history never has more hellos than them, so lowering the count
+    /// removes the latest hellos from the history and a reset clears it.
+    pub fn set_hello_counts(&mut self, hello_counts: HelloCounts) {
+        self.history.truncate(hello_counts.total());
+        self.activity = Activity::from_history(
+            self.history.records(),
+            system::unix_time(SystemTime::now()),
+        );
+        self.hello_counts = hello_counts;
+        self.notify_changed();
+    }
+
+    fn notify_changed(&self) {
+        if let Some(ref changed_tx) = self.changed_tx {
+            let _ = changed_tx.send(());
+        }
+    }
+
+    fn notify_hello(&self, source: Source) {
+        self.notify_changed();
+        if let Some(ref hello_tx) = self.hello_tx {
+            let hi_count = self.hi_count();
+            // Only fails if the webhooks thread has stopped
+            let _ = hello_tx.send(Hello { source, hi_count });
+        }
     }
 
     pub fn hi_count(&self) -> usize {
-        self.hi_count
+        self.hello_counts.total()
     }
 
-    fn load_hi_count(path: &Path) -> io::Result<usize> {
-        let string_count = std::fs::read_to_string(path)?;
-        usize::from_str(string_count.trim()).or(Ok(0))
+    pub fn hello_counts(&self) -> &HelloCounts {
+        &self.hello_counts
     }
 
-    pub fn save_hi_count(&self, path: &Path) -> io::Result<()> {
-        let tmp_filename = path
-            .file_name()
-            .map(|file_name| {
-                let mut tmp_name = file_name.to_os_string();
-                tmp_name.push(".tmp");
-                tmp_name
-            })
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "file_name is None"))?;
-        let tmp_path = path.with_file_name(tmp_filename);
-        fs::write(&tmp_path, self.hi_count().to_string())?;
+    /// What the display should show
+    pub fn display_state(&self) -> DisplayState {
+        DisplayState {
+            screen: self.screen,
+            hi_count: self.hi_count(),
+            sources: if self.config.show_sources {
+                Some(self.hello_counts.summary())
+            } else {
+                None
+            },
+            host: self.host(self.config.show_hostname),
+            battery: self.battery_level,
+            wifi: self.wifi_level,
+            peers: self.peers.len(),
+            leaderboard: if self.screen == Screen::Leaderboard {
+                self.leaderboard.standings(self.hi_count(), Instant::now())
+            } else {
+                Vec::new()
+            },
+            message: self.message.clone(),
+            activity: self.activity_buckets()[activity::HOURS - display::CHART_HOURS..].to_vec(),
+        }
+    }
 
-        std::fs::rename(tmp_path, path)?;
+    /// Hellos in each of the last `activity::HOURS` hours, oldest first
+    pub fn activity_buckets(&self) -> Vec<usize> {
+        self.activity.buckets(system::unix_time(SystemTime::now()))
+    }
 
-        println!("Saved hi count");
+    /// How to reach the badge, by hostname instead of the IP if `hostname` is set
+    pub fn host(&self, hostname: bool) -> Option<Host> {
+        self.address.as_ref().map(|address| {
+            if hostname {
+                Host::Name(format!("{}.local", self.uname.nodename()))
+            } else {
+                Host::Ip(address.ip)
+            }
+        })
+    }
 
-        Ok(())
+    pub fn set_ipv6(&mut self, ipv6: bool) {
+        self.ipv6 = ipv6;
+        self.address = address::discover(&self.system, &self.config.interfaces, ipv6);
     }
 
     pub fn refresh(&mut self) {
-        self.ip = get_interface_ip(&self.system, &self.interface);
+        self.address = address::discover(&self.system, &self.config.interfaces, self.ipv6);
         self.memory = self.system.memory().ok();
         self.uptime = self.system
             .uptime()
             .ok()
             .map(|uptime| Uptime::new(uptime.as_secs()))
             .unwrap_or_default();
+        self.soc_temperature =
+            temperature::read_sensor(Path::new(temperature::SOC_SENSOR)).ok();
+        self.load = LoadAverage::read().ok();
+        self.disk = self.system.mount_at("/").ok().map(DiskUsage::from);
+        // Without an address still show the wifi signal, it's probably why there's no address
+        let interface = self
+            .address
+            .as_ref()
+            .map(|address| address.interface.as_str())
+            .unwrap_or("wlan0");
+        self.network = NetworkTraffic::read(interface).ok();
+        self.wifi = WifiQuality::read(interface).ok();
+        self.battery = self
+            .battery_path
+            .as_ref()
+            .and_then(|path| Battery::read(path).ok());
+        self.wifi_level = self.wifi.map(|wifi| display::level(wifi.0, self.wifi_level));
+        self.battery_level = self
+            .battery
+            .map(|battery| display::level(battery.0, self.battery_level));
     }
 }
 
-fn get_interface_ip(system: &System, interface: &str) -> Option<systemstat::Ipv4Addr> {
-    if let Some(network) = system
-        .networks()
-        .ok()
-        .and_then(|mut info| info.remove(interface))
-    {
-        for addr in network.addrs {
-            match addr.addr {
-                systemstat::IpAddr::V4(addr) => return Some(addr),
-                _ => (),
-            }
-        }
-    }
+/// Write `contents` to a temporary file, then rename it over `path`. The file and directory are
+/// synced so the new contents survive the power being pulled.
+pub fn write_file_atomic<C: AsRef<[u8]>>(path: &Path, contents: C) -> io::Result<()> {
+    let tmp_filename = path
+        .file_name()
+        .map(|file_name| {
+            let mut tmp_name = file_name.to_os_string();
+            tmp_name.push(".tmp");
+            tmp_name
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "file_name is None"))?;
+    let tmp_path = path.with_file_name(tmp_filename);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents.as_ref())?;
+    file.sync_all()?;
 
-    None
+    fs::rename(tmp_path, path)?;
+    // The rename is only durable once the directory entry is
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use nix::sys::utsname::uname;
     use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::mpsc;
     use std::thread;
+    use std::time::Duration;
 
-    fn test_state(ip: Ipv4Addr) -> State {
+    fn test_state() -> State {
         State::new(
-            0,
-            Some(ip),
-            "Test OS".to_string(),
-            uname(),
+            HelloCounts::default(),
+            Config {
+                interfaces: vec!["lo".to_string()],
+                dedup_window: Duration::from_millis(100),
+                show_hostname: false,
+                show_sources: false,
+            },
             None,
-            Uptime::default(),
-            Duration::from_millis(100),
         )
     }
 
     #[test]
     fn test_inc_hello_dedup() {
         let localhost = Ipv4Addr::new(127, 0, 0, 1);
-        let mut state = test_state(localhost);
+        let mut state = test_state();
 
-        state.inc_hi_count(IpAddr::V4(localhost));
-        state.inc_hi_count(IpAddr::V4(localhost));
-        state.inc_hi_count(IpAddr::V4(localhost));
+        state.inc_hi_count(IpAddr::V4(localhost), Source::Api);
+        state.inc_hi_count(IpAddr::V4(localhost), Source::Api);
+        state.inc_hi_count(IpAddr::V4(localhost), Source::Web);
 
         assert_eq!(state.hi_count(), 1);
+        assert_eq!(state.dedup_rejections, 2);
     }
 
     #[test]
     fn test_inc_hello_expiration() {
         let localhost = Ipv4Addr::new(127, 0, 0, 1);
-        let mut state = test_state(localhost);
+        let mut state = test_state();
 
-        state.inc_hi_count(IpAddr::V4(localhost));
-        state.inc_hi_count(IpAddr::V4(localhost));
+        state.inc_hi_count(IpAddr::V4(localhost), Source::Api);
+        state.inc_hi_count(IpAddr::V4(localhost), Source::Api);
         thread::sleep(Duration::from_millis(101));
-        state.inc_hi_count(IpAddr::V4(localhost));
-        state.inc_hi_count(IpAddr::V4(localhost));
+        state.inc_hi_count(IpAddr::V4(localhost), Source::Api);
+        state.inc_hi_count(IpAddr::V4(localhost), Source::Api);
 
         assert_eq!(state.hi_count(), 2);
     }
+
+    #[test]
+    fn test_inc_hello_by_source() {
+        let mut state = test_state();
+
+        state.inc_hi_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), Source::Web);
+        state.inc_hi_count(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), Source::Script);
+        state.inc_hi_count_in_person();
+        state.inc_hi_count_in_person();
+
+        let counts = state.hello_counts();
+        assert_eq!(counts.total(), 4);
+        let sources = state
+            .history
+            .records()
+            .iter()
+            .map(|record| (record.source, record.address))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sources,
+            vec![
+                (Source::Web, Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))),
+                (Source::Script, Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)))),
+                (Source::InPerson, None),
+                (Source::InPerson, None),
+            ]
+        );
+        assert_eq!(counts.get(Source::Web), 1);
+        assert_eq!(counts.get(Source::Api), 0);
+        assert_eq!(counts.get(Source::InPerson), 2);
+        assert_eq!(counts.get(Source::Script), 1);
+        assert_eq!(state.activity_buckets().iter().sum::<usize>(), 4);
+    }
+
+    #[test]
+    fn test_set_hello_counts_truncates_history() {
+        let mut state = test_state();
+        for _ in 0..3 {
+            state.inc_hi_count_in_person();
+        }
+
+        // Raising the count leaves the history alone
+        state.set_hello_counts(HelloCounts::with_total(10));
+        assert_eq!(state.history.records().len(), 3);
+
+        state.set_hello_counts(HelloCounts::with_total(2));
+        assert_eq!(state.history.records().len(), 2);
+        assert_eq!(state.activity_buckets().iter().sum::<usize>(), 2);
+
+        state.set_hello_counts(HelloCounts::default());
+        assert!(state.history.records().is_empty());
+        assert_eq!(state.activity_buckets().iter().sum::<usize>(), 0);
+        assert_eq!(state.history.truncations(), 2);
+    }
+
+    #[test]
+    fn test_counted_hellos_are_sent() {
+        let localhost = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut state = test_state();
+        let (hello_tx, hello_rx) = mpsc::channel();
+        state.hello_tx = Some(hello_tx);
+
+        state.inc_hi_count(localhost, Source::Api);
+        // Deduplicated hellos aren't counted, so aren't sent
+        state.inc_hi_count(localhost, Source::Api);
+        state.inc_hi_count_in_person();
+
+        let hellos = hello_rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(
+            hellos,
+            vec![
+                Hello {
+                    source: Source::Api,
+                    hi_count: 1
+                },
+                Hello {
+                    source: Source::InPerson,
+                    hi_count: 2
+                },
+            ]
+        );
+    }
 }
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::io;
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use linux_embedded_hal::sysfs_gpio::Direction;
+use log::{info, warn};
+use linux_embedded_hal::Pin;
+
+use crate::app::State;
+use crate::display::Screen;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const DEBOUNCE: Duration = Duration::from_millis(30);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Action {
+    NextScreen,
+    Contact,
+    Leaderboard,
+    Refresh,
+    Hello,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "next-screen" => Ok(Action::NextScreen),
+            "contact" => Ok(Action::Contact),
+            "leaderboard" => Ok(Action::Leaderboard),
+            "refresh" => Ok(Action::Refresh),
+            "hello" => Ok(Action::Hello),
+            _ => Err(format!(
+                "unknown button action '{}', expected next-screen, contact, leaderboard, refresh, or hello",
+                s
+            )),
+        }
+    }
+}
+
+/// A button on a GPIO pin, in the form `PIN=SHORT[,LONG]`, E.g. `5=next-screen,refresh`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ButtonConfig {
+    pub pin: u64,
+    pub short: Action,
+    pub long: Option<Action>,
+}
+
+impl FromStr for ButtonConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let pin = parts
+            .next()
+            .and_then(|pin| u64::from_str(pin).ok())
+            .ok_or_else(|| format!("invalid button pin in '{}'", s))?;
+        let mut actions = parts
+            .next()
+            .ok_or_else(|| format!("button '{}' has no action", s))?
+            .split(',');
+        let short = Action::from_str(actions.next().unwrap_or(""))?;
+        let long = actions.next().map(Action::from_str).transpose()?;
+
+        Ok(ButtonConfig { pin, short, long })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Press {
+    Short,
+    Long,
+}
+
+/// Turns noisy samples of a button into presses
+#[derive(Debug)]
+pub struct Debouncer {
+    /// None for buttons without a long press action, whose presses are all short
+    long_press: Option<Duration>,
+    pressed: bool,
+    candidate: bool,
+    candidate_since: Option<Instant>,
+    pressed_at: Option<Instant>,
+    long_sent: bool,
+}
+
+impl Debouncer {
+    pub fn new(long_press: Option<Duration>) -> Self {
+        Debouncer {
+            long_press,
+            pressed: false,
+            candidate: false,
+            candidate_since: None,
+            pressed_at: None,
+            long_sent: false,
+        }
+    }
+
+    /// Feed in a sample taken at `now`. Long presses are reported while the button is still held.
+    pub fn update(&mut self, pressed: bool, now: Instant) -> Option<Press> {
+        if pressed != self.candidate {
+            self.candidate = pressed;
+            self.candidate_since = Some(now);
+        }
+
+        let settled = self
+            .candidate_since
+            .map(|since| now.duration_since(since) >= DEBOUNCE)
+            .unwrap_or(false);
+        if settled && self.candidate != self.pressed {
+            self.pressed = self.candidate;
+            if self.pressed {
+                self.pressed_at = Some(now);
+                self.long_sent = false;
+            } else if !self.long_sent {
+                return Some(Press::Short);
+            }
+        }
+
+        match (self.pressed_at, self.long_press) {
+            (Some(pressed_at), Some(long_press))
+                if self.pressed && !self.long_sent && now - pressed_at >= long_press =>
+            {
+                self.long_sent = true;
+                Some(Press::Long)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Where button levels come from
+pub trait PinSource {
+    fn is_pressed(&mut self, pin: u64) -> io::Result<bool>;
+}
+
+/// Buttons on sysfs GPIO pins
+pub struct SysfsPins {
+    pins: HashMap<u64, Pin>,
+    active_high: bool,
+}
+
+impl SysfsPins {
+    pub fn new(pin_numbers: &[u64], active_high: bool) -> io::Result<Self> {
+        let mut pins = HashMap::new();
+        for &number in pin_numbers {
+            let pin = Pin::new(number);
+            pin.export().map_err(gpio_error)?;
+            while !pin.is_exported() {}
+            pin.set_direction(Direction::In).map_err(gpio_error)?;
+            pins.insert(number, pin);
+        }
+
+        Ok(SysfsPins { pins, active_high })
+    }
+}
+
+impl PinSource for SysfsPins {
+    fn is_pressed(&mut self, pin: u64) -> io::Result<bool> {
+        let value = self
+            .pins
+            .get(&pin)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "pin not configured"))?
+            .get_value()
+            .map_err(gpio_error)?;
+
+        Ok((value != 0) == self.active_high)
+    }
+}
+
+fn gpio_error<E: std::fmt::Debug>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("GPIO error: {:?}", err))
+}
+
+pub struct Buttons<P: PinSource> {
+    source: P,
+    buttons: Vec<(ButtonConfig, Debouncer)>,
+}
+
+impl<P: PinSource> Buttons<P> {
+    pub fn new(source: P, configs: &[ButtonConfig], long_press: Duration) -> Self {
+        let buttons = configs
+            .iter()
+            .map(|config| {
+                let long_press = config.long.map(|_| long_press);
+                (config.clone(), Debouncer::new(long_press))
+            })
+            .collect();
+
+        Buttons { source, buttons }
+    }
+
+    /// Sample each button, returning the actions of any presses
+    pub fn poll(&mut self, now: Instant) -> Vec<Action> {
+        let mut actions = Vec::new();
+
+        for (config, debouncer) in self.buttons.iter_mut() {
+            let pressed = match self.source.is_pressed(config.pin) {
+                Ok(pressed) => pressed,
+                Err(err) => {
+                    warn!("unable to read button {}: {}", config.pin, err);
+                    continue;
+                }
+            };
+
+            match debouncer.update(pressed, now) {
+                Some(Press::Short) => actions.push(config.short),
+                Some(Press::Long) => actions.extend(config.long),
+                None => (),
+            }
+        }
+
+        actions
+    }
+
+    /// Poll the buttons forever, applying their actions to `state`
+    pub fn run(mut self, state: Arc<RwLock<State>>, wake: Sender<()>) {
+        loop {
+            for action in self.poll(Instant::now()) {
+                info!("Button action: {:?}", action);
+                apply(action, &state);
+                let _ = wake.send(());
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+pub fn apply(action: Action, state: &RwLock<State>) {
+    let mut state = state.write().expect("poisioned");
+    match action {
+        Action::NextScreen => state.screen = state.screen.next(),
+        Action::Contact => state.screen = Screen::Contact,
+        Action::Leaderboard => state.screen = Screen::Leaderboard,
+        Action::Refresh => state.force_refresh = true,
+        Action::Hello => state.inc_hi_count_in_person(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct FakePins {
+        pressed: HashMap<u64, bool>,
+    }
+
+    impl PinSource for FakePins {
+        fn is_pressed(&mut self, pin: u64) -> io::Result<bool> {
+            Ok(self.pressed.get(&pin).cloned().unwrap_or(false))
+        }
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_parse_button_config() {
+        assert_eq!(
+            ButtonConfig::from_str("5=next-screen,refresh"),
+            Ok(ButtonConfig {
+                pin: 5,
+                short: Action::NextScreen,
+                long: Some(Action::Refresh),
+            })
+        );
+        assert_eq!(
+            ButtonConfig::from_str("6=hello").map(|config| config.long),
+            Ok(None)
+        );
+        assert!(ButtonConfig::from_str("6").is_err());
+        assert!(ButtonConfig::from_str("x=hello").is_err());
+        assert!(ButtonConfig::from_str("6=dance").is_err());
+    }
+
+    #[test]
+    fn test_debounce_ignores_bounces() {
+        let mut debouncer = Debouncer::new(Some(ms(1000)));
+        let start = Instant::now();
+
+        // Contact bounce shorter than the debounce time
+        let samples = [(0, true), (5, false), (10, true), (15, false), (100, false)];
+        for &(offset, level) in samples.iter() {
+            assert_eq!(debouncer.update(level, start + ms(offset)), None);
+        }
+    }
+
+    #[test]
+    fn test_short_press() {
+        let mut debouncer = Debouncer::new(Some(ms(1000)));
+        let start = Instant::now();
+
+        assert_eq!(debouncer.update(true, start), None);
+        assert_eq!(debouncer.update(true, start + ms(40)), None);
+        assert_eq!(debouncer.update(false, start + ms(200)), None);
+        assert_eq!(
+            debouncer.update(false, start + ms(240)),
+            Some(Press::Short)
+        );
+        assert_eq!(debouncer.update(false, start + ms(300)), None);
+    }
+
+    #[test]
+    fn test_long_press() {
+        let mut debouncer = Debouncer::new(Some(ms(1000)));
+        let start = Instant::now();
+
+        assert_eq!(debouncer.update(true, start), None);
+        assert_eq!(debouncer.update(true, start + ms(40)), None);
+        assert_eq!(debouncer.update(true, start + ms(1040)), Some(Press::Long));
+        assert_eq!(debouncer.update(true, start + ms(1500)), None);
+        assert_eq!(debouncer.update(false, start + ms(1600)), None);
+        // No short press on release after a long one
+        assert_eq!(debouncer.update(false, start + ms(1700)), None);
+    }
+
+    #[test]
+    fn test_held_without_long_action() {
+        let mut debouncer = Debouncer::new(None);
+        let start = Instant::now();
+
+        assert_eq!(debouncer.update(true, start), None);
+        assert_eq!(debouncer.update(true, start + ms(40)), None);
+        assert_eq!(debouncer.update(true, start + ms(5000)), None);
+        assert_eq!(debouncer.update(false, start + ms(5100)), None);
+        assert_eq!(
+            debouncer.update(false, start + ms(5140)),
+            Some(Press::Short)
+        );
+    }
+
+    #[test]
+    fn test_buttons_map_presses_to_actions() {
+        let configs = [
+            ButtonConfig::from_str("5=next-screen,refresh").unwrap(),
+            ButtonConfig::from_str("6=hello").unwrap(),
+        ];
+        let mut buttons = Buttons::new(FakePins::default(), &configs, ms(1000));
+        let start = Instant::now();
+
+        buttons.source.pressed.insert(6, true);
+        assert!(buttons.poll(start).is_empty());
+        assert!(buttons.poll(start + ms(50)).is_empty());
+        buttons.source.pressed.insert(6, false);
+        buttons.source.pressed.insert(5, true);
+        assert!(buttons.poll(start + ms(100)).is_empty());
+        assert_eq!(buttons.poll(start + ms(150)), vec![Action::Hello]);
+        assert_eq!(buttons.poll(start + ms(1200)), vec![Action::Refresh]);
+
+        // Held for longer than a long press, but there's only a short action
+        buttons.source.pressed.insert(5, false);
+        buttons.source.pressed.insert(6, true);
+        assert!(buttons.poll(start + ms(1300)).is_empty());
+        assert!(buttons.poll(start + ms(1350)).is_empty());
+        assert!(buttons.poll(start + ms(3000)).is_empty());
+        buttons.source.pressed.insert(6, false);
+        assert!(buttons.poll(start + ms(3100)).is_empty());
+        assert_eq!(buttons.poll(start + ms(3150)), vec![Action::Hello]);
+    }
+}
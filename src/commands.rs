@@ -0,0 +1,278 @@
+//! The subcommands other than `serve`, for looking after the badge without editing its files
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use linux_embedded_hal::Delay;
+use log::info;
+use serde_json::{json, Map, Value};
+use ssd1675::GraphicDisplay;
+use structopt::StructOpt;
+
+use crate::app::{
+    write_file_atomic, State, HISTORY_PATH, HI_COUNT_PATH, PEERS_PATH, REFRESH_COUNT_PATH,
+    WEBHOOK_QUEUE_PATH,
+};
+use crate::config::Config;
+use crate::decoder::{self, Recorder};
+use crate::display::{self, DisplayState, Screen, COLS, ROWS};
+use crate::hardware;
+use crate::hellos::{HelloCounts, Source};
+use crate::history::{self, History};
+use crate::http::{self, Url};
+use crate::leaderboard::Count;
+use crate::lut::{Lut, Preset};
+use crate::peers::Peers;
+use crate::system;
+use crate::wear::RefreshCount;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct RenderOptions {
+    /// PNG file to write
+    #[structopt(short, long, parse(from_os_str))]
+    out: PathBuf,
+
+    /// Screen to draw: hello, contact, or leaderboard
+    #[structopt(long, default_value = "hello")]
+    screen: Screen,
+
+    /// Draw a message instead of the screen
+    #[structopt(long)]
+    message: Option<String>,
+
+    /// File of settings, as for serve
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct CountOptions {
+    /// Port the badge is serving on. The saved count is used when it isn't running.
+    #[structopt(short, long, default_value = "80")]
+    port: u16,
+
+    #[structopt(subcommand)]
+    command: CountCommand,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct HellosOptions {
+    /// csv, jsonl (JSON lines), or summary (hellos per hour and day, and by source, as JSON)
+    #[structopt(long, default_value = "csv")]
+    format: HellosFormat,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HellosFormat {
+    Csv,
+    JsonLines,
+    Summary,
+}
+
+impl FromStr for HellosFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(HellosFormat::Csv),
+            "jsonl" => Ok(HellosFormat::JsonLines),
+            "summary" => Ok(HellosFormat::Summary),
+            _ => Err(format!("unknown format '{}', expected csv, jsonl, or summary", s)),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum CountCommand {
+    /// Print the hello count
+    #[structopt(name = "get")]
+    Get,
+    /// Change the hello count. The counts of where hellos came from are cleared.
+    #[structopt(name = "set")]
+    Set { count: usize },
+    /// Set the hello count back to zero
+    #[structopt(name = "reset")]
+    Reset,
+}
+
+/// Draw what the display would show to a PNG, without touching the panel
+pub fn render(options: &RenderOptions) -> io::Result<()> {
+    let config = match options.config {
+        Some(ref path) => Config::default().load(path)?,
+        None => Config::default(),
+    };
+    let mut state = State::load(Path::new(HI_COUNT_PATH), config, None)?;
+    state.peers = Peers::load(Path::new(PEERS_PATH))?;
+    state.screen = options.screen;
+    state.message = options.message.clone();
+
+    let png = draw(&state.display_state())?;
+    fs::write(&options.out, png)?;
+    info!("Wrote {}", options.out.display());
+
+    Ok(())
+}
+
+/// `state` drawn by the display code, recorded and decoded from what would be sent to the panel
+fn draw(state: &DisplayState) -> io::Result<Vec<u8>> {
+    let recorder = Recorder::new();
+    let lut = Lut::preset(Preset::Default);
+    let mut black_buffer = [0u8; ROWS as usize * COLS as usize / 8];
+    let mut red_buffer = [0u8; ROWS as usize * COLS as usize / 8];
+    let mut display = GraphicDisplay::new(
+        hardware::recorded_display(&recorder, COLS, ROWS, lut.as_bytes()),
+        &mut black_buffer,
+        &mut red_buffer,
+    );
+
+    let updated = system::clock_time(SystemTime::now());
+    display::refresh(&mut display, &mut Delay {}, state, updated.as_deref())
+        .map_err(|()| io::Error::new(io::ErrorKind::Other, "unable to draw the display"))?;
+
+    decoder::decode(&recorder.transfers(), COLS, ROWS)
+        .pop()
+        .map(|frame| frame.png(hardware::ROTATION))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "nothing was drawn"))
+}
+
+/// Get or change the count of the running badge, or the saved count if it's not running
+pub fn count(options: &CountOptions) -> io::Result<()> {
+    let url = Url {
+        host: "127.0.0.1".to_string(),
+        port: options.port,
+        path: "/count".to_string(),
+    };
+    let new_count = match options.command {
+        CountCommand::Get => None,
+        CountCommand::Set { count } => Some(count),
+        CountCommand::Reset => Some(0),
+    };
+
+    let response = match new_count {
+        Some(count) => http::put(&url, "text/plain", count.to_string().as_bytes(), TIMEOUT),
+        None => http::get(&url, TIMEOUT),
+    };
+    let hi_count = match response {
+        Ok(ref response) if response.is_success() => {
+            serde_json::from_slice::<Count>(&response.body)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+                .hi_count
+        }
+        Ok(response) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("the badge responded with status {}", response.status),
+            ))
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+            let path = Path::new(HI_COUNT_PATH);
+            match new_count {
+                Some(count) => {
+                    write_file_atomic(path, HelloCounts::with_total(count).serialize())?;
+                    // As the running badge would, see `State::set_hello_counts`
+                    let history_path = Path::new(HISTORY_PATH);
+                    let mut history = History::load(history_path)?;
+                    if count < history.records().len() {
+                        history.truncate(count);
+                        history::save(history_path, history.records())?;
+                    }
+                    count
+                }
+                None => HelloCounts::load(path)?.total(),
+            }
+        }
+        Err(err) => return Err(err),
+    };
+
+    println!("{}", hi_count);
+    Ok(())
+}
+
+/// Print the saved state as JSON
+pub fn export() -> io::Result<()> {
+    let exported = exported(Path::new("."))?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&exported).expect("unable to serialise state")
+    );
+    Ok(())
+}
+
+/// Print the saved hellos. Hellos are saved shortly after they're counted, see `--save-delay-ms`.
+pub fn hellos(options: &HellosOptions) -> io::Result<()> {
+    let history = History::load(Path::new(HISTORY_PATH))?;
+    match options.format {
+        HellosFormat::Csv => print!("{}", history::csv(history.records())),
+        HellosFormat::JsonLines => print!("{}", history::json_lines(history.records())),
+        HellosFormat::Summary => {
+            let summary = history.summary(system::utc_offset(SystemTime::now()));
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&summary).expect("unable to serialise summary")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The state saved in `dir`
+fn exported(dir: &Path) -> io::Result<Value> {
+    let counts = HelloCounts::load(&dir.join(HI_COUNT_PATH))?;
+    let refreshes = RefreshCount::load(&dir.join(REFRESH_COUNT_PATH))?;
+    let peers = Peers::load(&dir.join(PEERS_PATH))?;
+    let webhook_queue = match fs::read_to_string(dir.join(WEBHOOK_QUEUE_PATH)) {
+        Ok(queue) => queue.lines().filter(|line| !line.trim().is_empty()).count(),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => 0,
+        Err(err) => return Err(err),
+    };
+
+    let sources = Source::ALL
+        .iter()
+        .map(|&source| (source.name().to_string(), json!(counts.get(source))))
+        .collect::<Map<_, _>>();
+    let peers = peers
+        .list()
+        .iter()
+        .map(|peer| json!({ "id": peer.id, "name": peer.name, "url": peer.url }))
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "hi_count": counts.total(),
+        "sources": sources,
+        "refreshes": { "full": refreshes.full, "partial": refreshes.partial },
+        "peers": peers,
+        "webhook_queue": webhook_queue,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_exported() {
+        let dir = env::temp_dir().join(format!("lca2019-{}-export", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(HI_COUNT_PATH), "12\nweb 10\napi 2\n").unwrap();
+        fs::write(dir.join(REFRESH_COUNT_PATH), "3 40\n").unwrap();
+
+        assert_eq!(
+            exported(&dir).unwrap(),
+            json!({
+                "hi_count": 12,
+                "sources": { "web": 10, "api": 2, "in-person": 0, "script": 0 },
+                "refreshes": { "full": 3, "partial": 40 },
+                "peers": [],
+                "webhook_queue": 0,
+            })
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,240 @@
+//! Settings that can be changed without restarting, read from `--config` and reloaded on SIGHUP
+//!
+//! The file has a `name = value` line for each setting to change from the command line options:
+//!
+//! ```text
+//! # Prefer the wired address
+//! interface = eth,wlan
+//! dedup-window = 1800
+//! show-hostname = true
+//! show-sources = false
+//! ```
+
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use nix::libc;
+
+use crate::address::DEFAULT_INTERFACES;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Interfaces to show the address of, in order of preference
+    pub interfaces: Vec<String>,
+    /// Hellos from an address are only counted once in this long
+    pub dedup_window: Duration,
+    pub show_hostname: bool,
+    pub show_sources: bool,
+}
+
+impl Default for Config {
+    /// The defaults of the `serve` options
+    fn default() -> Self {
+        Config {
+            interfaces: DEFAULT_INTERFACES.split(',').map(String::from).collect(),
+            dedup_window: Duration::from_secs(60 * 60),
+            show_hostname: false,
+            show_sources: false,
+        }
+    }
+}
+
+impl Config {
+    /// This config with the settings in `text` applied over it
+    pub fn apply(&self, text: &str) -> Result<Config, String> {
+        let mut config = self.clone();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (name, value) = line
+                .split_once('=')
+                .map(|(name, value)| (name.trim(), value.trim()))
+                .ok_or_else(|| format!("line {}: expected name = value", number + 1))?;
+            let invalid = || format!("line {}: invalid {} '{}'", number + 1, name, value);
+            match name {
+                "interface" => {
+                    config.interfaces = value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|interface| !interface.is_empty())
+                        .map(String::from)
+                        .collect();
+                    if config.interfaces.is_empty() {
+                        return Err(invalid());
+                    }
+                }
+                "dedup-window" => {
+                    let secs = u64::from_str(value).map_err(|_| invalid())?;
+                    config.dedup_window = Duration::from_secs(secs);
+                }
+                "show-hostname" => {
+                    config.show_hostname = bool::from_str(value).map_err(|_| invalid())?
+                }
+                "show-sources" => {
+                    config.show_sources = bool::from_str(value).map_err(|_| invalid())?
+                }
+                _ => return Err(format!("line {}: unknown setting '{}'", number + 1, name)),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// This config with the settings in the file at `path` applied over it
+    pub fn load(&self, path: &Path) -> io::Result<Config> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+        self.apply(&text).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            )
+        })
+    }
+}
+
+/// Watches for a file being written or replaced, using inotify
+pub struct Watcher {
+    inotify: File,
+    name: PathBuf,
+}
+
+impl Watcher {
+    /// Watch the directory rather than the file, as editors often replace the file
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let name = path
+            .file_name()
+            .map(PathBuf::from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "file_name is None"))?;
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let dir = CString::new(dir.as_os_str().as_bytes())?;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Closes the descriptor if adding the watch fails
+        let inotify = unsafe { File::from_raw_fd(fd) };
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+        if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Watcher { inotify, name })
+    }
+
+    /// Block until the file has changed
+    pub fn wait(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = self.inotify.read(&mut buf)?;
+            if event_names(&buf[..len]).any(|name| name == self.name.as_os_str().as_bytes()) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The names of the files in a buffer of inotify events
+fn event_names(mut buf: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let header = mem::size_of::<libc::inotify_event>();
+    std::iter::from_fn(move || {
+        if buf.len() < header {
+            return None;
+        }
+        // The name length follows the watch descriptor, mask, and cookie
+        let mut len = [0; 4];
+        len.copy_from_slice(&buf[12..16]);
+        let end = (header + u32::from_ne_bytes(len) as usize).min(buf.len());
+        // The name is padded with NULs
+        let name = &buf[header..end];
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        buf = &buf[end..];
+        Some(name)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn defaults() -> Config {
+        Config {
+            interfaces: vec!["wlan".to_string(), "eth".to_string()],
+            dedup_window: Duration::from_secs(3600),
+            show_hostname: false,
+            show_sources: false,
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let config = defaults()
+            .apply("# Wired first\ninterface = eth, wlan\n\nshow-hostname=true\n")
+            .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                interfaces: vec!["eth".to_string(), "wlan".to_string()],
+                show_hostname: true,
+                ..defaults()
+            }
+        );
+        assert_eq!(defaults().apply("").unwrap(), defaults());
+
+        assert_eq!(
+            defaults().apply("dedup-window = 1800\nshow-sources = yes"),
+            Err("line 2: invalid show-sources 'yes'".to_string())
+        );
+        assert_eq!(
+            defaults().apply("interface ="),
+            Err("line 1: invalid interface ''".to_string())
+        );
+        assert_eq!(
+            defaults().apply("port = 8080"),
+            Err("line 1: unknown setting 'port'".to_string())
+        );
+        assert!(defaults().apply("show-hostname").is_err());
+    }
+
+    #[test]
+    fn test_watch() {
+        let dir = env::temp_dir().join(format!("lca2019-{}-config", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lca2019.conf");
+
+        let mut watcher = Watcher::new(&path).unwrap();
+        let (changed_tx, changed_rx) = mpsc::channel();
+        thread::spawn(move || {
+            while watcher.wait().is_ok() {
+                let _ = changed_tx.send(());
+            }
+        });
+
+        // Other files in the directory are ignored
+        fs::write(dir.join("hi_count.txt"), "1\n").unwrap();
+        fs::write(&path, "show-hostname = true\n").unwrap();
+        assert!(changed_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(changed_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
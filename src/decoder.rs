@@ -0,0 +1,509 @@
+//! Decoder for the SSD1675 command stream.
+//!
+//! The `Recorder` provides fake SPI and GPIO implementations that can be handed to
+//! `ssd1675::Interface` to capture the bytes that would be sent to the controller.
+//! `decode` then replays those bytes against a model of the controller RAM to
+//! reconstruct the frames the panel would show, E.g. for `lca2019 render`.
+
+#![allow(deprecated)] // ssd1675 uses the v1 embedded-hal digital traits
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use embedded_hal::blocking::spi;
+use embedded_hal::digital::{InputPin, OutputPin};
+use ssd1675::{Color, Rotation};
+
+use crate::png;
+
+const WRITE_BLACK_DATA: u8 = 0x24;
+const WRITE_RED_DATA: u8 = 0x26;
+const DATA_ENTRY_MODE: u8 = 0x11;
+const MASTER_ACTIVATION: u8 = 0x20;
+const START_END_X_POSITION: u8 = 0x44;
+const START_END_Y_POSITION: u8 = 0x45;
+const X_ADDRESS: u8 = 0x4E;
+const Y_ADDRESS: u8 = 0x4F;
+/// Reads of the busy line before the fake controller has finished resetting
+const RESET_BUSY_READS: u32 = 3;
+
+/// A single transfer on the bus, as distinguished by the D/C line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transfer {
+    Command(u8),
+    Data(Vec<u8>),
+}
+
+#[derive(Debug, Default)]
+struct Bus {
+    dc_high: bool,
+    busy_reads: u32,
+    transfers: Vec<Transfer>,
+}
+
+/// Records everything written to the fake SPI device.
+#[derive(Debug, Clone, Default)]
+pub struct Recorder {
+    bus: Rc<RefCell<Bus>>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder::default()
+    }
+
+    /// SPI device that records writes
+    pub fn spi(&self) -> FakeSpi {
+        FakeSpi {
+            bus: Some(self.bus.clone()),
+        }
+    }
+
+    /// Data/command select pin
+    pub fn dc(&self) -> FakePin {
+        self.line(Line::DataCommand)
+    }
+
+    /// Reset pin, the busy line is high for a few reads after it's released
+    pub fn reset(&self) -> FakePin {
+        self.line(Line::Reset)
+    }
+
+    /// Busy pin
+    pub fn busy(&self) -> FakePin {
+        self.line(Line::Busy)
+    }
+
+    /// Any other pin, writes to it are ignored
+    pub fn pin(&self) -> FakePin {
+        self.line(Line::Other)
+    }
+
+    fn line(&self, line: Line) -> FakePin {
+        FakePin {
+            bus: self.bus.clone(),
+            line,
+        }
+    }
+
+    pub fn transfers(&self) -> Vec<Transfer> {
+        self.bus.borrow().transfers.clone()
+    }
+
+    pub fn clear(&self) {
+        self.bus.borrow_mut().transfers.clear();
+    }
+}
+
+pub struct FakeSpi {
+    bus: Option<Rc<RefCell<Bus>>>,
+}
+
+impl spi::Write<u8> for FakeSpi {
+    type Error = ();
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        let bus = match self.bus {
+            Some(ref bus) => bus,
+            None => return Ok(()),
+        };
+        let mut bus = bus.borrow_mut();
+
+        if bus.dc_high {
+            if let Some(Transfer::Data(data)) = bus.transfers.last_mut() {
+                data.extend_from_slice(words);
+                return Ok(());
+            }
+            bus.transfers.push(Transfer::Data(words.to_vec()));
+        } else {
+            for &command in words {
+                bus.transfers.push(Transfer::Command(command));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Line {
+    DataCommand,
+    Reset,
+    Busy,
+    Other,
+}
+
+pub struct FakePin {
+    bus: Rc<RefCell<Bus>>,
+    line: Line,
+}
+
+impl OutputPin for FakePin {
+    fn set_low(&mut self) {
+        if self.line == Line::DataCommand {
+            self.bus.borrow_mut().dc_high = false;
+        }
+    }
+
+    fn set_high(&mut self) {
+        match self.line {
+            Line::DataCommand => self.bus.borrow_mut().dc_high = true,
+            Line::Reset => self.bus.borrow_mut().busy_reads = RESET_BUSY_READS,
+            _ => {}
+        }
+    }
+}
+
+impl InputPin for FakePin {
+    // The fake controller is only busy just after it's reset
+    fn is_high(&self) -> bool {
+        if self.line != Line::Busy {
+            return false;
+        }
+
+        let mut bus = self.bus.borrow_mut();
+        let busy = bus.busy_reads > 0;
+        bus.busy_reads = bus.busy_reads.saturating_sub(1);
+        busy
+    }
+
+    fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+}
+
+/// The contents of the controller RAM when a display update was activated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    cols: u8,
+    rows: u16,
+    black: Vec<u8>,
+    red: Vec<u8>,
+}
+
+impl Frame {
+    fn new(cols: u8, rows: u16) -> Self {
+        let len = Self::row_bytes(cols) * rows as usize;
+        Frame {
+            cols,
+            rows,
+            black: vec![0; len],
+            red: vec![0; len],
+        }
+    }
+
+    fn row_bytes(cols: u8) -> usize {
+        (cols as usize).div_ceil(8)
+    }
+
+    /// Colour of the pixel at column `x`, row `y` in the controller's native orientation
+    pub fn native_pixel(&self, x: u32, y: u32) -> Option<Color> {
+        if x >= u32::from(self.cols) || y >= u32::from(self.rows) {
+            return None;
+        }
+
+        let index = y as usize * Self::row_bytes(self.cols) + x as usize / 8;
+        let bit = 0x80 >> (x % 8);
+        let colour = if self.red[index] & bit != 0 {
+            Color::Red
+        } else if self.black[index] & bit != 0 {
+            Color::White
+        } else {
+            Color::Black
+        };
+
+        Some(colour)
+    }
+
+    /// Colour of the pixel at `x`, `y` as seen by code drawing with `rotation`
+    pub fn pixel(&self, x: u32, y: u32, rotation: Rotation) -> Option<Color> {
+        let (cols, rows) = (u32::from(self.cols), u32::from(self.rows));
+        let (native_x, native_y) = match rotation {
+            Rotation::Rotate0 => (x, y),
+            Rotation::Rotate90 => (cols.checked_sub(y + 1)?, x),
+            Rotation::Rotate180 => (cols.checked_sub(x + 1)?, rows.checked_sub(y + 1)?),
+            Rotation::Rotate270 => (y, rows.checked_sub(x + 1)?),
+        };
+
+        self.native_pixel(native_x, native_y)
+    }
+
+    /// The frame as a PNG, the way up it's drawn with `rotation`
+    pub fn png(&self, rotation: Rotation) -> Vec<u8> {
+        let (width, height) = self.size(rotation);
+        let pixels = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| match self.pixel(x, y, rotation) {
+                Some(Color::Black) => 1,
+                Some(Color::Red) => 2,
+                _ => 0,
+            })
+            .collect::<Vec<_>>();
+
+        png::encode(
+            width,
+            height,
+            &[[255, 255, 255], [0, 0, 0], [200, 0, 0]],
+            &pixels,
+        )
+    }
+
+    /// Width and height of the frame when drawn with `rotation`
+    pub fn size(&self, rotation: Rotation) -> (u32, u32) {
+        match rotation {
+            Rotation::Rotate0 | Rotation::Rotate180 => (u32::from(self.cols), u32::from(self.rows)),
+            Rotation::Rotate90 | Rotation::Rotate270 => {
+                (u32::from(self.rows), u32::from(self.cols))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Ram {
+    frame: Frame,
+    x_range: (u8, u8),
+    y_range: (u16, u16),
+    x: u8,
+    y: u16,
+    increment_x: bool,
+    increment_y: bool,
+    vertical: bool,
+}
+
+impl Ram {
+    fn new(cols: u8, rows: u16) -> Self {
+        let x_end = (Frame::row_bytes(cols) - 1) as u8;
+        Ram {
+            frame: Frame::new(cols, rows),
+            x_range: (0, x_end),
+            y_range: (0, rows - 1),
+            x: 0,
+            y: 0,
+            increment_x: true,
+            increment_y: true,
+            vertical: false,
+        }
+    }
+
+    fn set_data_entry_mode(&mut self, mode: u8) {
+        self.increment_x = mode & 0b001 != 0;
+        self.increment_y = mode & 0b010 != 0;
+        self.vertical = mode & 0b100 != 0;
+    }
+
+    fn write(&mut self, red: bool, data: &[u8]) {
+        for &byte in data {
+            let index = self.y as usize * Frame::row_bytes(self.frame.cols) + self.x as usize;
+            let plane = if red {
+                &mut self.frame.red
+            } else {
+                &mut self.frame.black
+            };
+            // Writes outside of RAM are dropped by the controller
+            if usize::from(self.x) < Frame::row_bytes(self.frame.cols) && index < plane.len() {
+                plane[index] = byte;
+            }
+            self.advance();
+        }
+    }
+
+    fn advance(&mut self) {
+        let (x_min, x_max) = ordered(self.x_range);
+        let (y_min, y_max) = ordered(self.y_range);
+
+        if self.vertical {
+            if step(&mut self.y, y_min, y_max, self.increment_y) {
+                step(&mut self.x, x_min, x_max, self.increment_x);
+            }
+        } else if step(&mut self.x, x_min, x_max, self.increment_x) {
+            step(&mut self.y, y_min, y_max, self.increment_y);
+        }
+    }
+}
+
+fn ordered<T: PartialOrd + Copy>((a, b): (T, T)) -> (T, T) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// Move `value` one step through `min..=max`, returning true if it wrapped.
+fn step<T>(value: &mut T, min: T, max: T, increment: bool) -> bool
+where
+    T: PartialOrd + Copy + std::ops::Add<Output = T> + std::ops::Sub<Output = T> + From<u8>,
+{
+    let one = T::from(1);
+    if increment {
+        if *value >= max {
+            *value = min;
+            return true;
+        }
+        *value = *value + one;
+    } else {
+        if *value <= min {
+            *value = max;
+            return true;
+        }
+        *value = *value - one;
+    }
+
+    false
+}
+
+/// Replay `transfers` against a model of the controller RAM.
+///
+/// Returns one frame for each time a display update was activated.
+pub fn decode(transfers: &[Transfer], cols: u8, rows: u16) -> Vec<Frame> {
+    let mut ram = Ram::new(cols, rows);
+    let mut frames = Vec::new();
+    let mut command = None;
+
+    for transfer in transfers {
+        match transfer {
+            Transfer::Command(MASTER_ACTIVATION) => {
+                frames.push(ram.frame.clone());
+                command = None;
+            }
+            Transfer::Command(cmd) => command = Some(*cmd),
+            Transfer::Data(data) => match (command, data.as_slice()) {
+                (Some(WRITE_BLACK_DATA), data) => ram.write(false, data),
+                (Some(WRITE_RED_DATA), data) => ram.write(true, data),
+                (Some(DATA_ENTRY_MODE), [mode, ..]) => ram.set_data_entry_mode(*mode),
+                (Some(START_END_X_POSITION), [start, end, ..]) => ram.x_range = (*start, *end),
+                (Some(START_END_Y_POSITION), [start_lo, start_hi, end_lo, end_hi, ..]) => {
+                    let start = u16::from(*start_lo) | u16::from(*start_hi) << 8;
+                    let end = u16::from(*end_lo) | u16::from(*end_hi) << 8;
+                    ram.y_range = (start, end.min(rows - 1));
+                }
+                (Some(X_ADDRESS), [x, ..]) => ram.x = *x,
+                (Some(Y_ADDRESS), [lo, hi, ..]) => ram.y = u16::from(*lo) | u16::from(*hi) << 8,
+                _ => (),
+            },
+        }
+    }
+
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics::coord::Coord;
+    use embedded_graphics::prelude::*;
+    use embedded_graphics::Drawing;
+    use linux_embedded_hal::Delay;
+    use ssd1675::{Builder, Dimensions, Display, GraphicDisplay, Interface};
+
+    const ROWS: u16 = 212;
+    const COLS: u8 = 104;
+
+    #[test]
+    fn test_decode_raw_stream() {
+        let transfers = vec![
+            Transfer::Command(DATA_ENTRY_MODE),
+            Transfer::Data(vec![0b011]),
+            Transfer::Command(X_ADDRESS),
+            Transfer::Data(vec![0]),
+            Transfer::Command(Y_ADDRESS),
+            Transfer::Data(vec![1, 0]),
+            Transfer::Command(WRITE_BLACK_DATA),
+            Transfer::Data(vec![0x7F]),
+            Transfer::Command(X_ADDRESS),
+            Transfer::Data(vec![0]),
+            Transfer::Command(Y_ADDRESS),
+            Transfer::Data(vec![2, 0]),
+            Transfer::Command(WRITE_RED_DATA),
+            Transfer::Data(vec![0x80]),
+            Transfer::Command(MASTER_ACTIVATION),
+        ];
+
+        let frames = decode(&transfers, 16, 4);
+        assert_eq!(frames.len(), 1);
+        let frame = &frames[0];
+        assert_eq!(frame.native_pixel(0, 1), Some(Color::Black));
+        assert_eq!(frame.native_pixel(1, 1), Some(Color::White));
+        assert_eq!(frame.native_pixel(0, 2), Some(Color::Red));
+        assert_eq!(frame.native_pixel(16, 0), None);
+    }
+
+    #[test]
+    fn test_decode_wraps_within_window() {
+        let transfers = vec![
+            Transfer::Command(START_END_X_POSITION),
+            Transfer::Data(vec![0, 0]),
+            Transfer::Command(WRITE_BLACK_DATA),
+            Transfer::Data(vec![0xFF, 0x0F]),
+            Transfer::Command(MASTER_ACTIVATION),
+        ];
+
+        let frame = &decode(&transfers, 16, 4)[0];
+        assert_eq!(frame.native_pixel(0, 0), Some(Color::White));
+        assert_eq!(frame.native_pixel(0, 1), Some(Color::Black));
+        assert_eq!(frame.native_pixel(4, 1), Some(Color::White));
+        assert_eq!(frame.native_pixel(8, 0), Some(Color::Black));
+    }
+
+    #[test]
+    fn test_busy_after_reset() {
+        let recorder = Recorder::new();
+        let (mut reset, busy) = (recorder.reset(), recorder.busy());
+        assert!(busy.is_low());
+
+        reset.set_low();
+        reset.set_high();
+        let reads = (0..5).map(|_| busy.is_high()).collect::<Vec<_>>();
+        assert_eq!(reads, vec![true, true, true, false, false]);
+        assert!(recorder.pin().is_low());
+    }
+
+    #[test]
+    fn test_decode_graphic_display_rotation() {
+        let recorder = Recorder::new();
+        let interface = Interface::new(
+            recorder.spi(),
+            recorder.pin(),
+            recorder.busy(),
+            recorder.dc(),
+            recorder.reset(),
+        );
+        let config = Builder::new()
+            .dimensions(Dimensions {
+                rows: ROWS,
+                cols: COLS,
+            })
+            .rotation(Rotation::Rotate270)
+            .build()
+            .expect("invalid configuration");
+        let mut black_buffer = [0u8; ROWS as usize * COLS as usize / 8];
+        let mut red_buffer = [0u8; ROWS as usize * COLS as usize / 8];
+        let mut display = GraphicDisplay::new(
+            Display::new(interface, config),
+            &mut black_buffer,
+            &mut red_buffer,
+        );
+        let mut delay = Delay {};
+
+        display.reset(&mut delay).expect("reset");
+        display.clear(Color::White);
+        display.draw(
+            [
+                Pixel(Coord::new(0, 0).to_unsigned(), Color::Black),
+                Pixel(Coord::new(150, 30).to_unsigned(), Color::Red),
+            ]
+            .iter()
+            .cloned(),
+        );
+        display.update(&mut delay).expect("update");
+
+        let frames = decode(&recorder.transfers(), COLS, ROWS);
+        let frame = frames.last().expect("no frame shown");
+        assert_eq!(frame.size(Rotation::Rotate270), (ROWS as u32, COLS as u32));
+        assert_eq!(frame.pixel(0, 0, Rotation::Rotate270), Some(Color::Black));
+        assert_eq!(frame.pixel(1, 0, Rotation::Rotate270), Some(Color::White));
+        assert_eq!(frame.pixel(150, 30, Rotation::Rotate270), Some(Color::Red));
+        assert_eq!(frame.pixel(211, 103, Rotation::Rotate270), Some(Color::White));
+    }
+}
@@ -0,0 +1,940 @@
+// Graphics
+use embedded_graphics::coord::Coord;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Line;
+use embedded_graphics::Drawing;
+use embedded_hal::blocking::delay::DelayMs;
+use log::{debug, error, warn};
+use qrcode::{EcLevel, QrCode};
+use ssd1675::interface::DisplayInterface;
+use ssd1675::{Color, GraphicDisplay};
+
+// Font
+use profont::{ProFont12Point, ProFont14Point, ProFont24Point, ProFont7Point, ProFont9Point};
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::activity;
+use crate::address::Host;
+use crate::leaderboard::Standing;
+
+pub const ROWS: u16 = 212;
+pub const COLS: u8 = 104;
+/// Name at the top of the display
+pub const OWNER: &str = "Wesley Moore";
+const QR_X: i32 = 150;
+const QR_Y: i32 = 30;
+// Status icons go in the top right corner, between the name and the QR code
+const STATUS_X: i32 = 150;
+const STATUS_Y: i32 = 21;
+
+/// Hours shown in the bar chart after "Say hi at:"
+pub const CHART_HOURS: usize = 12;
+const CHART_X: i32 = 106;
+const CHART_Y: i32 = 76;
+const CHART_HEIGHT: usize = 10;
+
+// Percent covered by each status level, with the top one reaching 100%
+const LEVEL_BAND: u8 = 20;
+// Percent past the edge of a status level before it changes
+const LEVEL_HYSTERESIS: u8 = 5;
+
+// Rows of the leaderboard that fit below the title
+const LEADERBOARD_ROWS: usize = 4;
+
+// ProFont12 characters that fit across the display, and the lines below the name
+const MESSAGE_COLUMNS: usize = 26;
+const MESSAGE_LINES: usize = 5;
+
+// Contact details in the QR code on the contact screen
+const CONTACT_CARD: &str = "MECARD:N:Moore,Wesley;EMAIL:wes@wezm.net;URL:http://www.wezm.net/;;";
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Screen {
+    /// Hello count and the URL to say hello at
+    Hello,
+    /// Contact details
+    Contact,
+    /// Hello counts of the team
+    Leaderboard,
+}
+
+impl FromStr for Screen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hello" => Ok(Screen::Hello),
+            "contact" => Ok(Screen::Contact),
+            "leaderboard" => Ok(Screen::Leaderboard),
+            _ => Err(format!(
+                "unknown screen '{}', expected hello, contact, or leaderboard",
+                s
+            )),
+        }
+    }
+}
+
+impl Screen {
+    pub fn next(self) -> Self {
+        match self {
+            Screen::Hello => Screen::Contact,
+            Screen::Contact => Screen::Leaderboard,
+            Screen::Leaderboard => Screen::Hello,
+        }
+    }
+}
+
+/// The information shown on the display
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisplayState {
+    pub screen: Screen,
+    pub hi_count: usize,
+    /// Summary of where hellos came from, shown instead of the website if present
+    pub sources: Option<String>,
+    /// Where to say hello, None if there's no network
+    pub host: Option<Host>,
+    /// Battery level from 0 to 4, see `level`
+    pub battery: Option<u8>,
+    /// Wifi signal level from 0 to 4
+    pub wifi: Option<u8>,
+    /// Number of other badges met
+    pub peers: usize,
+    /// The team, only filled in on the leaderboard screen
+    pub leaderboard: Vec<Standing>,
+    /// Shown instead of the screen when set
+    pub message: Option<String>,
+    /// Hellos in each of the last `CHART_HOURS` hours, oldest first
+    pub activity: Vec<usize>,
+}
+
+impl DisplayState {
+    /// Are the counts and status icons the only difference between `self` and `other`?
+    fn only_details_differ(&self, other: &DisplayState) -> bool {
+        let details_changed = DisplayState {
+            hi_count: other.hi_count,
+            sources: other.sources.clone(),
+            battery: other.battery,
+            wifi: other.wifi,
+            peers: other.peers,
+            leaderboard: other.leaderboard.clone(),
+            activity: other.activity.clone(),
+            ..self.clone()
+        };
+        details_changed == *other
+    }
+}
+
+/// Turn a percentage into a level from 0 to 4 for the status icons, in 20% bands so that 80% and up
+/// shows as full.
+///
+/// Using levels instead of the percentage means small changes don't cause a refresh. The
+/// `previous` level is kept until the percentage is `LEVEL_HYSTERESIS` outside of it, so a reading
+/// hovering at the boundary between two levels doesn't either.
+pub fn level(percent: u8, previous: Option<u8>) -> u8 {
+    let percent = percent.min(100);
+    let level = (percent / LEVEL_BAND).min(4);
+    match previous {
+        Some(previous) if previous != level => {
+            let low = (previous * LEVEL_BAND).saturating_sub(LEVEL_HYSTERESIS);
+            let high = previous * LEVEL_BAND + LEVEL_BAND + LEVEL_HYSTERESIS;
+            if percent >= low && percent < high {
+                previous
+            } else {
+                level
+            }
+        }
+        _ => level,
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RefreshKind {
+    /// Tri-colour refresh that also clears any ghosting
+    Full,
+    /// Quicker black and white only refresh
+    Partial,
+}
+
+/// When partial refreshes are allowed
+#[derive(Debug, Copy, Clone)]
+pub struct RefreshPolicy {
+    /// Do a full refresh after this many partial ones. 0 disables partial refreshes.
+    pub full_every: u32,
+    /// Do a full refresh when there have been partial ones and this much time has passed
+    pub full_interval: Duration,
+}
+
+/// Tracks refreshes to decide what kind the next one should be
+#[derive(Debug)]
+pub struct RefreshTracker {
+    policy: RefreshPolicy,
+    partials: u32,
+    last_full: Option<Instant>,
+}
+
+impl RefreshTracker {
+    pub fn new(policy: RefreshPolicy) -> Self {
+        RefreshTracker {
+            policy,
+            partials: 0,
+            last_full: None,
+        }
+    }
+
+    /// The kind of refresh needed to go from showing `old` to `new`, if any
+    pub fn next(
+        &self,
+        old: Option<&DisplayState>,
+        new: &DisplayState,
+        now: Instant,
+    ) -> Option<RefreshKind> {
+        let full_due = match self.last_full {
+            Some(last_full) => {
+                self.partials >= self.policy.full_every
+                    || (self.partials > 0 && now - last_full >= self.policy.full_interval)
+            }
+            None => true,
+        };
+
+        match old {
+            Some(old) if old == new => {
+                if full_due && self.partials > 0 {
+                    Some(RefreshKind::Full)
+                } else {
+                    None
+                }
+            }
+            Some(old) if !full_due && old.only_details_differ(new) => Some(RefreshKind::Partial),
+            _ => Some(RefreshKind::Full),
+        }
+    }
+
+    pub fn record(&mut self, kind: RefreshKind, now: Instant) {
+        match kind {
+            RefreshKind::Full => {
+                self.partials = 0;
+                self.last_full = Some(now);
+            }
+            RefreshKind::Partial => self.partials += 1,
+        }
+    }
+}
+
+/// Reset the panel, draw `state`, update the panel, then put it back to sleep.
+///
+/// `updated` is the time of the refresh, shown next to the status icons.
+pub fn refresh<I, D>(
+    display: &mut GraphicDisplay<I>,
+    delay: &mut D,
+    state: &DisplayState,
+    updated: Option<&str>,
+) -> Result<(), I::Error>
+where
+    I: DisplayInterface,
+    I::Error: fmt::Debug,
+    D: DelayMs<u8>,
+{
+    show(display, delay, |display| render(display, state, updated))
+}
+
+/// Reset the panel, clear it and call `draw`, update the panel, then put it back to sleep
+pub fn show<I, D, F>(
+    display: &mut GraphicDisplay<I>,
+    delay: &mut D,
+    draw: F,
+) -> Result<(), I::Error>
+where
+    I: DisplayInterface,
+    I::Error: fmt::Debug,
+    D: DelayMs<u8>,
+    F: FnOnce(&mut GraphicDisplay<I>),
+{
+    match display.reset(delay) {
+        Ok(()) => debug!("Reset and initialised"),
+        Err(err) => error!("Error resetting display: {:?}", err),
+    }
+
+    display.clear(Color::White);
+    debug!("Clear");
+
+    draw(display);
+
+    let result = display.update(delay);
+    match result {
+        Ok(()) => debug!("Update..."),
+        Err(ref err) => error!("error updating display: {:?}", err),
+    }
+
+    match display.deep_sleep() {
+        Ok(()) => debug!("Finished - going to sleep"),
+        Err(err) => warn!("Error going to sleep: {:?}", err),
+    }
+
+    result
+}
+
+/// Draw `state` onto `display`
+pub fn render<D: Drawing<Color>>(display: &mut D, state: &DisplayState, updated: Option<&str>) {
+    display.draw(
+        ProFont24Point::render_str(OWNER)
+            .with_stroke(Some(Color::Red))
+            .with_fill(Some(Color::White))
+            .translate(Coord::new(1, -4))
+            .into_iter(),
+    );
+
+    match (&state.message, state.screen) {
+        (Some(message), _) => render_message(display, message),
+        (None, Screen::Hello) => render_hello(display, state),
+        (None, Screen::Contact) => render_contact(display),
+        (None, Screen::Leaderboard) => render_leaderboard(display, &state.leaderboard),
+    }
+
+    render_status(display, state, updated);
+}
+
+/// Battery and wifi icons followed by the time of the refresh
+fn render_status<D: Drawing<Color>>(display: &mut D, state: &DisplayState, updated: Option<&str>) {
+    let mut x = STATUS_X;
+
+    if let Some(level) = state.battery {
+        let (top, bottom) = (STATUS_Y, STATUS_Y + 5);
+        display.draw(rect(Coord::new(x, top), Coord::new(x + 9, bottom), false));
+        // Terminal
+        display.draw(rect(Coord::new(x + 10, top + 2), Coord::new(x + 10, bottom - 2), true));
+        if level > 0 {
+            let right = x + i32::from(level) * 2;
+            display.draw(rect(Coord::new(x + 1, top + 1), Coord::new(right, bottom - 1), true));
+        }
+        x += 14;
+    }
+
+    if let Some(level) = state.wifi {
+        // Four bars of increasing height, the ones above the level are just a stub
+        for bar in 0..4 {
+            let left = x + bar * 3;
+            let top = if bar < i32::from(level) {
+                STATUS_Y + 3 - bar
+            } else {
+                STATUS_Y + 5
+            };
+            display.draw(rect(Coord::new(left, top), Coord::new(left + 1, STATUS_Y + 5), true));
+        }
+        x += 14;
+    }
+
+    if let Some(updated) = updated {
+        display.draw(
+            ProFont7Point::render_str(updated)
+                .with_stroke(Some(Color::Black))
+                .with_fill(Some(Color::White))
+                .translate(Coord::new(x, STATUS_Y - 2))
+                .into_iter(),
+        );
+    }
+}
+
+/// Black pixels of the rectangle from `top_left` to `bottom_right` inclusive
+fn rect(top_left: Coord, bottom_right: Coord, filled: bool) -> impl Iterator<Item = Pixel<Color>> {
+    (top_left.1..=bottom_right.1).flat_map(move |y| {
+        (top_left.0..=bottom_right.0).filter_map(move |x| {
+            let edge = x == top_left.0
+                || x == bottom_right.0
+                || y == top_left.1
+                || y == bottom_right.1;
+            if filled || edge {
+                Some(Pixel(Coord::new(x, y).to_unsigned(), Color::Black))
+            } else {
+                None
+            }
+        })
+    })
+}
+
+fn render_leaderboard<D: Drawing<Color>>(display: &mut D, standings: &[Standing]) {
+    display.draw(
+        ProFont12Point::render_str("Team leaderboard")
+            .with_stroke(Some(Color::Black))
+            .with_fill(Some(Color::White))
+            .translate(Coord::new(1, 23))
+            .into_iter(),
+    );
+
+    if standings.len() <= 1 {
+        display.draw(
+            ProFont12Point::render_str("No team configured")
+                .with_stroke(Some(Color::Black))
+                .with_fill(Some(Color::White))
+                .translate(Coord::new(1, 38))
+                .into_iter(),
+        );
+        return;
+    }
+
+    for (i, line) in leaderboard_lines(standings).iter().enumerate() {
+        display.draw(
+            ProFont12Point::render_str(line)
+                .with_stroke(Some(Color::Black))
+                .with_fill(Some(Color::White))
+                .translate(Coord::new(1, 38 + i as i32 * 15))
+                .into_iter(),
+        );
+    }
+}
+
+/// The rows of the leaderboard that fit on the display, always including us
+fn leaderboard_lines(standings: &[Standing]) -> Vec<String> {
+    let mut rows = standings
+        .iter()
+        .take(LEADERBOARD_ROWS)
+        .collect::<Vec<_>>();
+    if !rows.iter().any(|standing| standing.is_me) {
+        if let Some(me) = standings.iter().find(|standing| standing.is_me) {
+            rows.pop();
+            rows.push(me);
+        }
+    }
+
+    rows.iter()
+        .map(|standing| {
+            let delta = if standing.delta > 0 {
+                format!("+{}", standing.delta)
+            } else {
+                String::new()
+            };
+            let line = format!(
+                "{}{:>2} {:<12.12}{:>5}{}{}",
+                if standing.is_me { ">" } else { " " },
+                standing.rank,
+                standing.name,
+                standing.count(),
+                if standing.stale { "*" } else { " " },
+                delta
+            );
+            line.trim_end().to_string()
+        })
+        .collect()
+}
+
+fn render_message<D: Drawing<Color>>(display: &mut D, message: &str) {
+    for (i, line) in wrap(message, MESSAGE_COLUMNS)
+        .iter()
+        .take(MESSAGE_LINES)
+        .enumerate()
+    {
+        display.draw(
+            ProFont12Point::render_str(line)
+                .with_stroke(Some(Color::Black))
+                .with_fill(Some(Color::White))
+                .translate(Coord::new(1, 30 + i as i32 * 15))
+                .into_iter(),
+        );
+    }
+}
+
+/// Break `text` into lines of at most `width` characters, on spaces where possible
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+
+    for word in text.split_whitespace() {
+        let mut word = word.chars().collect::<Vec<_>>();
+        // Words too long for a line of their own are split
+        while word.len() > width {
+            if !line.is_empty() {
+                lines.push(line.split_off(0));
+            }
+            lines.push(word.drain(..width).collect());
+        }
+
+        let needed = if line.is_empty() { 0 } else { 1 } + word.len();
+        if line.chars().count() + needed > width {
+            lines.push(line.split_off(0));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.extend(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+
+    lines
+}
+
+fn render_contact<D: Drawing<Color>>(display: &mut D) {
+    let lines = ["wes@wezm.net", "github.com/wezm", "@wezm"];
+    for (i, line) in lines.iter().enumerate() {
+        display.draw(
+            ProFont12Point::render_str(line)
+                .with_stroke(Some(Color::Black))
+                .with_fill(Some(Color::White))
+                .translate(Coord::new(1, 30 + i as i32 * 16))
+                .into_iter(),
+        );
+    }
+
+    // Bottom right corner, to leave room for the text
+    let qrcode = QrCode::with_error_correction_level(CONTACT_CARD, EcLevel::L).unwrap();
+    let size = qrcode.width() as i32 * 2;
+    let top_left = Coord::new(i32::from(ROWS) - size - 2, i32::from(COLS) - size - 2);
+    display.draw(QrCodeIterator::new(qrcode, top_left, 2));
+}
+
+fn render_hello<D: Drawing<Color>>(display: &mut D, state: &DisplayState) {
+    match state.sources {
+        Some(ref sources) if !sources.is_empty() => display.draw(
+            ProFont12Point::render_str(sources)
+                .with_stroke(Some(Color::Black))
+                .with_fill(Some(Color::White))
+                .translate(Coord::new(1, 23))
+                .into_iter(),
+        ),
+        _ => display.draw(
+            ProFont14Point::render_str("wezm.net")
+                .with_stroke(Some(Color::Black))
+                .with_fill(Some(Color::White))
+                .translate(Coord::new(1, 22))
+                .into_iter(),
+        ),
+    }
+
+    // Badges met goes after the website or sources, if there's room before the QR code
+    if state.peers > 0 {
+        let met = format!(
+            "{} badge{}",
+            state.peers,
+            if state.peers == 1 { "" } else { "s" }
+        );
+        let left = match state.sources {
+            Some(ref sources) if !sources.is_empty() => sources.len() as i32 * 8 + 9,
+            _ => 90,
+        };
+        // 9pt is 6px wide
+        if left + met.len() as i32 * 6 <= QR_X {
+            display.draw(
+                ProFont9Point::render_str(&met)
+                    .with_stroke(Some(Color::Black))
+                    .with_fill(Some(Color::White))
+                    .translate(Coord::new(left, 26))
+                    .into_iter(),
+            );
+        }
+    }
+
+    let hi = state.hi_count.to_string();
+    display.draw(
+        ProFont24Point::render_str(&hi)
+            .with_stroke(Some(Color::Black))
+            .with_fill(Some(Color::White))
+            .translate(Coord::new(1, 42))
+            .into_iter(),
+    );
+    let unit = if state.hi_count != 1 {
+        "people have"
+    } else {
+        "person has"
+    };
+    display.draw(
+        ProFont12Point::render_str(unit)
+            .with_stroke(Some(Color::Black))
+            .with_fill(Some(Color::White))
+            .translate(Coord::new(hi.len() as i32 * 17 + 4, 43))
+            .into_iter(),
+    );
+    display.draw(
+        ProFont12Point::render_str("said hello")
+            .with_stroke(Some(Color::Black))
+            .with_fill(Some(Color::White))
+            .translate(Coord::new(hi.len() as i32 * 17 + 4, 57))
+            .into_iter(),
+    );
+
+    let url = state
+        .host
+        .as_ref()
+        .map(Host::url)
+        .unwrap_or_else(|| "http://?.?.?.?/".to_string());
+    // http://192.168.100.100/ = 23 chars 230px
+    // http://10.0.0.18/ = 17 chars 170px
+    // http://[2001:db8:1234:5678:9abc::18]/ = 37 chars 370px
+
+    // If URL is longer than will fit on the screen use a smaller font
+    // 14pt is 10px wide
+    // 12pt is 8px wide
+    // 9pt is 6px wide
+    // 7pt is 5px wide, long IPv6 addresses may still not fit but the QR code has the full URL
+    let width = url.len();
+    if width * 10 <= ROWS as usize {
+        display.draw(
+            ProFont14Point::render_str(&url)
+                .with_stroke(Some(Color::Black))
+                .with_fill(Some(Color::White))
+                .translate(Coord::new(1, 88))
+                .into_iter(),
+        );
+    } else if width * 8 <= ROWS as usize {
+        display.draw(
+            ProFont12Point::render_str(&url)
+                .with_stroke(Some(Color::Black))
+                .with_fill(Some(Color::White))
+                .translate(Coord::new(1, 90))
+                .into_iter(),
+        );
+    } else if width * 6 <= ROWS as usize {
+        display.draw(
+            ProFont9Point::render_str(&url)
+                .with_stroke(Some(Color::Black))
+                .with_fill(Some(Color::White))
+                .translate(Coord::new(1, 92))
+                .into_iter(),
+        );
+    } else {
+        display.draw(
+            ProFont7Point::render_str(&url)
+                .with_stroke(Some(Color::Black))
+                .with_fill(Some(Color::White))
+                .translate(Coord::new(1, 94))
+                .into_iter(),
+        );
+    }
+    display.draw(
+        ProFont14Point::render_str("Say hi at:")
+            .with_stroke(Some(Color::Black))
+            .with_fill(Some(Color::White))
+            .translate(Coord::new(1, 73))
+            .into_iter(),
+    );
+    render_activity(display, &state.activity);
+
+    // Draw the URL QR code
+    let qrcode = QrCode::with_error_correction_level(url.as_bytes(), EcLevel::L).unwrap();
+    display.draw(QrCodeIterator::new(qrcode, Coord::new(QR_X, QR_Y), 2));
+}
+
+/// Bars of hellos per hour, two pixels wide. Nothing's drawn without any hellos.
+fn render_activity<D: Drawing<Color>>(display: &mut D, activity: &[usize]) {
+    if activity.iter().all(|&count| count == 0) {
+        return;
+    }
+
+    let bottom = CHART_Y + CHART_HEIGHT as i32 - 1;
+    for bar in activity::bars(activity, 2, CHART_HEIGHT) {
+        if bar.height == 0 {
+            continue;
+        }
+        for dx in 0..2 {
+            let x = CHART_X + (bar.x + dx) as i32;
+            display.draw(
+                Line::new(Coord::new(x, CHART_Y + bar.y as i32), Coord::new(x, bottom))
+                    .with_stroke(Some(Color::Black))
+                    .into_iter(),
+            );
+        }
+    }
+}
+
+pub struct QrCodeIterator {
+    colors: Vec<qrcode::Color>,
+    top_left: Coord,
+    index: usize,
+    width: usize,
+    x: usize,
+    y: usize,
+}
+
+impl QrCodeIterator {
+    pub fn new(code: QrCode, top_left: Coord, scale: usize) -> Self {
+        let width = code.width() * scale;
+        let colors = if scale > 1 {
+            // Map each pixel to more...
+            let colors = code.into_colors();
+            let mut expanded = vec![qrcode::Color::Light; colors.len() * scale.pow(2)];
+            for (i, color) in colors.into_iter().enumerate() {
+                if color == qrcode::Color::Light {
+                    continue;
+                }
+
+                for y in 0..scale {
+                    for x in 0..scale {
+                        expanded[i * scale + x + (width * y) + (i * scale / width * width)] = color;
+                    }
+                }
+
+            }
+
+            expanded
+        }
+        else {
+            code.into_colors()
+        };
+
+        QrCodeIterator {
+            width,
+            colors,
+            index: 0,
+            x: 0,
+            y: 0,
+            top_left,
+        }
+    }
+}
+
+impl Iterator for QrCodeIterator {
+    type Item = Pixel<Color>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.colors.len() {
+            None
+        } else {
+            let colour = self.colors[self.index].select(Color::Black, Color::White);
+            let pixel = Pixel(
+                Coord::new(
+                    self.top_left.0 + self.x as i32,
+                    self.top_left.1 + self.y as i32,
+                )
+                .to_unsigned(),
+                colour,
+            );
+
+            self.index += 1;
+
+            if self.x != 0 && self.x % (self.width - 1) == 0 {
+                self.x = 0;
+                self.y += 1;
+            } else {
+                self.x += 1;
+            }
+
+            Some(pixel)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display_state(hi_count: usize) -> DisplayState {
+        DisplayState {
+            screen: Screen::Hello,
+            hi_count,
+            sources: None,
+            host: Some(Host::Ip("10.0.0.18".parse().unwrap())),
+            battery: Some(4),
+            wifi: Some(3),
+            peers: 0,
+            leaderboard: Vec::new(),
+            message: None,
+            activity: Vec::new(),
+        }
+    }
+
+    fn standing(rank: usize, name: &str, hi_count: usize, is_me: bool) -> Standing {
+        Standing {
+            rank,
+            name: name.to_string(),
+            hi_count: Some(hi_count),
+            delta: 0,
+            is_me,
+            stale: false,
+            error: None,
+        }
+    }
+
+    fn tracker(full_every: u32) -> RefreshTracker {
+        RefreshTracker::new(RefreshPolicy {
+            full_every,
+            full_interval: Duration::from_secs(60),
+        })
+    }
+
+    #[test]
+    fn test_first_refresh_is_full() {
+        let tracker = tracker(5);
+        let kind = tracker.next(None, &display_state(1), Instant::now());
+        assert_eq!(kind, Some(RefreshKind::Full));
+    }
+
+    #[test]
+    fn test_count_change_is_partial_until_full_due() {
+        let mut tracker = tracker(2);
+        let now = Instant::now();
+        tracker.record(RefreshKind::Full, now);
+
+        let (one, two, three) = (display_state(1), display_state(2), display_state(3));
+        assert_eq!(tracker.next(Some(&one), &one, now), None);
+        assert_eq!(
+            tracker.next(Some(&one), &two, now),
+            Some(RefreshKind::Partial)
+        );
+        tracker.record(RefreshKind::Partial, now);
+        assert_eq!(
+            tracker.next(Some(&two), &three, now),
+            Some(RefreshKind::Partial)
+        );
+        tracker.record(RefreshKind::Partial, now);
+        assert_eq!(tracker.next(Some(&three), &one, now), Some(RefreshKind::Full));
+    }
+
+    #[test]
+    fn test_sources_change_is_partial() {
+        let mut tracker = tracker(5);
+        let now = Instant::now();
+        tracker.record(RefreshKind::Full, now);
+
+        let old = display_state(1);
+        let new = DisplayState {
+            hi_count: 2,
+            sources: Some("web 2".to_string()),
+            activity: vec![0, 1],
+            ..old.clone()
+        };
+        assert_eq!(
+            tracker.next(Some(&old), &new, now),
+            Some(RefreshKind::Partial)
+        );
+    }
+
+    #[test]
+    fn test_status_and_peers_change_is_partial() {
+        let mut tracker = tracker(5);
+        let now = Instant::now();
+        tracker.record(RefreshKind::Full, now);
+
+        let old = display_state(1);
+        let new = DisplayState {
+            battery: Some(3),
+            wifi: None,
+            peers: 2,
+            ..old.clone()
+        };
+        assert_eq!(
+            tracker.next(Some(&old), &new, now),
+            Some(RefreshKind::Partial)
+        );
+    }
+
+    #[test]
+    fn test_level() {
+        assert_eq!(level(0, None), 0);
+        assert_eq!(level(19, None), 0);
+        assert_eq!(level(20, None), 1);
+        assert_eq!(level(79, None), 3);
+        assert_eq!(level(80, None), 4);
+        assert_eq!(level(100, None), 4);
+        assert_eq!(level(255, None), 4);
+    }
+
+    #[test]
+    fn test_level_hysteresis() {
+        // Hovering around 40% stays at whichever level it was
+        assert_eq!(level(39, Some(2)), 2);
+        assert_eq!(level(36, Some(2)), 2);
+        assert_eq!(level(41, Some(1)), 1);
+        assert_eq!(level(44, Some(1)), 1);
+        // Until it's clearly in another level
+        assert_eq!(level(34, Some(2)), 1);
+        assert_eq!(level(45, Some(1)), 2);
+        assert_eq!(level(10, Some(3)), 0);
+        assert_eq!(level(100, Some(3)), 4);
+        assert_eq!(level(100, Some(4)), 4);
+        assert_eq!(level(76, Some(4)), 4);
+        assert_eq!(level(74, Some(4)), 3);
+    }
+
+    #[test]
+    fn test_host_change_is_full() {
+        let mut tracker = tracker(5);
+        let now = Instant::now();
+        tracker.record(RefreshKind::Full, now);
+
+        let old = display_state(1);
+        let new = DisplayState {
+            host: None,
+            ..old.clone()
+        };
+        assert_eq!(tracker.next(Some(&old), &new, now), Some(RefreshKind::Full));
+    }
+
+    #[test]
+    fn test_screen_change_is_full() {
+        let mut tracker = tracker(5);
+        let now = Instant::now();
+        tracker.record(RefreshKind::Full, now);
+
+        let old = display_state(1);
+        let new = DisplayState {
+            screen: Screen::Contact,
+            ..old.clone()
+        };
+        assert_eq!(tracker.next(Some(&old), &new, now), Some(RefreshKind::Full));
+    }
+
+    #[test]
+    fn test_leaderboard_lines() {
+        let mut standings = vec![
+            standing(1, "Busy Badge", 50, false),
+            standing(2, "A Badge With A Long Name", 20, false),
+            standing(3, "Third", 10, false),
+            standing(4, "Fourth", 8, false),
+            standing(5, OWNER, 7, true),
+        ];
+        standings[0].delta = 12;
+        standings[2].stale = true;
+
+        // We replace the last row when outside the top rows
+        assert_eq!(
+            leaderboard_lines(&standings),
+            vec![
+                "  1 Busy Badge     50 +12",
+                "  2 A Badge With   20",
+                "  3 Third          10*",
+                "> 5 Wesley Moore    7",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(
+            wrap("Back at the  booth at 2pm", 12),
+            vec!["Back at the", "booth at 2pm"]
+        );
+        assert_eq!(
+            wrap("see https://wezm.net/", 8),
+            vec!["see", "https://", "wezm.net", "/"]
+        );
+        assert!(wrap(" ", 8).is_empty());
+    }
+
+    #[test]
+    fn test_full_refresh_after_interval() {
+        let mut tracker = tracker(5);
+        let now = Instant::now();
+        tracker.record(RefreshKind::Full, now);
+        tracker.record(RefreshKind::Partial, now);
+
+        let state = display_state(2);
+        assert_eq!(tracker.next(Some(&state), &state, now), None);
+        let later = now + Duration::from_secs(61);
+        assert_eq!(
+            tracker.next(Some(&state), &state, later),
+            Some(RefreshKind::Full)
+        );
+    }
+
+    #[test]
+    fn test_partial_disabled() {
+        let mut tracker = tracker(0);
+        let now = Instant::now();
+        tracker.record(RefreshKind::Full, now);
+
+        let kind = tracker.next(Some(&display_state(1)), &display_state(2), now);
+        assert_eq!(kind, Some(RefreshKind::Full));
+    }
+}
@@ -1,91 +1,266 @@
+#![allow(deprecated)] // the pins are handed to ssd1675, which takes the v1 digital traits
+
+use std::convert::Infallible;
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_hal::blocking::spi;
+use embedded_hal::digital::{InputPin, OutputPin};
 use linux_embedded_hal::spidev::{self, SpidevOptions};
-use linux_embedded_hal::sysfs_gpio::Direction;
+use linux_embedded_hal::sysfs_gpio::{self, Direction};
 use linux_embedded_hal::{Pin, Spidev};
+use log::debug;
 
-use ssd1675::{Builder, Dimensions, Display, Rotation};
-
-#[rustfmt::skip]
-const LUT: [u8; 70] = [
-    // Phase 0     Phase 1     Phase 2     Phase 3     Phase 4     Phase 5     Phase 6
-    // A B C D     A B C D     A B C D     A B C D     A B C D     A B C D     A B C D
-    0b01001000, 0b10100000, 0b00010000, 0b00010000, 0b00010011, 0b00000000, 0b00000000,  // LUT0 - Black
-    0b01001000, 0b10100000, 0b10000000, 0b00000000, 0b00000011, 0b00000000, 0b00000000,  // LUTT1 - White
-    0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,  // IGNORE
-    0b01001000, 0b10100101, 0b00000000, 0b10111011, 0b00000000, 0b00000000, 0b00000000,  // LUT3 - Red
-    0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,  // LUT4 - VCOM
-
-    // Duration            |  Repeat
-    // A   B     C     D   |
-    64,   12,   32,   12,    6,   // 0 Flash
-    16,   8,    4,    4,     6,   // 1 clear
-    4,    8,    8,    16,    16,  // 2 bring in the black
-    2,    2,    2,    64,    32,  // 3 time for red
-    2,    2,    2,    2,     2,   // 4 final black sharpen phase
-    0,    0,    0,    0,     0,   // 5
-    0,    0,    0,    0,     0    // 6
-];
-
-pub fn display<'a>(
-    cols: u8,
-    rows: u16,
-) -> Result<
-    (ssd1675::display::Display<
-        'a,
-        ssd1675::interface::Interface<
-            linux_embedded_hal::Spidev,
-            linux_embedded_hal::Pin,
-            linux_embedded_hal::Pin,
-            linux_embedded_hal::Pin,
-            linux_embedded_hal::Pin,
-        >,
-    >),
-    std::io::Error,
-> {
-    // Configure SPI
-    let mut spi = Spidev::open("/dev/spidev0.0").expect("SPI device");
+use ssd1675::interface::DisplayInterface;
+use ssd1675::{Builder, Dimensions, Display, Interface, Rotation};
+
+use crate::decoder::{FakePin, FakeSpi, Recorder};
+
+/// The badge is worn with the connector at the top
+pub const ROTATION: Rotation = Rotation::Rotate270;
+
+pub const SPI_DEVICE: &str = "/dev/spidev0.0";
+
+// https://pinout.xyz/pinout/inky_phat
+pub const CS_PIN: u64 = 8; // BCM8
+pub const BUSY_PIN: u64 = 17; // BCM17
+pub const DC_PIN: u64 = 22; // BCM22
+pub const RESET_PIN: u64 = 27; // BCM27
+
+/// How long to wait for the kernel to export a pin
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub type PanelInterface = Interface<Spidev, Pin, Pin, Pin, Pin>;
+
+/// Where the panel's SPI device and pins come from, so `selftest` can set them up step by step
+pub trait Board {
+    type Spi: spi::Write<u8>;
+    type Pin: Gpio + InputPin + OutputPin;
+
+    fn open_spi(&self) -> io::Result<Self::Spi>;
+    fn configure_spi(&self, spi: &mut Self::Spi) -> io::Result<()>;
+    /// Set up pin `number` as an output, starting at `value`
+    fn output_pin(&self, number: u64, value: u8) -> io::Result<Self::Pin>;
+    /// Set up pin `number` as an input
+    fn input_pin(&self, number: u64) -> io::Result<Self::Pin>;
+}
+
+/// A pin that can fail to be set or read, unlike the embedded-hal ones
+pub trait Gpio {
+    type Error: fmt::Display;
+
+    fn set(&mut self, value: u8) -> Result<(), Self::Error>;
+    fn value(&self) -> Result<u8, Self::Error>;
+}
+
+/// The Raspberry Pi the panel is plugged into
+pub struct Pi;
+
+impl Board for Pi {
+    type Spi = Spidev;
+    type Pin = Pin;
+
+    fn open_spi(&self) -> io::Result<Spidev> {
+        open_spi()
+    }
+
+    fn configure_spi(&self, spi: &mut Spidev) -> io::Result<()> {
+        configure_spi(spi)
+    }
+
+    fn output_pin(&self, number: u64, value: u8) -> io::Result<Pin> {
+        output_pin(number, value)
+    }
+
+    fn input_pin(&self, number: u64) -> io::Result<Pin> {
+        input_pin(number)
+    }
+}
+
+impl Gpio for Pin {
+    type Error = sysfs_gpio::Error;
+
+    fn set(&mut self, value: u8) -> Result<(), Self::Error> {
+        self.0.set_value(value)
+    }
+
+    fn value(&self) -> Result<u8, Self::Error> {
+        self.0.get_value()
+    }
+}
+
+/// The panel as recorded by a `Recorder`, whose busy line clears shortly after reset
+impl Board for Recorder {
+    type Spi = FakeSpi;
+    type Pin = FakePin;
+
+    fn open_spi(&self) -> io::Result<FakeSpi> {
+        Ok(self.spi())
+    }
+
+    fn configure_spi(&self, _spi: &mut FakeSpi) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn output_pin(&self, number: u64, _value: u8) -> io::Result<FakePin> {
+        Ok(match number {
+            DC_PIN => self.dc(),
+            RESET_PIN => self.reset(),
+            _ => self.pin(),
+        })
+    }
+
+    fn input_pin(&self, number: u64) -> io::Result<FakePin> {
+        Ok(match number {
+            BUSY_PIN => self.busy(),
+            _ => self.pin(),
+        })
+    }
+}
+
+impl Gpio for FakePin {
+    type Error = Infallible;
+
+    fn set(&mut self, value: u8) -> Result<(), Self::Error> {
+        if value == 0 {
+            self.set_low();
+        } else {
+            self.set_high();
+        }
+        Ok(())
+    }
+
+    fn value(&self) -> Result<u8, Self::Error> {
+        Ok(u8::from(self.is_high()))
+    }
+}
+
+pub fn display(cols: u8, rows: u16, lut: &[u8]) -> io::Result<Display<'_, PanelInterface>> {
+    let mut spi = open_spi()?;
+    configure_spi(&mut spi)?;
+
+    let cs = output_pin(CS_PIN, 1)?;
+    let busy = input_pin(BUSY_PIN)?;
+    let dc = output_pin(DC_PIN, 1)?;
+    let reset = output_pin(RESET_PIN, 1)?;
+    debug!("Pins configured");
+
+    // Initialise display controller
+    let controller = Interface::new(spi, cs, busy, dc, reset);
+    Ok(display_with(controller, cols, rows, lut))
+}
+
+pub fn open_spi() -> io::Result<Spidev> {
+    Spidev::open(SPI_DEVICE)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", SPI_DEVICE, err)))
+}
+
+pub fn configure_spi(spi: &mut Spidev) -> io::Result<()> {
     let options = SpidevOptions::new()
         .bits_per_word(8)
         .max_speed_hz(4_000_000)
         .mode(spidev::SPI_MODE_0)
         .build();
-    spi.configure(&options).expect("SPI configuration");
-
-    // https://pinout.xyz/pinout/inky_phat
-    // Configure Digital I/O Pins
-    let cs = Pin::new(8); // BCM8
-    cs.export().expect("cs export");
-    while !cs.is_exported() {}
-    cs.set_direction(Direction::Out).expect("CS Direction");
-    cs.set_value(1).expect("CS Value set to 1");
-
-    let busy = Pin::new(17); // BCM17
-    busy.export().expect("busy export");
-    while !busy.is_exported() {}
-    busy.set_direction(Direction::In).expect("busy Direction");
-
-    let dc = Pin::new(22); // BCM22
-    dc.export().expect("dc export");
-    while !dc.is_exported() {}
-    dc.set_direction(Direction::Out).expect("dc Direction");
-    dc.set_value(1).expect("dc Value set to 1");
-
-    let reset = Pin::new(27); // BCM27
-    reset.export().expect("reset export");
-    while !reset.is_exported() {}
+    spi.configure(&options)
+}
+
+/// Export pin `number` as an output, starting at `value`
+pub fn output_pin(number: u64, value: u8) -> io::Result<Pin> {
+    let pin = export(number)?;
+    pin.set_direction(Direction::Out)
+        .and_then(|()| pin.set_value(value))
+        .map_err(|err| gpio_error(number, err))?;
+    Ok(pin)
+}
+
+/// Export pin `number` as an input
+pub fn input_pin(number: u64) -> io::Result<Pin> {
+    let pin = export(number)?;
+    pin.set_direction(Direction::In).map_err(|err| gpio_error(number, err))?;
+    Ok(pin)
+}
+
+fn export(number: u64) -> io::Result<Pin> {
+    let pin = Pin::new(number);
+    pin.export().map_err(|err| gpio_error(number, err))?;
+
+    let started = Instant::now();
+    while !pin.is_exported() {
+        if started.elapsed() > EXPORT_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("BCM{} wasn't exported", number),
+            ));
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    Ok(pin)
+}
+
+/// Reset the controller and wait for it to stop being busy, returning how long that took
+pub fn reset_and_wait<P: Gpio>(
+    reset: &mut P,
+    busy: &P,
+    timeout: Duration,
+) -> io::Result<Duration> {
     reset
-        .set_direction(Direction::Out)
-        .expect("reset Direction");
-    reset.set_value(1).expect("reset Value set to 1");
-    println!("Pins configured");
+        .set(0)
+        .and_then(|()| {
+            thread::sleep(Duration::from_millis(10));
+            reset.set(1)
+        })
+        .map_err(|err| gpio_error(RESET_PIN, err))?;
 
-    // Initialise display controller
-    let controller = ssd1675::Interface::new(spi, cs, busy, dc, reset);
+    let started = Instant::now();
+    while busy.value().map_err(|err| gpio_error(BUSY_PIN, err))? != 0 {
+        if started.elapsed() > timeout {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("still busy after {}s", timeout.as_secs()),
+            ));
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    Ok(started.elapsed())
+}
 
+fn gpio_error<E: fmt::Display>(number: u64, err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("BCM{}: {}", number, err))
+}
+
+/// A display driven through `controller`
+pub fn display_with<I: DisplayInterface>(
+    controller: I,
+    cols: u8,
+    rows: u16,
+    lut: &[u8],
+) -> Display<'_, I> {
     let config = Builder::new()
         .dimensions(Dimensions { rows, cols })
-        .rotation(Rotation::Rotate270)
-        .lut(&LUT)
+        .rotation(ROTATION)
+        .lut(lut)
         .build()
         .expect("invalid configuration");
-    Ok(Display::new(controller, config))
+    Display::new(controller, config)
+}
+
+/// A display that's recorded by `recorder` instead of sent to the panel
+pub fn recorded_display<'a>(
+    recorder: &Recorder,
+    cols: u8,
+    rows: u16,
+    lut: &'a [u8],
+) -> Display<'a, Interface<FakeSpi, FakePin, FakePin, FakePin, FakePin>> {
+    let controller = Interface::new(
+        recorder.spi(),
+        recorder.pin(),
+        recorder.busy(),
+        recorder.dc(),
+        recorder.reset(),
+    );
+    display_with(controller, cols, rows, lut)
 }
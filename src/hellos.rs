@@ -0,0 +1,201 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Where a hello came from
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    /// The form on the web page
+    Web,
+    /// A POST to /hi from something other than the form, like curl
+    Api,
+    /// Someone in front of the badge, E.g. pressing a button
+    InPerson,
+    /// Automated hellos that identify themselves with `?source=script`
+    Script,
+}
+
+impl Source {
+    pub const ALL: [Source; 4] = [Source::Web, Source::Api, Source::InPerson, Source::Script];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Web => "web",
+            Source::Api => "api",
+            Source::InPerson => "in-person",
+            Source::Script => "script",
+        }
+    }
+
+    /// Short name for the display
+    fn abbreviation(self) -> &'static str {
+        match self {
+            Source::Web => "web",
+            Source::Api => "api",
+            Source::InPerson => "irl",
+            Source::Script => "bot",
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Source::Web => 0,
+            Source::Api => 1,
+            Source::InPerson => 2,
+            Source::Script => 3,
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Source::ALL
+            .iter()
+            .cloned()
+            .find(|source| source.name() == s)
+            .ok_or_else(|| format!("unknown hello source '{}'", s))
+    }
+}
+
+/// Hello count for the templates
+pub struct SourceCount {
+    pub name: &'static str,
+    pub count: usize,
+}
+
+/// Total number of hellos and the number from each source.
+///
+/// The total can be more than the sum of the sources as hellos weren't always counted by source.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HelloCounts {
+    total: usize,
+    sources: [usize; 4],
+}
+
+impl HelloCounts {
+    /// A total without the counts from each source, E.g. when it's set by hand
+    pub fn with_total(total: usize) -> Self {
+        HelloCounts {
+            total,
+            sources: [0; 4],
+        }
+    }
+
+    /// Read the counts saved at `path`, none if nothing's been saved yet
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Parse the saved counts: the total on the first line, followed by a line of
+    /// `source count` for each source.
+    pub fn parse(text: &str) -> Self {
+        let mut lines = text.lines();
+        let mut counts = HelloCounts {
+            total: lines
+                .next()
+                .and_then(|total| usize::from_str(total.trim()).ok())
+                .unwrap_or(0),
+            sources: [0; 4],
+        };
+
+        for line in lines {
+            let mut fields = line.split_whitespace();
+            let source = fields.next().and_then(|name| Source::from_str(name).ok());
+            let count = fields.next().and_then(|count| usize::from_str(count).ok());
+            if let (Some(source), Some(count)) = (source, count) {
+                counts.sources[source.index()] = count;
+            }
+        }
+
+        counts
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut text = format!("{}\n", self.total);
+        for &source in Source::ALL.iter() {
+            text.push_str(&format!("{} {}\n", source.name(), self.get(source)));
+        }
+        text
+    }
+
+    pub fn inc(&mut self, source: Source) {
+        self.total += 1;
+        self.sources[source.index()] += 1;
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn get(&self, source: Source) -> usize {
+        self.sources[source.index()]
+    }
+
+    pub fn by_source(&self) -> Vec<SourceCount> {
+        Source::ALL
+            .iter()
+            .map(|&source| SourceCount {
+                name: source.name(),
+                count: self.get(source),
+            })
+            .collect()
+    }
+
+    /// Compact summary of the sources that have said hello, for the display
+    pub fn summary(&self) -> String {
+        Source::ALL
+            .iter()
+            .filter(|&&source| self.get(source) > 0)
+            .map(|&source| format!("{} {}", source.abbreviation(), self.get(source)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl fmt::Display for HelloCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let sources = Source::ALL
+            .iter()
+            .map(|&source| format!("{} {}", self.get(source), source.name()))
+            .collect::<Vec<_>>();
+        f.write_str(&sources.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_total_only() {
+        let counts = HelloCounts::parse("42\n");
+        assert_eq!(counts.total(), 42);
+        assert_eq!(counts.get(Source::Web), 0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut counts = HelloCounts::parse("10");
+        counts.inc(Source::Web);
+        counts.inc(Source::Web);
+        counts.inc(Source::InPerson);
+
+        let parsed = HelloCounts::parse(&counts.serialize());
+        assert_eq!(parsed, counts);
+        assert_eq!(parsed.total(), 13);
+        assert_eq!(parsed.get(Source::Web), 2);
+        assert_eq!(parsed.to_string(), "2 web, 0 api, 1 in-person, 0 script");
+        assert_eq!(parsed.summary(), "web 2 irl 1");
+    }
+}
@@ -0,0 +1,343 @@
+//! Every hello that's been counted, with when it was said, for graphing after the conference.
+//!
+//! The hellos are appended to a file of JSON lines as they're saved. Times are bucketed into hours
+//! and days in the local time zone.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::app::write_file_atomic;
+use crate::hellos::Source;
+
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = 24 * HOUR;
+
+/// A hello that was counted
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    pub source: Source,
+    /// Who said hello, None for hellos in person
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+}
+
+/// Number of hellos in the hour or day starting at `start`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bucket {
+    pub start: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub total: usize,
+    /// Addresses that said hello, not counting hellos in person
+    pub unique_addresses: usize,
+    pub by_source: BTreeMap<&'static str, usize>,
+    /// Days and hours with hellos, oldest first
+    pub per_day: Vec<Bucket>,
+    pub per_hour: Vec<Bucket>,
+    /// The earliest of the hours with the most hellos
+    pub busiest_hour: Option<Bucket>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    records: Vec<Record>,
+    /// Times hellos have been removed, the saved history has to be rewritten after
+    truncations: usize,
+}
+
+impl History {
+    /// Read the hellos saved at `path`, none if nothing's been saved yet
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err),
+        };
+        let records = contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(record) => Some(record),
+                Err(err) => {
+                    warn!("skipping invalid hello in history: {}", err);
+                    None
+                }
+            })
+            .collect();
+
+        Ok(History {
+            records,
+            truncations: 0,
+        })
+    }
+
+    pub fn record(&mut self, record: Record) {
+        self.records.push(record);
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Keep only the first `len` hellos
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.records.len() {
+            self.records.truncate(len);
+            self.truncations += 1;
+        }
+    }
+
+    pub fn truncations(&self) -> usize {
+        self.truncations
+    }
+
+    /// `utc_offset` is how many seconds local time is ahead of UTC
+    pub fn summary(&self, utc_offset: i64) -> Summary {
+        let mut per_day = BTreeMap::new();
+        let mut per_hour = BTreeMap::new();
+        let mut by_source = Source::ALL
+            .iter()
+            .map(|&source| (source.name(), 0))
+            .collect::<BTreeMap<_, _>>();
+        let mut addresses = HashSet::new();
+
+        for record in &self.records {
+            let local = record.time as i64 + utc_offset;
+            *per_day.entry(local.div_euclid(DAY)).or_insert(0) += 1;
+            *per_hour.entry(local.div_euclid(HOUR)).or_insert(0) += 1;
+            *by_source.entry(record.source.name()).or_insert(0) += 1;
+            if let Some(address) = record.address {
+                addresses.insert(address);
+            }
+        }
+
+        let per_day = per_day
+            .into_iter()
+            .map(|(day, count)| Bucket {
+                start: date(day),
+                count,
+            })
+            .collect::<Vec<_>>();
+        let per_hour = per_hour
+            .into_iter()
+            .map(|(hour, count)| Bucket {
+                start: format!("{} {:02}:00", date(hour.div_euclid(24)), hour.rem_euclid(24)),
+                count,
+            })
+            .collect::<Vec<_>>();
+        let busiest_hour = per_hour
+            .iter()
+            .fold(None, |busiest: Option<&Bucket>, bucket| match busiest {
+                Some(busiest) if busiest.count >= bucket.count => Some(busiest),
+                _ => Some(bucket),
+            })
+            .cloned();
+
+        Summary {
+            total: self.records.len(),
+            unique_addresses: addresses.len(),
+            by_source,
+            per_day,
+            per_hour,
+            busiest_hour,
+        }
+    }
+
+    /// Hellos in each hour of the day, as the lines of a text bar chart. The bars are at most
+    /// `width` characters long.
+    pub fn histogram(&self, utc_offset: i64, width: usize) -> Vec<String> {
+        let mut hours = [0; 24];
+        for record in &self.records {
+            let local = record.time as i64 + utc_offset;
+            hours[(local.rem_euclid(DAY) / HOUR) as usize] += 1;
+        }
+
+        let max = hours.iter().cloned().max().unwrap_or(0);
+        let first = hours.iter().position(|&count| count > 0);
+        let last = hours.iter().rposition(|&count| count > 0);
+        match (first, last) {
+            (Some(first), Some(last)) => (first..=last)
+                .map(|hour| {
+                    let bar = "#".repeat((hours[hour] * width).div_ceil(max));
+                    format!("{:02}:00 {:<width$} {}", hour, bar, hours[hour], width = width)
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Add `records` to the end of the history saved at `path`
+pub fn append(path: &Path, records: &[Record]) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(json_lines(records).as_bytes())?;
+    file.sync_data()
+}
+
+/// Replace the history saved at `path` with `records`
+pub fn save(path: &Path, records: &[Record]) -> io::Result<()> {
+    write_file_atomic(path, json_lines(records))
+}
+
+/// One JSON object per line, the same as the saved history
+pub fn json_lines(records: &[Record]) -> String {
+    let mut lines = String::new();
+    for record in records {
+        lines.push_str(&serde_json::to_string(record).expect("unable to serialise hello"));
+        lines.push('\n');
+    }
+    lines
+}
+
+/// CSV with a header, times in UTC
+pub fn csv(records: &[Record]) -> String {
+    let mut csv = "time,source,address\n".to_string();
+    for record in records {
+        let time = record.time as i64;
+        let address = record
+            .address
+            .map(|address| address.to_string())
+            .unwrap_or_default();
+        csv.push_str(&format!(
+            "{}T{:02}:{:02}:{:02}Z,{},{}\n",
+            date(time.div_euclid(DAY)),
+            time.rem_euclid(DAY) / HOUR,
+            time.rem_euclid(HOUR) / 60,
+            time.rem_euclid(60),
+            record.source.name(),
+            address
+        ));
+    }
+    csv
+}
+
+/// The date `days` after the Unix epoch, as YYYY-MM-DD.
+///
+/// See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn date(days: i64) -> String {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months start from March, so the leap day is at the end
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let (year, month) = if month < 10 {
+        (year_of_era + era * 400, month + 3)
+    } else {
+        (year_of_era + era * 400 + 1, month - 9)
+    };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    // 2019-01-22T09:30:00Z, the Tuesday of the conference
+    const TUESDAY: u64 = 1_548_149_400;
+
+    fn history() -> History {
+        let address = |last| Some(IpAddr::from([10, 0, 0, last]));
+        let mut history = History::default();
+        for &(time, source, address) in &[
+            (TUESDAY, Source::Web, address(1)),
+            (TUESDAY + 60, Source::Api, address(2)),
+            (TUESDAY + 3600, Source::Web, address(1)),
+            (TUESDAY + 3660, Source::InPerson, None),
+            (TUESDAY + 24 * 3600, Source::Web, address(3)),
+        ] {
+            history.record(Record {
+                time,
+                source,
+                address,
+            });
+        }
+        history
+    }
+
+    #[test]
+    fn test_date() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(-1), "1969-12-31");
+        assert_eq!(date(17_918), "2019-01-22");
+        assert_eq!(date(11_016), "2000-02-29");
+    }
+
+    #[test]
+    fn test_summary() {
+        let bucket = |start: &str, count| Bucket {
+            start: start.to_string(),
+            count,
+        };
+        // Christchurch was 13 hours ahead of UTC
+        let summary = history().summary(13 * 3600);
+
+        assert_eq!(summary.total, 5);
+        assert_eq!(summary.unique_addresses, 3);
+        assert_eq!(summary.by_source["web"], 3);
+        assert_eq!(summary.by_source["script"], 0);
+        assert_eq!(
+            summary.per_day,
+            vec![bucket("2019-01-22", 4), bucket("2019-01-23", 1)]
+        );
+        assert_eq!(
+            summary.per_hour,
+            vec![
+                bucket("2019-01-22 22:00", 2),
+                bucket("2019-01-22 23:00", 2),
+                bucket("2019-01-23 22:00", 1),
+            ]
+        );
+        assert_eq!(summary.busiest_hour, Some(bucket("2019-01-22 22:00", 2)));
+        assert_eq!(History::default().summary(0).busiest_hour, None);
+    }
+
+    #[test]
+    fn test_histogram() {
+        assert_eq!(
+            history().histogram(0, 6),
+            vec!["09:00 ###### 3", "10:00 ####   2"]
+        );
+        assert!(History::default().histogram(0, 6).is_empty());
+    }
+
+    #[test]
+    fn test_export() {
+        let history = history();
+        let csv = csv(&history.records()[2..4]);
+        assert_eq!(
+            csv,
+            "time,source,address\n\
+             2019-01-22T10:30:00Z,web,10.0.0.1\n\
+             2019-01-22T10:31:00Z,in-person,\n"
+        );
+
+        let path = env::temp_dir().join(format!("lca2019-{}-export-history.jsonl", process::id()));
+        let _ = fs::remove_file(&path);
+        append(&path, &history.records()[..2]).unwrap();
+        append(&path, &history.records()[2..]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap().lines().nth(3),
+            Some(r#"{"time":1548153060,"source":"in-person"}"#)
+        );
+        assert_eq!(History::load(&path).unwrap().records(), history.records());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,270 @@
+//! Blocking requests for talking to other badges and webhooks, made with hyper's client
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use hyper::{header, Body, Client, Method, Request};
+use tokio::runtime::current_thread::Runtime;
+use tokio::timer::Timeout;
+
+// Responses bigger than this are an error, nothing we talk to should send this much
+const MAX_RESPONSE: u64 = 1024 * 1024;
+
+/// An `http://` URL
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    /// Host name or IP, without brackets for IPv6
+    pub host: String,
+    pub port: u16,
+    /// Path and query
+    pub path: String,
+}
+
+impl FromStr for Url {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || invalid_input(&format!("invalid URL '{}'", s));
+        let rest = s.strip_prefix("http://").ok_or_else(|| {
+            invalid_input(&format!("'{}' isn't an http:// URL", s))
+        })?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+
+        // [::1]:8080, 10.0.0.18:8080, or without the port
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let end = bracketed.find(']').ok_or_else(invalid)?;
+            (&bracketed[..end], bracketed[end + 1..].strip_prefix(':'))
+        } else {
+            let mut parts = authority.splitn(2, ':');
+            (parts.next().unwrap_or(""), parts.next())
+        };
+        let port = match port {
+            Some(port) => u16::from_str(port).map_err(|_| invalid())?,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Url {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        if self.host.contains(':') {
+            write!(f, "http://[{}]:{}{}", self.host, self.port, self.path)
+        } else {
+            write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+}
+
+pub fn get(url: &Url, timeout: Duration) -> io::Result<Response> {
+    request(Method::GET, url, None, timeout)
+}
+
+/// POST `body` with the `content_type`
+pub fn post(url: &Url, content_type: &str, body: &[u8], timeout: Duration) -> io::Result<Response> {
+    request(Method::POST, url, Some((content_type, body)), timeout)
+}
+
+/// PUT `body` with the `content_type`
+pub fn put(url: &Url, content_type: &str, body: &[u8], timeout: Duration) -> io::Result<Response> {
+    request(Method::PUT, url, Some((content_type, body)), timeout)
+}
+
+/// Make a request with hyper's client, blocking until the whole response has been read
+fn request(
+    method: Method,
+    url: &Url,
+    body: Option<(&str, &[u8])>,
+    timeout: Duration,
+) -> io::Result<Response> {
+    let mut builder = Request::builder();
+    builder
+        .method(method)
+        .uri(url.to_string())
+        .header(header::USER_AGENT, "lca2019");
+    let request = match body {
+        Some((content_type, body)) => builder
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body.to_vec())),
+        None => builder.body(Body::empty()),
+    }
+    .map_err(|err| invalid_input(&err.to_string()))?;
+
+    let response = Client::new()
+        .request(request)
+        .map_err(hyper_error)
+        .and_then(|response| {
+            let status = response.status().as_u16();
+            response
+                .into_body()
+                .map_err(hyper_error)
+                .fold(Vec::new(), |mut body, chunk| {
+                    body.extend_from_slice(&chunk);
+                    if body.len() as u64 > MAX_RESPONSE {
+                        Err(invalid_data("response is too big"))
+                    } else {
+                        Ok(body)
+                    }
+                })
+                .map(move |body| Response { status, body })
+        });
+
+    Runtime::new()?
+        .block_on(Timeout::new(response, timeout))
+        .map_err(|err| {
+            if err.is_elapsed() {
+                io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", url))
+            } else {
+                err.into_inner().unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::Other, "timer unavailable")
+                })
+            }
+        })
+}
+
+fn hyper_error(err: hyper::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// A request received by `serve`
+    #[derive(Debug)]
+    pub struct Received {
+        pub request_line: String,
+        pub body: Vec<u8>,
+    }
+
+    /// Serve `responses` in order, one per connection, on a local port. Returns the URL of the
+    /// server and the requests it received.
+    pub fn serve(responses: Vec<String>) -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    let lower = line.to_lowercase();
+                    if let Some(length) = lower.strip_prefix("content-length:") {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+                let _ = tx.send(Received {
+                    request_line: request_line.trim().to_string(),
+                    body,
+                });
+            }
+        });
+
+        (url, rx)
+    }
+
+    pub fn ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            Url::from_str("http://10.0.0.18/count").unwrap(),
+            Url {
+                host: "10.0.0.18".to_string(),
+                port: 80,
+                path: "/count".to_string(),
+            }
+        );
+        let ipv6 = Url::from_str("http://[2001:db8::18]:8080").unwrap();
+        assert_eq!((ipv6.host.as_str(), ipv6.port), ("2001:db8::18", 8080));
+        assert_eq!(ipv6.to_string(), "http://[2001:db8::18]:8080/");
+        assert!(Url::from_str("https://example.com/").is_err());
+        assert!(Url::from_str("http://:80/").is_err());
+    }
+
+    #[test]
+    fn test_chunked_response() {
+        let (url, _) = serve(vec![
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n7\r\n, badge\r\n0\r\n\r\n"
+                .to_string(),
+        ]);
+        let response = get(&Url::from_str(&url).unwrap(), Duration::from_secs(5)).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"Hello, badge");
+    }
+
+    #[test]
+    fn test_post() {
+        let (url, requests) = serve(vec![
+            "HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok".to_string(),
+        ]);
+        let url = Url::from_str(&format!("{}hook", url)).unwrap();
+
+        let response = post(&url, "application/json", b"{}", Duration::from_secs(5)).unwrap();
+        assert_eq!(response.status, 201);
+        assert!(response.is_success());
+        assert_eq!(response.body, b"ok");
+
+        let received = requests.recv().unwrap();
+        assert_eq!(received.request_line, "POST /hook HTTP/1.1");
+        assert_eq!(received.body, b"{}");
+    }
+}
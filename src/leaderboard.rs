@@ -0,0 +1,316 @@
+//! Team leaderboard of hello counts, fetched from the `/count` endpoint of other badges
+
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::app::State;
+use crate::display::OWNER;
+use crate::http::{self, Url};
+
+const ONE_HOUR: Duration = Duration::from_secs(60 * 60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Body of `GET /count`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Count {
+    pub name: String,
+    pub hi_count: usize,
+}
+
+/// Samples of a count over the last hour
+#[derive(Debug, Clone, Default)]
+struct History {
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl History {
+    fn record(&mut self, now: Instant, count: usize) {
+        self.samples.push_back((now, count));
+        // Keep the newest sample that's at least an hour old as the baseline
+        while self.samples.len() > 1 && now.duration_since(self.samples[1].0) >= ONE_HOUR {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Increase of `count` over the oldest sample from the last hour
+    fn delta(&self, count: usize) -> usize {
+        self.samples
+            .front()
+            .map(|&(_, baseline)| count.saturating_sub(baseline))
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Member {
+    url: Url,
+    name: Option<String>,
+    count: Option<usize>,
+    updated: Option<Instant>,
+    error: Option<String>,
+    history: History,
+}
+
+/// A row of the leaderboard
+#[derive(Debug, Clone, PartialEq)]
+pub struct Standing {
+    pub rank: usize,
+    pub name: String,
+    /// None if the badge has never been reached
+    pub hi_count: Option<usize>,
+    /// Hellos in the last hour
+    pub delta: usize,
+    pub is_me: bool,
+    /// The count is old because the badge hasn't been reached recently
+    pub stale: bool,
+    pub error: Option<String>,
+}
+
+impl Standing {
+    pub fn count(&self) -> String {
+        self.hi_count
+            .map(|count| count.to_string())
+            .unwrap_or_else(|| "?".to_string())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Leaderboard {
+    members: Vec<Member>,
+    own_history: History,
+    stale_after: Duration,
+}
+
+impl Leaderboard {
+    /// `urls` are the `/count` URLs of the other badges. Counts older than `stale_after` are
+    /// marked as stale.
+    pub fn new(urls: Vec<Url>, stale_after: Duration) -> Self {
+        let members = urls
+            .into_iter()
+            .map(|url| Member {
+                url,
+                name: None,
+                count: None,
+                updated: None,
+                error: None,
+                history: History::default(),
+            })
+            .collect();
+
+        Leaderboard {
+            members,
+            own_history: History::default(),
+            stale_after,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn urls(&self) -> Vec<Url> {
+        self.members.iter().map(|member| member.url.clone()).collect()
+    }
+
+    /// Record the result of fetching `url`. The last good count is kept if it failed.
+    pub fn update(&mut self, url: &Url, result: io::Result<Count>, now: Instant) {
+        let member = match self.members.iter_mut().find(|member| member.url == *url) {
+            Some(member) => member,
+            None => return,
+        };
+
+        match result {
+            Ok(count) => {
+                member.name = Some(count.name);
+                member.count = Some(count.hi_count);
+                member.updated = Some(now);
+                member.error = None;
+                member.history.record(now, count.hi_count);
+            }
+            Err(err) => member.error = Some(err.to_string()),
+        }
+    }
+
+    pub fn record_own(&mut self, hi_count: usize, now: Instant) {
+        self.own_history.record(now, hi_count);
+    }
+
+    /// The team, most hellos first. Badges that have never been reached come last.
+    pub fn standings(&self, hi_count: usize, now: Instant) -> Vec<Standing> {
+        let mut standings = vec![Standing {
+            rank: 0,
+            name: OWNER.to_string(),
+            hi_count: Some(hi_count),
+            delta: self.own_history.delta(hi_count),
+            is_me: true,
+            stale: false,
+            error: None,
+        }];
+        standings.extend(self.members.iter().map(|member| Standing {
+            rank: 0,
+            name: member.name.clone().unwrap_or_else(|| member.url.host.clone()),
+            hi_count: member.count,
+            delta: member
+                .count
+                .map(|count| member.history.delta(count))
+                .unwrap_or(0),
+            is_me: false,
+            stale: member
+                .updated
+                .map(|updated| now.duration_since(updated) > self.stale_after)
+                .unwrap_or(true),
+            error: member.error.clone(),
+        }));
+
+        // Stable so ties keep us first, then the configured order
+        standings.sort_by_key(|standing| Reverse(standing.hi_count));
+        let counts = standings
+            .iter()
+            .map(|standing| standing.hi_count)
+            .collect::<Vec<_>>();
+        for standing in standings.iter_mut() {
+            standing.rank = 1 + counts
+                .iter()
+                .filter(|&&count| count > standing.hi_count)
+                .count();
+        }
+
+        standings
+    }
+}
+
+pub fn fetch(url: &Url) -> io::Result<Count> {
+    let response = http::get(url, FETCH_TIMEOUT)?;
+    if !response.is_success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("HTTP status {}", response.status),
+        ));
+    }
+
+    serde_json::from_slice(&response.body)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Fetch the counts of the team every `interval`, forever
+pub fn run(state: Arc<RwLock<State>>, interval: Duration) {
+    loop {
+        let urls = state.read().expect("poisioned").leaderboard.urls();
+        // Fetch without holding the lock, badges that can't be reached take a while
+        let results = urls
+            .into_iter()
+            .map(|url| {
+                let result = fetch(&url);
+                if let Err(ref err) = result {
+                    warn!("unable to fetch hello count from {}: {}", url, err);
+                }
+                (url, result)
+            })
+            .collect::<Vec<_>>();
+
+        {
+            let mut state = state.write().expect("poisioned");
+            let now = Instant::now();
+            let hi_count = state.hi_count();
+            state.leaderboard.record_own(hi_count, now);
+            for (url, result) in results {
+                state.leaderboard.update(&url, result, now);
+            }
+        }
+
+        thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::{ok, serve};
+    use std::net::TcpListener;
+
+    fn count_url(base: &str) -> Url {
+        format!("{}count", base).parse().unwrap()
+    }
+
+    #[test]
+    fn test_history_delta() {
+        let mut history = History::default();
+        let start = Instant::now();
+        history.record(start, 10);
+        history.record(start + Duration::from_secs(30 * 60), 12);
+        assert_eq!(history.delta(15), 5);
+
+        // The sample at the start drops out once the half hour one is an hour old
+        history.record(start + Duration::from_secs(90 * 60), 20);
+        assert_eq!(history.delta(20), 8);
+    }
+
+    #[test]
+    fn test_leaderboard_with_local_badges() {
+        let (busy, _) = serve(vec![ok(r#"{"name":"Busy Badge","hi_count":50}"#)]);
+        let (quiet, _) = serve(vec![ok(r#"{"name":"Quiet Badge","hi_count":3}"#)]);
+        // A port with nothing listening on it
+        let unreachable = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/", listener.local_addr().unwrap())
+        };
+
+        let urls = vec![count_url(&busy), count_url(&quiet), count_url(&unreachable)];
+        let mut leaderboard = Leaderboard::new(urls.clone(), Duration::from_secs(180));
+        let now = Instant::now();
+        leaderboard.record_own(5, now);
+        for url in &urls {
+            leaderboard.update(url, fetch(url), now);
+        }
+
+        let standings = leaderboard.standings(7, now);
+        let summary = standings
+            .iter()
+            .map(|standing| (standing.rank, standing.count(), standing.is_me))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (1, "50".to_string(), false),
+                (2, "7".to_string(), true),
+                (3, "3".to_string(), false),
+                (4, "?".to_string(), false),
+            ]
+        );
+        assert_eq!(standings[0].name, "Busy Badge");
+        assert_eq!(standings[1].delta, 2);
+        assert!(!standings[0].stale);
+        assert!(standings[3].stale);
+        assert!(standings[3].error.is_some());
+
+        // The cached count is kept, but marked stale, when a badge can't be reached
+        let later = now + Duration::from_secs(200);
+        leaderboard.update(
+            &urls[0],
+            Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+            later,
+        );
+        let standings = leaderboard.standings(7, later);
+        assert_eq!(standings[0].hi_count, Some(50));
+        assert!(standings[0].stale);
+        assert_eq!(standings[0].error, Some("timed out".to_string()));
+    }
+
+    #[test]
+    fn test_fetch_errors() {
+        let (url, _) = serve(vec![
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+            ok("not json"),
+        ]);
+        let url = count_url(&url);
+        assert!(fetch(&url).is_err());
+        assert_eq!(fetch(&url).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
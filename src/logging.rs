@@ -0,0 +1,332 @@
+//! Levelled logging to stderr with per-module filters, as text, for journald, or as JSON lines
+
+use std::cmp::{self, Reverse};
+use std::env;
+use std::io::{self, Write};
+use std::mem;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, log_enabled, Level, LevelFilter, Log, Metadata, Record};
+use nix::libc;
+use serde_json::{json, Map, Value};
+
+pub const DEFAULT_FILTERS: &str = "info";
+/// Target of the HTTP access log, so it can be filtered separately
+pub const ACCESS: &str = "access";
+
+const CRATE: &str = "lca2019";
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    /// Journald if running under systemd with output going to the journal, otherwise text
+    Auto,
+    Text,
+    /// Syslog priority prefixes that journald understands, without the time
+    Journald,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Format::Auto),
+            "text" => Ok(Format::Text),
+            "journald" => Ok(Format::Journald),
+            "json" => Ok(Format::Json),
+            _ => Err(format!(
+                "unknown log format '{}', expected auto, text, journald, or json",
+                s
+            )),
+        }
+    }
+}
+
+impl Format {
+    fn resolve(self) -> Self {
+        match self {
+            // systemd sets JOURNAL_STREAM when stdout or stderr is connected to the journal
+            Format::Auto if env::var_os("JOURNAL_STREAM").is_some() => Format::Journald,
+            Format::Auto => Format::Text,
+            format => format,
+        }
+    }
+}
+
+/// A default level and levels for modules, E.g. `info,display=debug,access=off`.
+///
+/// Modules can be given with or without the crate name. A level applies to the module and the
+/// modules inside it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filters {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl FromStr for Filters {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filters = Filters {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        };
+
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.find('=') {
+                Some(equals) => {
+                    let level = LevelFilter::from_str(directive[equals + 1..].trim())
+                        .map_err(|_| format!("invalid log level in '{}'", directive))?;
+                    let module = directive[..equals].trim().to_string();
+                    filters.modules.push((module, level));
+                }
+                None => {
+                    filters.default = LevelFilter::from_str(directive)
+                        .map_err(|_| format!("invalid log level '{}'", directive))?;
+                }
+            }
+        }
+
+        // Most specific first
+        filters
+            .modules
+            .sort_by_key(|(module, _)| Reverse(module.len()));
+        Ok(filters)
+    }
+}
+
+impl Filters {
+    pub fn level(&self, target: &str) -> LevelFilter {
+        let local = target
+            .strip_prefix(CRATE)
+            .and_then(|rest| rest.strip_prefix("::"));
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                in_module(target, module) || local.is_some_and(|local| in_module(local, module))
+            })
+            .map(|&(_, level)| level)
+            .unwrap_or(self.default)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, cmp::max)
+    }
+}
+
+fn in_module(target: &str, module: &str) -> bool {
+    target == module
+        || target
+            .strip_prefix(module)
+            .is_some_and(|rest| rest.starts_with("::"))
+}
+
+struct Logger {
+    filters: Filters,
+    format: Format,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filters.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format_line(
+            self.format,
+            SystemTime::now(),
+            record.level(),
+            record.target(),
+            &record.args().to_string(),
+        );
+        let stderr = io::stderr();
+        let _ = writeln!(stderr.lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+/// Install the logger. `format` is resolved here so that `Auto` picks up the environment.
+pub fn init(filters: Filters, format: Format) -> Result<(), log::SetLoggerError> {
+    let format = format.resolve();
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+    log::set_max_level(filters.max());
+    log::set_boxed_logger(Box::new(Logger { filters, format }))
+}
+
+/// Log an HTTP request to the access log
+pub fn access(remote: IpAddr, method: &str, path: &str, status: u16, latency: Duration) {
+    if !log_enabled!(target: ACCESS, Level::Info) {
+        return;
+    }
+
+    let latency_ms = latency.as_secs() as f64 * 1000. + f64::from(latency.subsec_micros()) / 1000.;
+    if JSON.load(Ordering::Relaxed) {
+        // Separate fields instead of a message to parse
+        let mut fields = Map::new();
+        fields.insert("remote".to_string(), json!(remote.to_string()));
+        fields.insert("method".to_string(), json!(method));
+        fields.insert("path".to_string(), json!(path));
+        fields.insert("status".to_string(), json!(status));
+        fields.insert("latency_ms".to_string(), json!(latency_ms));
+        let line = json_line(SystemTime::now(), Level::Info, ACCESS, None, fields);
+        let stderr = io::stderr();
+        let _ = writeln!(stderr.lock(), "{}", line);
+    } else {
+        info!(
+            target: ACCESS,
+            "{} \"{} {}\" {} {:.1}ms", remote, method, path, status, latency_ms
+        );
+    }
+}
+
+fn format_line(
+    format: Format,
+    time: SystemTime,
+    level: Level,
+    target: &str,
+    message: &str,
+) -> String {
+    // The crate name is the same on every line
+    let short_target = target
+        .strip_prefix(CRATE)
+        .and_then(|rest| rest.strip_prefix("::"))
+        .unwrap_or(target);
+
+    match format {
+        Format::Journald => format!(
+            "<{}>{}: {}",
+            priority(level),
+            short_target,
+            message
+        ),
+        Format::Json => json_line(time, level, target, Some(message), Map::new()),
+        Format::Text | Format::Auto => format!(
+            "{} {:<5} {}: {}",
+            timestamp(time),
+            level,
+            short_target,
+            message
+        ),
+    }
+}
+
+fn json_line(
+    time: SystemTime,
+    level: Level,
+    target: &str,
+    message: Option<&str>,
+    fields: Map<String, Value>,
+) -> String {
+    let mut line = Map::new();
+    line.insert("time".to_string(), json!(timestamp(time)));
+    line.insert("level".to_string(), json!(level.to_string().to_lowercase()));
+    line.insert("target".to_string(), json!(target));
+    if let Some(message) = message {
+        line.insert("message".to_string(), json!(message));
+    }
+    line.extend(fields);
+
+    Value::Object(line).to_string()
+}
+
+/// Syslog priority, see sd-daemon(3)
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// RFC 3339 time in UTC, with milliseconds
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    unsafe { libc::gmtime_r(&secs, &mut tm) };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time() -> SystemTime {
+        // 2019-01-21 10:30:00.250 UTC, the first day of linux.conf.au 2019
+        UNIX_EPOCH + Duration::from_millis(1_548_066_600_250)
+    }
+
+    #[test]
+    fn test_filters() {
+        let filters = Filters::from_str("warn,display=debug,lca2019::mqtt=off,access=info").unwrap();
+        assert_eq!(filters.level("lca2019::display"), LevelFilter::Debug);
+        assert_eq!(filters.level("lca2019::display::inner"), LevelFilter::Debug);
+        assert_eq!(filters.level("lca2019::displayed"), LevelFilter::Warn);
+        assert_eq!(filters.level("lca2019::mqtt"), LevelFilter::Off);
+        assert_eq!(filters.level("access"), LevelFilter::Info);
+        assert_eq!(filters.level("hyper::proto"), LevelFilter::Warn);
+        assert_eq!(filters.max(), LevelFilter::Debug);
+
+        assert_eq!(
+            Filters::from_str("").unwrap().level("lca2019"),
+            LevelFilter::Info
+        );
+        assert!(Filters::from_str("display=loud").is_err());
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(time()), "2019-01-21T10:30:00.250Z");
+    }
+
+    #[test]
+    fn test_format_line() {
+        let line = |format| {
+            format_line(format, time(), Level::Warn, "lca2019::display", "Too cold")
+        };
+
+        assert_eq!(
+            line(Format::Text),
+            "2019-01-21T10:30:00.250Z WARN  display: Too cold"
+        );
+        assert_eq!(line(Format::Journald), "<4>display: Too cold");
+
+        let json: Value = serde_json::from_str(&line(Format::Json)).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "time": "2019-01-21T10:30:00.250Z",
+                "level": "warn",
+                "target": "lca2019::display",
+                "message": "Too cold",
+            })
+        );
+    }
+}
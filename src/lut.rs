@@ -0,0 +1,404 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+pub const LUT_LEN: usize = 70;
+const PHASES: usize = 7;
+const VOLTAGE_LEN: usize = 5 * PHASES;
+const TIMING_WIDTH: usize = 5;
+
+#[rustfmt::skip]
+const DEFAULT: [u8; LUT_LEN] = [
+    // Phase 0     Phase 1     Phase 2     Phase 3     Phase 4     Phase 5     Phase 6
+    // A B C D     A B C D     A B C D     A B C D     A B C D     A B C D     A B C D
+    0b01001000, 0b10100000, 0b00010000, 0b00010000, 0b00010011, 0b00000000, 0b00000000,  // LUT0 - Black
+    0b01001000, 0b10100000, 0b10000000, 0b00000000, 0b00000011, 0b00000000, 0b00000000,  // LUTT1 - White
+    0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,  // IGNORE
+    0b01001000, 0b10100101, 0b00000000, 0b10111011, 0b00000000, 0b00000000, 0b00000000,  // LUT3 - Red
+    0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,  // LUT4 - VCOM
+
+    // Duration            |  Repeat
+    // A   B     C     D   |
+    64,   12,   32,   12,    6,   // 0 Flash
+    16,   8,    4,    4,     6,   // 1 clear
+    4,    8,    8,    16,    16,  // 2 bring in the black
+    2,    2,    2,    64,    32,  // 3 time for red
+    2,    2,    2,    2,     2,   // 4 final black sharpen phase
+    0,    0,    0,    0,     0,   // 5
+    0,    0,    0,    0,     0    // 6
+];
+
+// Shorter flash and red phases. Less saturated red but roughly half the refresh time.
+#[rustfmt::skip]
+const FAST: [u8; LUT_LEN] = [
+    // Phase 0     Phase 1     Phase 2     Phase 3     Phase 4     Phase 5     Phase 6
+    // A B C D     A B C D     A B C D     A B C D     A B C D     A B C D     A B C D
+    0b01001000, 0b10100000, 0b00010000, 0b00010000, 0b00010011, 0b00000000, 0b00000000,  // LUT0 - Black
+    0b01001000, 0b10100000, 0b10000000, 0b00000000, 0b00000011, 0b00000000, 0b00000000,  // LUTT1 - White
+    0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,  // IGNORE
+    0b01001000, 0b10100101, 0b00000000, 0b10111011, 0b00000000, 0b00000000, 0b00000000,  // LUT3 - Red
+    0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,  // LUT4 - VCOM
+
+    // Duration            |  Repeat
+    // A   B     C     D   |
+    32,   8,    16,   8,     2,   // 0 Flash
+    8,    4,    2,    2,     2,   // 1 clear
+    4,    4,    4,    8,     8,   // 2 bring in the black
+    2,    2,    2,    32,    12,  // 3 time for red
+    2,    2,    2,    2,     1,   // 4 final black sharpen phase
+    0,    0,    0,    0,     0,   // 5
+    0,    0,    0,    0,     0    // 6
+];
+
+// More repeats of the black, red and sharpen phases to reduce ghosting.
+#[rustfmt::skip]
+const HIGH_CONTRAST: [u8; LUT_LEN] = [
+    // Phase 0     Phase 1     Phase 2     Phase 3     Phase 4     Phase 5     Phase 6
+    // A B C D     A B C D     A B C D     A B C D     A B C D     A B C D     A B C D
+    0b01001000, 0b10100000, 0b00010000, 0b00010000, 0b00010011, 0b00000000, 0b00000000,  // LUT0 - Black
+    0b01001000, 0b10100000, 0b10000000, 0b00000000, 0b00000011, 0b00000000, 0b00000000,  // LUTT1 - White
+    0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,  // IGNORE
+    0b01001000, 0b10100101, 0b00000000, 0b10111011, 0b00000000, 0b00000000, 0b00000000,  // LUT3 - Red
+    0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,  // LUT4 - VCOM
+
+    // Duration            |  Repeat
+    // A   B     C     D   |
+    64,   12,   32,   12,    8,   // 0 Flash
+    16,   8,    4,    4,     8,   // 1 clear
+    4,    8,    8,    16,    24,  // 2 bring in the black
+    2,    2,    2,    64,    48,  // 3 time for red
+    2,    2,    2,    2,     6,   // 4 final black sharpen phase
+    0,    0,    0,    0,     0,   // 5
+    0,    0,    0,    0,     0    // 6
+];
+
+// The particles move slower in the cold, so everything takes longer. Red in particular.
+#[rustfmt::skip]
+const COLD: [u8; LUT_LEN] = [
+    // Phase 0     Phase 1     Phase 2     Phase 3     Phase 4     Phase 5     Phase 6
+    // A B C D     A B C D     A B C D     A B C D     A B C D     A B C D     A B C D
+    0b01001000, 0b10100000, 0b00010000, 0b00010000, 0b00010011, 0b00000000, 0b00000000,  // LUT0 - Black
+    0b01001000, 0b10100000, 0b10000000, 0b00000000, 0b00000011, 0b00000000, 0b00000000,  // LUTT1 - White
+    0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,  // IGNORE
+    0b01001000, 0b10100101, 0b00000000, 0b10111011, 0b00000000, 0b00000000, 0b00000000,  // LUT3 - Red
+    0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,  // LUT4 - VCOM
+
+    // Duration            |  Repeat
+    // A   B     C     D   |
+    64,   16,   48,   16,    8,   // 0 Flash
+    24,   12,   8,    8,     8,   // 1 clear
+    8,    12,   12,   24,    16,  // 2 bring in the black
+    4,    4,    4,    96,    48,  // 3 time for red
+    4,    4,    4,    4,     4,   // 4 final black sharpen phase
+    0,    0,    0,    0,     0,   // 5
+    0,    0,    0,    0,     0    // 6
+];
+
+// Black and white only, for partial updates. The red LUT is empty so red pixels are left as they
+// were. Ghosts a little, so a full refresh is needed every so often.
+#[rustfmt::skip]
+const BLACK_ONLY: [u8; LUT_LEN] = [
+    // Phase 0     Phase 1     Phase 2     Phase 3     Phase 4     Phase 5     Phase 6
+    // A B C D     A B C D     A B C D     A B C D     A B C D     A B C D     A B C D
+    0b01001000, 0b10100000, 0b00010000, 0b00000000, 0b00010011, 0b00000000, 0b00000000,  // LUT0 - Black
+    0b01001000, 0b10100000, 0b10000000, 0b00000000, 0b00000011, 0b00000000, 0b00000000,  // LUTT1 - White
+    0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,  // IGNORE
+    0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,  // LUT3 - Red
+    0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000,  // LUT4 - VCOM
+
+    // Duration            |  Repeat
+    // A   B     C     D   |
+    16,   4,    8,    4,     1,   // 0 Flash
+    8,    4,    2,    2,     1,   // 1 clear
+    4,    4,    4,    8,     4,   // 2 bring in the black
+    0,    0,    0,    0,     0,   // 3 no red
+    2,    2,    2,    2,     1,   // 4 final black sharpen phase
+    0,    0,    0,    0,     0,   // 5
+    0,    0,    0,    0,     0    // 6
+];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Preset {
+    Default,
+    Fast,
+    HighContrast,
+    Cold,
+    BlackOnly,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 5] = [
+        Preset::Default,
+        Preset::Fast,
+        Preset::HighContrast,
+        Preset::Cold,
+        Preset::BlackOnly,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Default => "default",
+            Preset::Fast => "fast",
+            Preset::HighContrast => "high-contrast",
+            Preset::Cold => "cold",
+            Preset::BlackOnly => "black-only",
+        }
+    }
+
+    fn bytes(self) -> &'static [u8; LUT_LEN] {
+        match self {
+            Preset::Default => &DEFAULT,
+            Preset::Fast => &FAST,
+            Preset::HighContrast => &HIGH_CONTRAST,
+            Preset::Cold => &COLD,
+            Preset::BlackOnly => &BLACK_ONLY,
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Preset::ALL
+            .iter()
+            .cloned()
+            .find(|preset| preset.name() == s)
+            .ok_or_else(|| invalid_data(format!("unknown LUT preset '{}'", s)))
+    }
+}
+
+/// Waveform look up table for the SSD1675
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lut {
+    name: String,
+    bytes: [u8; LUT_LEN],
+}
+
+impl Lut {
+    pub fn preset(preset: Preset) -> Self {
+        Lut {
+            name: preset.name().to_string(),
+            bytes: *preset.bytes(),
+        }
+    }
+
+    /// Use the named preset if there is one, otherwise load `spec` as a file
+    pub fn resolve(spec: &str) -> io::Result<Self> {
+        match Preset::from_str(spec) {
+            Ok(preset) => Ok(Self::preset(preset)),
+            Err(_) => Self::load(Path::new(spec)),
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&path.display().to_string(), &text)
+    }
+
+    /// Parse a LUT in one of two formats:
+    ///
+    /// * Annotated text: comma separated values in the same layout as the built-in
+    ///   presets. Values may be binary (`0b`), hex (`0x`), or decimal.
+    /// * Hex: pairs of hex digits, optionally separated by whitespace.
+    ///
+    /// In both formats `//` and `#` start a comment that runs to the end of the line.
+    pub fn parse(name: &str, text: &str) -> io::Result<Self> {
+        let content = text
+            .lines()
+            .map(|line| {
+                let end = line
+                    .find("//")
+                    .into_iter()
+                    .chain(line.find('#'))
+                    .min()
+                    .unwrap_or(line.len());
+                &line[..end]
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let values = if content.contains(',') {
+            parse_annotated(&content)?
+        } else {
+            parse_hex(&content)?
+        };
+
+        if values.len() != LUT_LEN {
+            return Err(invalid_data(format!(
+                "LUT {} has {} bytes, expected {}",
+                name,
+                values.len(),
+                LUT_LEN
+            )));
+        }
+
+        let mut bytes = [0; LUT_LEN];
+        bytes.copy_from_slice(&values);
+        let lut = Lut {
+            name: name.to_string(),
+            bytes,
+        };
+        lut.validate()?;
+
+        Ok(lut)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        let mut any_phase = false;
+
+        for phase in 0..PHASES {
+            let drives = (0..5).any(|lut| self.bytes[lut * PHASES + phase] != 0);
+            let timing = self.timing(phase);
+            let duration = timing[..4].iter().any(|&tp| tp != 0);
+
+            if drives && !duration {
+                return Err(invalid_data(format!(
+                    "LUT {} phase {} drives the panel but has no duration",
+                    self.name, phase
+                )));
+            }
+            any_phase |= duration;
+        }
+
+        if !any_phase {
+            return Err(invalid_data(format!("LUT {} has no phases", self.name)));
+        }
+
+        Ok(())
+    }
+
+    /// Durations A-D and the repeat count of a phase
+    pub fn timing(&self, phase: usize) -> &[u8] {
+        let start = VOLTAGE_LEN + phase * TIMING_WIDTH;
+        &self.bytes[start..start + TIMING_WIDTH]
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl fmt::Display for Lut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.write_str(&self.name)
+    }
+}
+
+fn parse_annotated(content: &str) -> io::Result<Vec<u8>> {
+    content
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|token| !token.is_empty())
+        .map(|token| {
+            let parsed = if let Some(binary) = token.strip_prefix("0b") {
+                u8::from_str_radix(binary, 2)
+            } else if let Some(hex) = token.strip_prefix("0x") {
+                u8::from_str_radix(hex, 16)
+            } else {
+                u8::from_str(token)
+            };
+            parsed.map_err(|_| invalid_data(format!("invalid LUT value '{}'", token)))
+        })
+        .collect()
+}
+
+fn parse_hex(content: &str) -> io::Result<Vec<u8>> {
+    let digits = content
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<Vec<_>>();
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = pair.iter().collect::<String>();
+            u8::from_str_radix(&pair, 16)
+                .ok()
+                .filter(|_| pair.len() == 2)
+                .ok_or_else(|| invalid_data(format!("invalid LUT hex byte '{}'", pair)))
+        })
+        .collect()
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_annotated() {
+        let text = "
+            // Phase 0 .. Phase 6
+            0b01001000, 0b10100000, 0b00010000, 0b00010000, 0b00010011, 0b00000000, 0b00000000,  // LUT0 - Black
+            0b01001000, 0b10100000, 0b10000000, 0b00000000, 0b00000011, 0b00000000, 0b00000000,  // LUTT1 - White
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  # IGNORE
+            0b01001000, 0b10100101, 0b00000000, 0b10111011, 0b00000000, 0b00000000, 0b00000000,  // LUT3 - Red
+            0, 0, 0, 0, 0, 0, 0,  // LUT4 - VCOM
+
+            64,   12,   32,   12,    6,   // 0 Flash
+            16,   8,    4,    4,     6,   // 1 clear
+            4,    8,    8,    16,    16,  // 2 bring in the black
+            2,    2,    2,    64,    32,  // 3 time for red
+            2,    2,    2,    2,     2,   // 4 final black sharpen phase
+            0,    0,    0,    0,     0,   // 5
+            0,    0,    0,    0,     0    // 6
+        ";
+
+        let lut = Lut::parse("test", text).unwrap();
+        assert_eq!(lut.as_bytes(), Lut::preset(Preset::Default).as_bytes());
+        assert_eq!(lut.timing(3), &[2, 2, 2, 64, 32]);
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        let hex = DEFAULT
+            .chunks(7)
+            .map(|row| {
+                row.iter()
+                    .map(|byte| format!("{:02X}", byte))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let lut = Lut::parse("test", &hex).unwrap();
+        assert_eq!(lut.as_bytes(), &DEFAULT[..]);
+    }
+
+    #[test]
+    fn test_parse_wrong_length() {
+        let err = Lut::parse("test", "48 A0 10").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_parse_phase_without_duration() {
+        let mut bytes = DEFAULT;
+        bytes[5] = 0b01000000; // Drive phase 5, which has no duration
+        let hex = bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        assert!(Lut::parse("test", &hex).is_err());
+    }
+
+    #[test]
+    fn test_presets_are_valid() {
+        for &preset in Preset::ALL.iter() {
+            let lut = Lut::preset(preset);
+            assert!(lut.validate().is_ok(), "{} is invalid", preset.name());
+            assert_eq!(Preset::from_str(preset.name()).unwrap(), preset);
+        }
+    }
+}
@@ -1,60 +1,220 @@
+mod activity;
+mod address;
 mod app;
+mod buttons;
+mod commands;
+mod config;
+mod decoder;
+mod display;
 mod hardware;
+mod hellos;
+mod history;
+mod http;
+mod leaderboard;
+mod logging;
+mod lut;
+mod mdns;
+mod metrics;
+mod mqtt;
+mod peers;
+mod persist;
+mod png;
+mod selftest;
+mod signals;
+mod systemd;
 mod system;
+mod temperature;
+mod wear;
+mod webhooks;
 mod webserver;
 
+use log::{debug, error, info, warn};
+use nix::sys::signal::Signal;
 use structopt::StructOpt;
 
 use linux_embedded_hal::Delay;
 
-use ssd1675::{Color, GraphicDisplay};
-
-// Graphics
-use embedded_graphics::coord::Coord;
-use embedded_graphics::prelude::*;
-use embedded_graphics::Drawing;
-use qrcode::{EcLevel, QrCode};
-
-// Font
-use profont::{ProFont12Point, ProFont14Point, ProFont24Point};
+use ssd1675::GraphicDisplay;
 
 // HTTP Server
-use futures::Future;
+use futures::sync::oneshot;
+use futures::{Future, Stream};
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
-use std::net::SocketAddr;
-
-// System info
-use systemstat::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+use tokio::timer::Interval;
 
 use std::alloc;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
 use std::sync::{Arc, RwLock};
 use std::thread;
-use std::time::Duration;
-
-use crate::app::State;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::app::{
+    State, HISTORY_PATH, HI_COUNT_PATH, PEERS_PATH, REFRESH_COUNT_PATH, WEBHOOK_QUEUE_PATH,
+};
+use crate::buttons::{ButtonConfig, Buttons, SysfsPins};
+use crate::config::{Config, Watcher};
+use crate::display::{DisplayState, RefreshKind, RefreshPolicy, RefreshTracker, COLS, ROWS};
+use crate::history::History;
+use crate::http::Url;
+use crate::leaderboard::Leaderboard;
+use crate::lut::{Lut, Preset};
+use crate::metrics::Metrics;
+use crate::peers::{Exchange, Identity, Peers};
+use crate::persist::Persister;
+use crate::signals::Signals;
+use crate::systemd::Watchdog;
+use crate::temperature::{preset_for, Celsius, Compensation};
+use crate::wear::{RefreshBudget, RefreshCount};
+use crate::webhooks::{Target, Webhooks};
 
 #[global_allocator]
 static GLOBAL: alloc::System = alloc::System;
 
-const ROWS: u16 = 212;
-const COLS: u8 = 104;
-const LISTEN_ADDR: &str = "0.0.0.0";
-const QR_X: i32 = 150;
-const QR_Y: i32 = 30;
+#[derive(StructOpt, Debug)]
+#[structopt(name = "lca2019", about = "linux.conf.au 2019 conference badge.")]
+struct Cli {
+    /// Log levels: a default and per-module levels, E.g. info,display=debug,access=off
+    #[structopt(
+        long,
+        env = "LCA2019_LOG",
+        raw(default_value = "logging::DEFAULT_FILTERS")
+    )]
+    log: logging::Filters,
+
+    /// Log format: auto (journald when run by systemd, otherwise text), text, journald, or json
+    #[structopt(long, default_value = "auto")]
+    log_format: logging::Format,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Update the display and serve the web pages, what the badge normally does
+    #[structopt(name = "serve")]
+    Serve(ServeOptions),
+    /// Draw what the display would show to a PNG
+    #[structopt(name = "render")]
+    Render(commands::RenderOptions),
+    /// Get, set, or reset the hello count
+    #[structopt(name = "count")]
+    Count(commands::CountOptions),
+    /// Print the saved state as JSON
+    #[structopt(name = "export")]
+    Export,
+    /// Print the saved hellos, or a summary of them
+    #[structopt(name = "hellos")]
+    Hellos(commands::HellosOptions),
+    /// Check the display hardware and draw test patterns on it
+    #[structopt(name = "selftest")]
+    Selftest(selftest::SelftestOptions),
+}
 
 #[derive(StructOpt, Debug, Clone)]
-#[structopt(name = "lca2019", about = "linux.conf.au 2019 conference badge.")]
-struct Options {
+struct ServeOptions {
     /// HTTP server port
     #[structopt(short, long, default_value = "80")]
     port: u16,
 
-    /// Interface whose IP is shown on the display
-    #[structopt(short, long, default_value = "wlan0")]
-    interface: String,
+    /// Interfaces to show the address of, in order of preference. Each one matches interfaces
+    /// starting with it, E.g. wlan matches wlan0
+    #[structopt(
+        short,
+        long = "interface",
+        raw(default_value = "address::DEFAULT_INTERFACES", use_delimiter = "true")
+    )]
+    interfaces: Vec<String>,
+
+    /// Show <hostname>.local on the display instead of the IP address
+    #[structopt(long)]
+    show_hostname: bool,
+
+    /// Seconds before another hello from the same address is counted
+    #[structopt(long, default_value = "3600")]
+    dedup_window: u64,
+
+    /// File of settings to use instead of the options above, reloaded on SIGHUP. See the README.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Also reload --config when the file changes
+    #[structopt(long)]
+    watch_config: bool,
+
+    /// Advertise the web server with mDNS and answer for <hostname>.local
+    #[structopt(long)]
+    mdns: bool,
+
+    /// Find other badges on the network and exchange hellos with them
+    #[structopt(long)]
+    peers: bool,
+
+    /// UDP port to exchange hellos with other badges on
+    #[structopt(long, default_value = "41019")]
+    peer_port: u16,
+
+    /// Another badge on the team to show on the leaderboard, E.g. http://10.0.0.20/
+    #[structopt(long = "team")]
+    team: Vec<Url>,
+
+    /// Seconds between fetching the hello counts of the team
+    #[structopt(long, default_value = "60")]
+    team_interval: u64,
+
+    /// URL to POST a JSON event to for each hello. Prefix it with thresholds= to only send
+    /// threshold events, E.g. thresholds=http://10.0.0.5/hook
+    #[structopt(long = "webhook")]
+    webhooks: Vec<Target>,
+
+    /// Hello counts to send a threshold event to the webhooks at, E.g. 100,250,500
+    #[structopt(long, raw(use_delimiter = "true"))]
+    webhook_thresholds: Vec<usize>,
+
+    /// MQTT broker to publish the state of the badge to, E.g. mqtt://10.0.0.5 or
+    /// mqtts://broker.local for TLS
+    #[structopt(long)]
+    mqtt: Option<mqtt::Broker>,
+
+    /// Prefix of the MQTT topics, defaults to badge/<hostname>
+    #[structopt(long)]
+    mqtt_topic: Option<String>,
+
+    /// User name to log in to the MQTT broker with
+    #[structopt(long)]
+    mqtt_username: Option<String>,
+
+    /// Password to log in to the MQTT broker with
+    #[structopt(long, env = "MQTT_PASSWORD")]
+    mqtt_password: Option<String>,
+
+    /// CA certificate (PEM) to trust for mqtts:// brokers, in addition to the system ones
+    #[structopt(long, parse(from_os_str))]
+    mqtt_ca: Option<PathBuf>,
+
+    /// Power supply to read the battery level from, E.g. /sys/class/power_supply/BAT0, or a
+    /// file containing the percentage
+    #[structopt(long, parse(from_os_str))]
+    battery: Option<PathBuf>,
+
+    /// Message to show on the display when shutting down, E.g. "Badge offline"
+    #[structopt(long)]
+    offline_message: Option<String>,
+
+    /// Milliseconds to wait for hellos to stop before saving the hello count
+    #[structopt(long, default_value = "2000")]
+    save_delay_ms: u64,
+
+    /// Save the hello count at least this often in seconds, while hellos keep coming
+    #[structopt(long, default_value = "60")]
+    save_interval: u64,
 
     /// Don't try to update the ePaper display
     #[structopt(short, long)]
@@ -67,176 +227,413 @@ struct Options {
     /// Don't loop, just run once and exit
     #[structopt(short = "o", long)]
     oneshot: bool,
-}
 
-#[derive(Debug, PartialEq)]
-struct DisplayState {
-    hi_count: usize,
-    ip: Option<Ipv4Addr>,
+    /// Show where hellos came from on the display
+    #[structopt(long)]
+    show_sources: bool,
+
+    /// Waveform LUT: auto, a preset (default, fast, high-contrast, cold), or a path to a LUT file
+    #[structopt(short, long, default_value = "auto")]
+    lut: String,
+
+    /// Waveform LUT for partial updates: a preset or a path to a LUT file
+    #[structopt(long, default_value = "black-only")]
+    partial_lut: String,
+
+    /// Do a full refresh after this many partial ones, 0 to disable partial refreshes
+    #[structopt(long, default_value = "10")]
+    full_refresh_every: u32,
+
+    /// Do a full refresh after this many seconds if there have been partial ones
+    #[structopt(long, default_value = "3600")]
+    full_refresh_interval: u64,
+
+    /// Maximum number of display refreshes in an hour
+    #[structopt(
+        long,
+        default_value = "30",
+        parse(try_from_str = "wear::parse_max_per_hour")
+    )]
+    max_refreshes_per_hour: usize,
+
+    /// Minimum number of seconds between display refreshes
+    #[structopt(long, default_value = "30")]
+    min_refresh_interval: u64,
+
+    /// Button on a GPIO pin: PIN=SHORT[,LONG] where the short and long press actions are one of
+    /// next-screen, contact, leaderboard, refresh, or hello. E.g. 5=next-screen,refresh
+    #[structopt(short, long = "button")]
+    buttons: Vec<ButtonConfig>,
+
+    /// Buttons read high when pressed, instead of low
+    #[structopt(long)]
+    buttons_active_high: bool,
+
+    /// Milliseconds a button must be held for a long press
+    #[structopt(long, default_value = "1000")]
+    long_press_ms: u64,
+
+    /// File to read the panel or ambient temperature from. Without one the LUT isn't chosen by
+    /// temperature and the display is always updated.
+    #[structopt(long, parse(from_os_str))]
+    sensor: Option<PathBuf>,
+
+    /// Degrees added to the sensor reading to get the panel temperature
+    #[structopt(long, default_value = "0", raw(allow_hyphen_values = "true"))]
+    sensor_offset: f32,
+
+    /// Don't update the display below this temperature (°C)
+    #[structopt(long, default_value = "0", raw(allow_hyphen_values = "true"))]
+    min_temp: f32,
+
+    /// Don't update the display above this temperature (°C)
+    #[structopt(long, default_value = "40", raw(allow_hyphen_values = "true"))]
+    max_temp: f32,
 }
 
 fn main() -> Result<(), std::io::Error> {
-    let options = Options::from_args();
+    let cli = Cli::from_args();
+    logging::init(cli.log, cli.log_format).expect("unable to set up logging");
+
+    match cli.command {
+        Command::Serve(options) => serve(options),
+        Command::Render(options) => commands::render(&options),
+        Command::Count(options) => commands::count(&options),
+        Command::Export => commands::export(),
+        Command::Hellos(options) => commands::hellos(&options),
+        Command::Selftest(options) => selftest::selftest(&options),
+    }
+}
+
+fn serve(options: ServeOptions) -> io::Result<()> {
+    // Before any threads are started so that they all leave the signals to the shutdown thread
+    let signals = Signals::block()?;
+
+    let save_path = Path::new(HI_COUNT_PATH);
+    let refresh_count_path = Path::new(REFRESH_COUNT_PATH);
+    let defaults = Config {
+        interfaces: options.interfaces.clone(),
+        dedup_window: Duration::from_secs(options.dedup_window),
+        show_hostname: options.show_hostname,
+        show_sources: options.show_sources,
+    };
+    let config = match options.config {
+        Some(ref path) => defaults.load(path)?,
+        None => defaults.clone(),
+    };
+
+    let history_path = Path::new(HISTORY_PATH);
+    let mut state = State::load(&save_path, config, options.battery.clone())?;
+    state.set_history(History::load(history_path)?);
+    let state = Arc::new(RwLock::new(state));
+
+    let persister = Arc::new(Persister::new(
+        save_path.to_path_buf(),
+        history_path.to_path_buf(),
+        &state.read().expect("poisioned"),
+        Duration::from_millis(options.save_delay_ms),
+        Duration::from_secs(options.save_interval),
+    ));
+    {
+        let (changed_tx, changed_rx) = mpsc::channel();
+        state.write().expect("poisioned").changed_tx = Some(changed_tx);
+        let persister = persister.clone();
+        let state = state.clone();
+        thread::spawn(move || persister.run(&state, changed_rx));
+    }
 
-    let save_path = Path::new("hi_count.txt");
-    let hello_max_age = Duration::from_secs(60 * 60);
+    let (wake, wake_rx) = mpsc::channel();
+    let metrics = Arc::new(Metrics::new());
+    let stopping = Arc::new(AtomicBool::new(false));
+    let (stop_server, stop_server_rx) = oneshot::channel::<()>();
+    let stop_server_rx = stop_server_rx.shared();
+    let (stopped, stopped_rx) = mpsc::channel::<()>();
+
+    {
+        let state = state.clone();
+        let wake = wake.clone();
+        let stopping = stopping.clone();
+        let offline_message = options.offline_message.clone();
+        let config_path = options.config.clone();
+        let defaults = defaults.clone();
+        thread::spawn(move || {
+            loop {
+                match signals.wait() {
+                    Ok(Signal::SIGHUP) => match config_path {
+                        Some(ref path) => reload_config(path, &defaults, &state, &wake),
+                        None => warn!("Received SIGHUP without --config, nothing to reload"),
+                    },
+                    Ok(signal) => {
+                        info!("Received {:?}, shutting down", signal);
+                        let _ = systemd::notify("STOPPING=1");
+                        break;
+                    }
+                    Err(err) => {
+                        error!("unable to wait for signals: {}", err);
+                        return;
+                    }
+                }
+            }
 
-    let state = Arc::new(RwLock::new(State::load(
-        &save_path,
-        options.interface.clone(),
-        hello_max_age,
-    )?));
+            if let Some(message) = offline_message {
+                let mut state = state.write().expect("poisioned");
+                state.message = Some(message);
+                state.force_refresh = true;
+            }
+            stopping.store(true, Ordering::SeqCst);
+            let _ = wake.send(());
+            let _ = stop_server.send(());
+            let _ = stopped.send(());
+        });
+    }
+
+    if let (true, Some(path)) = (options.watch_config, options.config.clone()) {
+        let mut watcher = Watcher::new(&path)?;
+        let state = state.clone();
+        let wake = wake.clone();
+        thread::spawn(move || loop {
+            match watcher.wait() {
+                Ok(()) => reload_config(&path, &defaults, &state, &wake),
+                Err(err) => {
+                    error!("unable to watch {}: {}", path.display(), err);
+                    return;
+                }
+            }
+        });
+    }
+
+    // Bound before the other threads start so that mDNS advertises the port it's really on
+    let listener = if options.noserver {
+        None
+    } else {
+        Some(match systemd::listener()? {
+            Some(listener) => listener,
+            None => bind(options.port)?,
+        })
+    };
+    if let Some(ref listener) = listener {
+        // The systemd socket or the fallback may be IPv4 only
+        let ipv6 = listener.local_addr()?.is_ipv6();
+        state.write().expect("poisioned").set_ipv6(ipv6);
+    }
+
+    if let (true, Some(listener)) = (options.mdns, &listener) {
+        let responder = mdns::Responder::bind()?;
+        let state = state.clone();
+        let port = listener.local_addr()?.port();
+        thread::spawn(move || responder.run(state, port));
+    }
+
+    // The display thread and HTTP server report in, and if either stops systemd restarts the badge
+    let watchdog_interval = systemd::watchdog_interval();
+    let watchdog = watchdog_interval.map(|interval| {
+        let mut parts = Vec::new();
+        if !options.nodisplay && !options.oneshot {
+            parts.push("display");
+        }
+        if listener.is_some() {
+            parts.push("http");
+        }
+        let watchdog = Arc::new(Watchdog::new(interval, &parts));
+        {
+            let watchdog = watchdog.clone();
+            thread::spawn(move || watchdog.run());
+        }
+        watchdog
+    });
+
+    if options.peers {
+        let peers_path = PathBuf::from(PEERS_PATH);
+        state.write().expect("poisioned").peers = Peers::load(&peers_path)?;
+        let identity = Identity::load_or_generate(Path::new("badge_key.txt"))?;
+        let exchange = Exchange::bind(identity, options.peer_port)?;
+        let state = state.clone();
+        thread::spawn(move || exchange.run(state, peers_path));
+    }
+
+    if !options.team.is_empty() {
+        let urls = options
+            .team
+            .iter()
+            .map(|url| Url {
+                path: "/count".to_string(),
+                ..url.clone()
+            })
+            .collect();
+        let interval = Duration::from_secs(options.team_interval);
+        state.write().expect("poisioned").leaderboard = Leaderboard::new(urls, interval * 3);
+        let state = state.clone();
+        thread::spawn(move || leaderboard::run(state, interval));
+    }
+
+    if !options.webhooks.is_empty() {
+        let (hello_tx, hello_rx) = mpsc::channel();
+        let webhooks = {
+            let mut state = state.write().expect("poisioned");
+            state.hello_tx = Some(hello_tx);
+            Webhooks::load(
+                Path::new(WEBHOOK_QUEUE_PATH),
+                options.webhooks.clone(),
+                options.webhook_thresholds.clone(),
+                state.hi_count(),
+            )?
+        };
+        thread::spawn(move || webhooks.run(hello_rx));
+    }
+
+    if let Some(ref broker) = options.mqtt {
+        let nodename = state.read().expect("poisioned").uname.nodename().to_string();
+        let config = mqtt::Config {
+            broker: broker.clone(),
+            ca: options.mqtt_ca.clone(),
+            username: options.mqtt_username.clone(),
+            password: options.mqtt_password.clone(),
+            client_id: format!("lca2019-{}", nodename),
+            topic: options
+                .mqtt_topic
+                .clone()
+                .unwrap_or_else(|| format!("badge/{}", nodename)),
+        };
+        let state = state.clone();
+        let wake = wake.clone();
+        thread::spawn(move || mqtt::run(config, state, wake));
+    }
+
+    if !options.buttons.is_empty() {
+        let pins = options
+            .buttons
+            .iter()
+            .map(|button| button.pin)
+            .collect::<Vec<_>>();
+        let buttons = Buttons::new(
+            SysfsPins::new(&pins, options.buttons_active_high)?,
+            &options.buttons,
+            Duration::from_millis(options.long_press_ms),
+        );
+        let state = state.clone();
+        let wake = wake.clone();
+        thread::spawn(move || buttons.run(state, wake));
+    }
 
     let display_thread = if !options.nodisplay {
         let options = options.clone();
         let state = state.clone();
+        let metrics = metrics.clone();
+        let stopping = stopping.clone();
+        let watchdog = watchdog.clone();
+        // A LUT of None means choose one based on the temperature
+        let lut = match options.lut.as_str() {
+            "auto" => None,
+            spec => Some(Lut::resolve(spec)?),
+        };
+        let partial_lut = Lut::resolve(&options.partial_lut)?;
+        let policy = RefreshPolicy {
+            full_every: options.full_refresh_every,
+            full_interval: Duration::from_secs(options.full_refresh_interval),
+        };
+        let mut budget = RefreshBudget::new(
+            options.max_refreshes_per_hour,
+            Duration::from_secs(options.min_refresh_interval),
+        );
+        // Only a count of wear, so it's not worth stopping the badge over
+        let mut refreshes = RefreshCount::load(&refresh_count_path).unwrap_or_else(|err| {
+            warn!("unable to load refresh count, starting from zero: {}", err);
+            RefreshCount::default()
+        });
+        state.write().expect("poisioned").refreshes = refreshes;
+        let compensation = options.sensor.clone().map(|sensor| {
+            Compensation::new(
+                sensor,
+                options.sensor_offset,
+                Celsius(options.min_temp),
+                Celsius(options.max_temp),
+            )
+        });
 
         Some(thread::spawn(move || {
             let mut delay = Delay {};
 
-            let display = hardware::display(COLS, ROWS).expect("unable to create display");
-
             let mut black_buffer = [0u8; ROWS as usize * COLS as usize / 8];
             let mut red_buffer = [0u8; ROWS as usize * COLS as usize / 8];
-            let mut display = GraphicDisplay::new(display, &mut black_buffer, &mut red_buffer);
-            let mut old_display_state = DisplayState {
-                hi_count: 1,
-                ip: None,
-            };
+            let mut old_display_state: Option<DisplayState> = None;
+            let mut tracker = RefreshTracker::new(policy);
+            let mut forced = false;
             let update_delay = Duration::from_secs(15);
 
             loop {
-                // TODO: Extract this to an update display function
+                if let Some(ref watchdog) = watchdog {
+                    watchdog.beat("display");
+                }
+                // Checked before the state is read so the offline message is on the last refresh
+                let last = stopping.load(Ordering::SeqCst);
                 let display_state = {
-                    let state = state.read().expect("poisioned");
-                    let new_display_state = DisplayState {
-                        hi_count: state.hi_count(),
-                        ip: state.ip,
-                    };
-
-                    if new_display_state.hi_count != old_display_state.hi_count {
-                        if let Err(err) = state.save_hi_count(&save_path) {
-                            println!("unable to save hi count: {:?}", err);
-                        }
-                    }
-
-                    new_display_state
+                    let mut state = state.write().expect("poisioned");
+                    forced |= mem::take(&mut state.force_refresh);
+                    state.display_state()
                 };
 
-                if display_state != old_display_state {
-                    match display.reset(&mut delay) {
-                        Ok(()) => println!("Reset and initialised"),
-                        Err(err) => println!("Error resetting display: {:?}", err),
-                    }
-
-                    display.clear(Color::White);
-                    println!("Clear");
-
-                    display.draw(
-                        ProFont24Point::render_str("Wesley Moore")
-                            .with_stroke(Some(Color::Red))
-                            .with_fill(Some(Color::White))
-                            .translate(Coord::new(1, -4))
-                            .into_iter(),
-                    );
-
-                    display.draw(
-                        ProFont14Point::render_str("wezm.net")
-                            .with_stroke(Some(Color::Black))
-                            .with_fill(Some(Color::White))
-                            .translate(Coord::new(1, 22))
-                            .into_iter(),
-                    );
-
-                    let hi = display_state.hi_count.to_string();
-                    display.draw(
-                        ProFont24Point::render_str(&hi)
-                            .with_stroke(Some(Color::Black))
-                            .with_fill(Some(Color::White))
-                            .translate(Coord::new(1, 42))
-                            .into_iter(),
-                    );
-                    let unit = if display_state.hi_count != 1 {
-                        "people have"
-                    } else {
-                        "person has"
-                    };
-                    display.draw(
-                        ProFont12Point::render_str(unit)
-                            .with_stroke(Some(Color::Black))
-                            .with_fill(Some(Color::White))
-                            .translate(Coord::new(hi.len() as i32 * 17 + 4, 43))
-                            .into_iter(),
-                    );
-                    display.draw(
-                        ProFont12Point::render_str("said hello")
-                            .with_stroke(Some(Color::Black))
-                            .with_fill(Some(Color::White))
-                            .translate(Coord::new(hi.len() as i32 * 17 + 4, 57))
-                            .into_iter(),
-                    );
-
-                    let ip = display_state
-                        .ip
-                        .map(|ip| ip.to_string())
-                        .unwrap_or_else(|| "?.?.?.?".to_string());
-                    // http://192.168.100.100/ = 23 chars 230px
-                    // http://10.0.0.18/ = 17 chars 170px
-                    let url = format!("http://{}/", ip);
-
-                    // If URL is longer than will fit on the screen use a smaller font
-                    // 14pt is 10px wide
-                    // 12pt is 8px wide
-                    if url.len() * 10 > ROWS as usize {
-                        display.draw(
-                            ProFont12Point::render_str(&url)
-                                .with_stroke(Some(Color::Black))
-                                .with_fill(Some(Color::White))
-                                .translate(Coord::new(1, 90))
-                                .into_iter(),
-                        );
-                    } else {
-                        display.draw(
-                            ProFont14Point::render_str(&url)
-                                .with_stroke(Some(Color::Black))
-                                .with_fill(Some(Color::White))
-                                .translate(Coord::new(1, 88))
-                                .into_iter(),
-                        );
-                    }
-                    display.draw(
-                        ProFont14Point::render_str("Say hi at:")
-                            .with_stroke(Some(Color::Black))
-                            .with_fill(Some(Color::White))
-                            .translate(Coord::new(1, 73))
-                            .into_iter(),
-                    );
-
-                    // Draw the URL QR code
-                    let qrcode =
-                        QrCode::with_error_correction_level(url.as_bytes(), EcLevel::L).unwrap();
-                    display.draw(QrCodeIterator::new(qrcode, Coord::new(QR_X, QR_Y), 2));
-
-                    match display.update(&mut delay) {
-                        Ok(()) => println!("Update..."),
-                        Err(err) => println!("error updating display: {:?}", err),
-                    }
-
-                    match display.deep_sleep() {
-                        Ok(()) => println!("Finished - going to sleep"),
-                        Err(err) => println!("Error going to sleep: {:?}", err),
-                    }
+                let kind = if forced {
+                    Some(RefreshKind::Full)
                 } else {
-                    println!("No change, skip display update");
+                    tracker.next(old_display_state.as_ref(), &display_state, Instant::now())
+                };
+                match kind {
+                    Some(kind) => match budget.wait(Instant::now()) {
+                        Some(wait) if !last => info!(
+                            "Refresh budget used, defer display update for {}s",
+                            wait.as_secs()
+                        ),
+                        _ => {
+                            let waveform =
+                                refresh_lut(kind, &lut, &partial_lut, compensation.as_ref());
+                            // A display that can't be set up is tried again on the next update
+                            let display = waveform.as_ref().and_then(|waveform| {
+                                hardware::display(COLS, ROWS, waveform.as_bytes())
+                                    .map_err(|err| error!("unable to set up the display: {}", err))
+                                    .ok()
+                            });
+                            if let Some(display) = display {
+                                let mut display = GraphicDisplay::new(
+                                    display,
+                                    &mut black_buffer,
+                                    &mut red_buffer,
+                                );
+                                let updated = system::clock_time(SystemTime::now());
+                                let started = Instant::now();
+                                let result = display::refresh(
+                                    &mut display,
+                                    &mut delay,
+                                    &display_state,
+                                    updated.as_deref(),
+                                );
+
+                                let now = Instant::now();
+                                metrics.observe_refresh(kind, now - started, result.is_ok());
+                                budget.record(now);
+                                if result.is_ok() {
+                                    tracker.record(kind, now);
+                                    refreshes.record(kind);
+                                    state.write().expect("poisioned").refreshes = refreshes;
+                                    if let Err(err) = refreshes.save(&refresh_count_path) {
+                                        error!("unable to save refresh count: {}", err);
+                                    }
+                                    old_display_state = Some(display_state);
+                                    forced = false;
+                                }
+                            }
+                        }
+                    },
+                    None => debug!("No change, skip display update"),
                 }
 
-                old_display_state = display_state;
-
-                if options.oneshot {
+                // The panel is put into deep sleep after each refresh, so there's nothing more to do
+                if options.oneshot || last {
                     break;
                 }
 
-                thread::sleep(update_delay);
+                // Wait for the next update, or something like a button press that needs the
+                // display updated sooner
+                let _ = wake_rx.recv_timeout(update_delay);
 
                 {
                     let mut state = state.write().expect("poisioned");
@@ -248,117 +645,146 @@ fn main() -> Result<(), std::io::Error> {
         None
     };
 
-    if !options.noserver {
-        // TODO: Implement shutdown?
+    if let Err(err) = systemd::notify("READY=1") {
+        warn!("unable to notify systemd: {}", err);
+    }
+
+    if let Some(listener) = listener {
         let new_service = make_service_fn(move |socket: &AddrStream| {
             let remote_addr = socket.remote_addr();
 
             // This double clone doesn't seem right... but works
             let state = state.clone();
+            let metrics = metrics.clone();
 
-            service_fn(move |req| webserver::handle_request(state.clone(), remote_addr, req))
+            service_fn(move |req| {
+                webserver::handle_request(state.clone(), metrics.clone(), remote_addr, req)
+            })
         });
 
-        let addr = SocketAddr::from((LISTEN_ADDR.parse::<Ipv4Addr>().unwrap(), options.port));
-        let server = Server::bind(&addr)
+        let addr = listener.local_addr()?;
+        let server = Server::from_tcp(listener)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
             .serve(new_service)
-            .map_err(|e| eprintln!("server error: {}", e));
-
-        // println!(
-        //     "Starting server on http:://{}",
-        //     wlan0_address
-        //         .map(|ip| format!("{}:{}", ip, options.port))
-        //         .unwrap_or_else(|| format!("{}:{}", LISTEN_ADDR, options.port))
-        // );
-
-        hyper::rt::run(server);
+            // Stop accepting connections and finish the requests in progress
+            .with_graceful_shutdown(stop_server_rx.clone().then(|_| Ok::<(), ()>(())))
+            .map_err(|e| error!("server error: {}", e));
+
+        info!("Starting server on {}", addr);
+
+        hyper::rt::run(futures::lazy(move || {
+            if let (Some(watchdog), Some(interval)) = (watchdog, watchdog_interval) {
+                // Ticks as long as the event loop isn't stuck. Stops with the server so that
+                // shutting down isn't held up.
+                let heartbeat = Interval::new(Instant::now(), interval / 4)
+                    .for_each(move |_| {
+                        watchdog.beat("http");
+                        Ok(())
+                    })
+                    .map_err(|err| error!("watchdog timer error: {}", err))
+                    .select(stop_server_rx.then(|_| Ok(())))
+                    .then(|_| Ok::<(), ()>(()));
+                hyper::rt::spawn(heartbeat);
+            }
+            server
+        }));
     }
 
-    match (options.oneshot, display_thread) {
-        (true, Some(thread)) => {
+    match display_thread {
+        Some(thread) => {
             let _ = thread.join();
         }
-        _ => (),
+        None if options.noserver && !options.oneshot => {
+            let _ = stopped_rx.recv();
+        }
+        None => (),
     }
 
-    Ok(())
-}
+    if let Err(err) = persister.save(&state) {
+        error!("unable to save hi count: {}", err);
+    }
+    info!("Shut down");
 
-struct QrCodeIterator {
-    colors: Vec<qrcode::Color>,
-    top_left: Coord,
-    index: usize,
-    width: usize,
-    x: usize,
-    y: usize,
+    Ok(())
 }
 
-impl QrCodeIterator {
-    pub fn new(code: QrCode, top_left: Coord, scale: usize) -> Self {
-        let width = code.width() * scale;
-        let colors = if scale > 1 {
-            // Map each pixel to more...
-            let colors = code.into_colors();
-            let mut expanded = vec![qrcode::Color::Light; colors.len() * scale.pow(2)];
-            for (i, color) in colors.into_iter().enumerate() {
-                if color == qrcode::Color::Light {
-                    continue;
-                }
-
-                for y in 0..scale {
-                    for x in 0..scale {
-                        expanded[i * scale + x + (width * y) + (i * scale / width * width)] = color;
-                    }
-                }
+/// Apply the settings in `path` over `defaults`. An invalid config is logged and the current one
+/// kept. The display is woken, but only refreshes if the change shows on it.
+/// Listen on IPv6 and IPv4, or only IPv4 if IPv6 isn't available
+fn bind(port: u16) -> io::Result<TcpListener> {
+    match TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)) {
+        Ok(listener) if listener.only_v6().ok() == Some(false) => return Ok(listener),
+        Ok(_) => warn!("IPv6 sockets aren't dual-stack, listening on IPv4 only"),
+        Err(err) => warn!("unable to listen on IPv6, listening on IPv4 only: {}", err),
+    }
 
-            }
+    TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))
+}
 
-            expanded
+fn reload_config(path: &Path, defaults: &Config, state: &RwLock<State>, wake: &Sender<()>) {
+    let config = match defaults.load(path) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("keeping the current config: {}", err);
+            return;
         }
-        else {
-            code.into_colors()
-        };
+    };
 
-        QrCodeIterator {
-            width,
-            colors,
-            index: 0,
-            x: 0,
-            y: 0,
-            top_left,
+    {
+        let mut state = state.write().expect("poisioned");
+        if state.config == config {
+            debug!("Config unchanged");
+            return;
         }
+        state.config = config;
+        // Pick up the address of a different interface
+        state.refresh();
     }
+    info!("Reloaded config from {}", path.display());
+    let _ = wake.send(());
 }
 
-impl Iterator for QrCodeIterator {
-    type Item = Pixel<Color>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= self.colors.len() {
+/// Choose the LUT for a refresh, None if the panel is too hot or cold to update
+fn refresh_lut(
+    kind: RefreshKind,
+    lut: &Option<Lut>,
+    partial_lut: &Lut,
+    compensation: Option<&Compensation>,
+) -> Option<Lut> {
+    let temperature = compensation.and_then(|compensation| match compensation.read() {
+        Ok(temperature) => Some(temperature),
+        Err(err) => {
+            warn!("unable to read temperature: {}", err);
             None
-        } else {
-            let colour = self.colors[self.index].select(Color::Black, Color::White);
-            let pixel = Pixel(
-                Coord::new(
-                    self.top_left.0 + self.x as i32,
-                    self.top_left.1 + self.y as i32,
-                )
-                .to_unsigned(),
-                colour,
-            );
-
-            self.index += 1;
+        }
+    });
 
-            if self.x != 0 && self.x % (self.width - 1) == 0 {
-                self.x = 0;
-                self.y += 1;
-            } else {
-                self.x += 1;
-            }
+    if let (Some(compensation), Some(temperature)) = (compensation, temperature) {
+        if !compensation.in_range(temperature) {
+            warn!(
+                "Temperature {} outside of rated range, skip display update",
+                temperature
+            );
+            return None;
+        }
+    }
 
-            Some(pixel)
+    let refresh_lut = match (kind, lut) {
+        (RefreshKind::Partial, _) => partial_lut.clone(),
+        (RefreshKind::Full, Some(lut)) => lut.clone(),
+        (RefreshKind::Full, None) => {
+            Lut::preset(temperature.map(preset_for).unwrap_or(Preset::Default))
         }
+    };
+    match temperature {
+        Some(temperature) => info!(
+            "{:?} refresh using LUT {} at {}",
+            kind, refresh_lut, temperature
+        ),
+        None => info!("{:?} refresh using LUT {}", kind, refresh_lut),
     }
+
+    Some(refresh_lut)
 }
 
 #[cfg(test)]
@@ -0,0 +1,806 @@
+//! Minimal mDNS responder (RFC 6762) that advertises the web server with DNS-SD (RFC 6763)
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use nix::sys::socket::{self, sockopt, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
+
+use crate::app::State;
+use crate::display::OWNER;
+
+pub const MDNS_PORT: u16 = 5353;
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+
+const SERVICE_TYPE: &str = "_http._tcp.local";
+const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+// TTLs recommended by RFC 6762 section 10
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+// Replies to legacy resolvers mustn't be cached for long
+const LEGACY_TTL: u32 = 10;
+// The hello count in the TXT record changes with every hello, so changes that only affect the TXT
+// record are announced at most this often (RFC 6762 section 8.4)
+const TXT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+const CLASS_IN: u16 = 1;
+// Top bit of the class is cache-flush in records and unicast-response in questions
+const CLASS_FLAG: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub unicast_response: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Data {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<String>),
+    Other(u16, Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub ttl: u32,
+    pub cache_flush: bool,
+    pub data: Data,
+}
+
+impl Record {
+    fn new(name: String, ttl: u32, cache_flush: bool, data: Data) -> Self {
+        Record {
+            name,
+            ttl,
+            cache_flush,
+            data,
+        }
+    }
+
+    pub fn rtype(&self) -> u16 {
+        match self.data {
+            Data::A(_) => TYPE_A,
+            Data::Aaaa(_) => TYPE_AAAA,
+            Data::Ptr(_) => TYPE_PTR,
+            Data::Srv { .. } => TYPE_SRV,
+            Data::Txt(_) => TYPE_TXT,
+            Data::Other(rtype, _) => rtype,
+        }
+    }
+}
+
+/// A DNS message, only the parts that mDNS uses
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Message {
+    pub id: u16,
+    pub response: bool,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+impl Message {
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader { bytes, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+
+        let mut message = Message {
+            id,
+            response: flags & FLAG_RESPONSE != 0,
+            ..Message::default()
+        };
+        for _ in 0..counts[0] {
+            let name = reader.name()?;
+            let qtype = reader.u16()?;
+            let class = reader.u16()?;
+            message.questions.push(Question {
+                name,
+                qtype,
+                unicast_response: class & CLASS_FLAG != 0,
+            });
+        }
+        for _ in 0..counts[1] {
+            message.answers.push(reader.record()?);
+        }
+        // Authority records are only used when probing, which we don't take part in
+        for _ in 0..counts[2] {
+            reader.record()?;
+        }
+        for _ in 0..counts[3] {
+            message.additionals.push(reader.record()?);
+        }
+
+        Ok(message)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(512);
+        let flags = if self.response {
+            FLAG_RESPONSE | FLAG_AUTHORITATIVE
+        } else {
+            0
+        };
+        for &value in [
+            self.id,
+            flags,
+            self.questions.len() as u16,
+            self.answers.len() as u16,
+            0,
+            self.additionals.len() as u16,
+        ]
+        .iter()
+        {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+
+        for question in &self.questions {
+            write_name(&mut bytes, &question.name);
+            bytes.extend_from_slice(&question.qtype.to_be_bytes());
+            let class = if question.unicast_response {
+                CLASS_IN | CLASS_FLAG
+            } else {
+                CLASS_IN
+            };
+            bytes.extend_from_slice(&class.to_be_bytes());
+        }
+        for record in self.answers.iter().chain(self.additionals.iter()) {
+            write_record(&mut bytes, record);
+        }
+
+        bytes
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or_else(truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Read a name, following any compression pointers
+    fn name(&mut self) -> io::Result<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+
+        // Limit the number of labels and pointers followed so a pointer loop can't hang us
+        for _ in 0..128 {
+            let len = usize::from(*self.bytes.get(pos).ok_or_else(truncated)?);
+            if len == 0 {
+                if !jumped {
+                    self.pos = pos + 1;
+                }
+                return Ok(labels.join("."));
+            } else if len & 0xc0 == 0xc0 {
+                let low = usize::from(*self.bytes.get(pos + 1).ok_or_else(truncated)?);
+                if !jumped {
+                    self.pos = pos + 2;
+                    jumped = true;
+                }
+                pos = (len & 0x3f) << 8 | low;
+            } else {
+                let label = self
+                    .bytes
+                    .get(pos + 1..pos + 1 + len)
+                    .ok_or_else(truncated)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+        }
+
+        Err(invalid_data("name is too long"))
+    }
+
+    fn record(&mut self) -> io::Result<Record> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = usize::from(self.u16()?);
+        let end = self.pos + len;
+
+        let data = match rtype {
+            TYPE_A if len == 4 => {
+                let bytes = self.take(4)?;
+                Data::A(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))
+            }
+            TYPE_AAAA if len == 16 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(self.take(16)?);
+                Data::Aaaa(Ipv6Addr::from(octets))
+            }
+            TYPE_PTR => Data::Ptr(self.name()?),
+            TYPE_SRV => {
+                let _priority = self.u16()?;
+                let _weight = self.u16()?;
+                let port = self.u16()?;
+                Data::Srv {
+                    port,
+                    target: self.name()?,
+                }
+            }
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                let mut txt = Reader {
+                    bytes: self.take(len)?,
+                    pos: 0,
+                };
+                while txt.pos < txt.bytes.len() {
+                    let len = usize::from(txt.take(1)?[0]);
+                    let string = txt.take(len)?;
+                    if !string.is_empty() {
+                        strings.push(String::from_utf8_lossy(string).into_owned());
+                    }
+                }
+                Data::Txt(strings)
+            }
+            _ => Data::Other(rtype, self.take(len)?.to_vec()),
+        };
+        self.pos = end;
+
+        Ok(Record::new(name, ttl, class & CLASS_FLAG != 0, data))
+    }
+}
+
+/// Write a name without compression
+fn write_name(bytes: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label);
+    }
+    bytes.push(0);
+}
+
+fn write_record(bytes: &mut Vec<u8>, record: &Record) {
+    let mut data = Vec::new();
+    match record.data {
+        Data::A(ip) => data.extend_from_slice(&ip.octets()),
+        Data::Aaaa(ip) => data.extend_from_slice(&ip.octets()),
+        Data::Ptr(ref name) => write_name(&mut data, name),
+        Data::Srv { port, ref target } => {
+            // Priority and weight
+            data.extend_from_slice(&[0, 0, 0, 0]);
+            data.extend_from_slice(&port.to_be_bytes());
+            write_name(&mut data, target);
+        }
+        Data::Txt(ref strings) => {
+            for string in strings {
+                let string = &string.as_bytes()[..string.len().min(255)];
+                data.push(string.len() as u8);
+                data.extend_from_slice(string);
+            }
+            // A TXT record can't be empty
+            if strings.is_empty() {
+                data.push(0);
+            }
+        }
+        Data::Other(_, ref other) => data.extend_from_slice(other),
+    }
+
+    write_name(bytes, &record.name);
+    bytes.extend_from_slice(&record.rtype().to_be_bytes());
+    let class = if record.cache_flush {
+        CLASS_IN | CLASS_FLAG
+    } else {
+        CLASS_IN
+    };
+    bytes.extend_from_slice(&class.to_be_bytes());
+    bytes.extend_from_slice(&record.ttl.to_be_bytes());
+    bytes.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(&data);
+}
+
+/// What the badge advertises
+#[derive(Debug, Clone, PartialEq)]
+pub struct Service {
+    /// Hostname without .local
+    pub hostname: String,
+    /// Instance name shown when browsing, E.g. "Wesley Moore's badge"
+    pub instance: String,
+    pub port: u16,
+    pub addresses: Vec<IpAddr>,
+    pub txt: Vec<String>,
+}
+
+impl Service {
+    pub fn from_state(state: &State, port: u16) -> Self {
+        Service {
+            hostname: state.uname.nodename().to_string(),
+            instance: format!("{}'s badge", OWNER),
+            port,
+            addresses: state.address.iter().map(|address| address.ip).collect(),
+            txt: vec![
+                "path=/".to_string(),
+                format!("owner={}", OWNER),
+                format!("hellos={}", state.hi_count()),
+            ],
+        }
+    }
+
+    fn host(&self) -> String {
+        format!("{}.local", self.hostname)
+    }
+
+    /// Is the TXT record the only difference between `self` and `other`?
+    fn only_txt_differs(&self, other: &Service) -> bool {
+        let txt_changed = Service {
+            txt: other.txt.clone(),
+            ..self.clone()
+        };
+        txt_changed == *other
+    }
+
+    fn instance_name(&self) -> String {
+        // Dots would split the instance into more labels
+        format!("{}.{}", self.instance.replace('.', " "), SERVICE_TYPE)
+    }
+
+    fn address_records(&self) -> Vec<Record> {
+        self.addresses
+            .iter()
+            .map(|ip| {
+                let data = match *ip {
+                    IpAddr::V4(ip) => Data::A(ip),
+                    IpAddr::V6(ip) => Data::Aaaa(ip),
+                };
+                Record::new(self.host(), HOST_TTL, true, data)
+            })
+            .collect()
+    }
+
+    fn service_records(&self) -> Vec<Record> {
+        vec![
+            Record::new(
+                self.instance_name(),
+                HOST_TTL,
+                true,
+                Data::Srv {
+                    port: self.port,
+                    target: self.host(),
+                },
+            ),
+            Record::new(
+                self.instance_name(),
+                SERVICE_TTL,
+                true,
+                Data::Txt(self.txt.clone()),
+            ),
+        ]
+    }
+
+    fn pointer_record(&self) -> Record {
+        Record::new(
+            SERVICE_TYPE.to_string(),
+            SERVICE_TTL,
+            false,
+            Data::Ptr(self.instance_name()),
+        )
+    }
+
+    /// Records answering `question`, and the additional records that go with them
+    fn answer(&self, question: &Question) -> (Vec<Record>, Vec<Record>) {
+        let name = question.name.as_str();
+        let wants = |rtype| question.qtype == rtype || question.qtype == TYPE_ANY;
+
+        if name.eq_ignore_ascii_case(SERVICE_TYPES) && wants(TYPE_PTR) {
+            let record = Record::new(
+                SERVICE_TYPES.to_string(),
+                SERVICE_TTL,
+                false,
+                Data::Ptr(SERVICE_TYPE.to_string()),
+            );
+            (vec![record], vec![])
+        } else if name.eq_ignore_ascii_case(SERVICE_TYPE) && wants(TYPE_PTR) {
+            let mut additionals = self.service_records();
+            additionals.extend(self.address_records());
+            (vec![self.pointer_record()], additionals)
+        } else if name.eq_ignore_ascii_case(&self.instance_name()) {
+            let answers = self
+                .service_records()
+                .into_iter()
+                .filter(|record| wants(record.rtype()))
+                .collect();
+            (answers, self.address_records())
+        } else if name.eq_ignore_ascii_case(&self.host()) {
+            let answers = self
+                .address_records()
+                .into_iter()
+                .filter(|record| wants(record.rtype()))
+                .collect();
+            (answers, vec![])
+        } else {
+            (vec![], vec![])
+        }
+    }
+
+    /// The response to `query`, None if none of its questions are for us.
+    ///
+    /// Legacy queries come from resolvers that aren't on the mDNS port. They're answered
+    /// unicast, like regular DNS.
+    pub fn respond(&self, query: &Message, legacy: bool) -> Option<Message> {
+        let mut response = Message {
+            response: true,
+            ..Message::default()
+        };
+
+        for question in &query.questions {
+            let (answers, additionals) = self.answer(question);
+            for record in answers {
+                if !response.answers.contains(&record) {
+                    response.answers.push(record);
+                }
+            }
+            for record in additionals {
+                if !response.answers.contains(&record) && !response.additionals.contains(&record)
+                {
+                    response.additionals.push(record);
+                }
+            }
+        }
+
+        if response.answers.is_empty() {
+            return None;
+        }
+
+        if legacy {
+            response.id = query.id;
+            response.questions = query.questions.clone();
+            for record in response
+                .answers
+                .iter_mut()
+                .chain(response.additionals.iter_mut())
+            {
+                record.ttl = record.ttl.min(LEGACY_TTL);
+                record.cache_flush = false;
+            }
+        }
+
+        Some(response)
+    }
+
+    /// Unsolicited response with all of the records
+    pub fn announcement(&self) -> Message {
+        let mut answers = vec![self.pointer_record()];
+        answers.extend(self.service_records());
+        answers.extend(self.address_records());
+
+        Message {
+            response: true,
+            answers,
+            ..Message::default()
+        }
+    }
+}
+
+/// Should `service` be announced at `now`, given what was last announced and when?
+fn needs_announcement(
+    announced: Option<&(Service, Instant)>,
+    service: &Service,
+    now: Instant,
+) -> bool {
+    match announced {
+        None => true,
+        Some((previous, _)) if previous == service => false,
+        Some((previous, at)) if previous.only_txt_differs(service) => {
+            now.duration_since(*at) >= TXT_ANNOUNCE_INTERVAL
+        }
+        Some(_) => true,
+    }
+}
+
+pub struct Responder {
+    socket: UdpSocket,
+    multicast: SocketAddr,
+}
+
+impl Responder {
+    /// Bind to the mDNS port and join the multicast group. The port is shared with any other
+    /// responder, like avahi.
+    pub fn bind() -> io::Result<Self> {
+        let socket = shared_socket(SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)))?;
+        socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_ttl_v4(255)?;
+
+        Ok(Responder::new(socket, SocketAddr::from((MDNS_GROUP, MDNS_PORT))))
+    }
+
+    /// Respond on `socket`, sending multicast responses to `multicast`
+    pub fn new(socket: UdpSocket, multicast: SocketAddr) -> Self {
+        Responder { socket, multicast }
+    }
+
+    pub fn announce(&self, service: &Service) -> io::Result<()> {
+        self.socket
+            .send_to(&service.announcement().to_bytes(), self.multicast)
+            .map(|_| ())
+    }
+
+    /// Receive a packet and respond to it if it's a query for `service`
+    pub fn poll(&self, service: &Service) -> io::Result<()> {
+        let mut packet = [0; 9000];
+        let (len, from) = self.socket.recv_from(&mut packet)?;
+        let query = match Message::parse(&packet[..len]) {
+            Ok(ref query) if !query.response => query.clone(),
+            _ => return Ok(()),
+        };
+
+        let legacy = from.port() != MDNS_PORT;
+        if let Some(response) = service.respond(&query, legacy) {
+            let unicast = legacy
+                || query
+                    .questions
+                    .iter()
+                    .all(|question| question.unicast_response);
+            let to = if unicast { from } else { self.multicast };
+            self.socket.send_to(&response.to_bytes(), to)?;
+        }
+
+        Ok(())
+    }
+
+    /// Answer queries forever, announcing the service again whenever it changes. Changes to just
+    /// the TXT record are rate limited, queries are always answered with the latest one.
+    pub fn run(self, state: Arc<RwLock<State>>, port: u16) {
+        if let Err(err) = self.socket.set_read_timeout(Some(Duration::from_secs(1))) {
+            warn!("unable to set mDNS read timeout: {}", err);
+        }
+        let mut announced: Option<(Service, Instant)> = None;
+
+        loop {
+            let service = Service::from_state(&state.read().expect("poisioned"), port);
+            let now = Instant::now();
+            if needs_announcement(announced.as_ref(), &service, now) {
+                match self.announce(&service) {
+                    Ok(()) => info!("Announced {} with mDNS", service.instance_name()),
+                    Err(err) => warn!("unable to send mDNS announcement: {}", err),
+                }
+                announced = Some((service.clone(), now));
+            }
+
+            match self.poll(&service) {
+                Ok(()) => (),
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::WouldBlock
+                        || err.kind() == io::ErrorKind::TimedOut => {}
+                Err(err) => warn!("mDNS error: {}", err),
+            }
+        }
+    }
+}
+
+/// A UDP socket bound with SO_REUSEADDR and SO_REUSEPORT so other processes can share the port
+fn shared_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let fd = socket::socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::empty(),
+        None,
+    )
+    .map_err(socket_error)?;
+    // Owning the fd in a UdpSocket closes it on error
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    socket::setsockopt(socket.as_raw_fd(), sockopt::ReuseAddr, &true).map_err(socket_error)?;
+    socket::setsockopt(socket.as_raw_fd(), sockopt::ReusePort, &true).map_err(socket_error)?;
+    socket::bind(
+        socket.as_raw_fd(),
+        &SockAddr::new_inet(InetAddr::from_std(&addr)),
+    )
+    .map_err(socket_error)?;
+
+    Ok(socket)
+}
+
+fn socket_error(err: nix::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("socket error: {:?}", err))
+}
+
+fn truncated() -> io::Error {
+    invalid_data("truncated message")
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service() -> Service {
+        Service {
+            hostname: "badge".to_string(),
+            instance: "Wesley Moore's badge".to_string(),
+            port: 8080,
+            addresses: vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 18))],
+            txt: vec!["owner=Wesley Moore".to_string(), "hellos=3".to_string()],
+        }
+    }
+
+    fn query(id: u16, name: &str, qtype: u16) -> Message {
+        Message {
+            id,
+            questions: vec![Question {
+                name: name.to_string(),
+                qtype,
+                unicast_response: false,
+            }],
+            ..Message::default()
+        }
+    }
+
+    fn loopback() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket
+    }
+
+    fn receive(socket: &UdpSocket) -> Message {
+        let mut packet = [0; 9000];
+        let len = socket.recv(&mut packet).unwrap();
+        Message::parse(&packet[..len]).unwrap()
+    }
+
+    #[test]
+    fn test_txt_announcements_rate_limited() {
+        let start = Instant::now();
+        let announced = (service(), start);
+        assert!(needs_announcement(None, &service(), start));
+        assert!(!needs_announcement(Some(&announced), &service(), start));
+
+        // A new hello only changes the TXT record
+        let mut hello = service();
+        hello.txt[1] = "hellos=4".to_string();
+        assert!(!needs_announcement(
+            Some(&announced),
+            &hello,
+            start + Duration::from_secs(1)
+        ));
+        assert!(needs_announcement(
+            Some(&announced),
+            &hello,
+            start + TXT_ANNOUNCE_INTERVAL
+        ));
+
+        // Other changes are announced straight away
+        let mut moved = hello.clone();
+        moved.addresses = vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 19))];
+        assert!(needs_announcement(
+            Some(&announced),
+            &moved,
+            start + Duration::from_secs(1)
+        ));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let announcement = service().announcement();
+        assert_eq!(
+            Message::parse(&announcement.to_bytes()).unwrap(),
+            announcement
+        );
+    }
+
+    #[test]
+    fn test_parse_compressed_name() {
+        #[rustfmt::skip]
+        let packet = [
+            0, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0,
+            // badge.local A
+            5, b'b', b'a', b'd', b'g', b'e', 5, b'l', b'o', b'c', b'a', b'l', 0, 0, 1, 0, 1,
+            // Pointer to .local at offset 18 AAAA
+            3, b'p', b'h', b'o', 0xc0, 18, 0, 28, 0, 1,
+        ];
+        let message = Message::parse(&packet).unwrap();
+        assert_eq!(message.questions[0].name, "badge.local");
+        assert_eq!(message.questions[1].name, "pho.local");
+        assert_eq!(message.questions[1].qtype, TYPE_AAAA);
+        assert!(Message::parse(&packet[..20]).is_err());
+    }
+
+    #[test]
+    fn test_respond() {
+        let service = service();
+
+        let response = service
+            .respond(&query(0, "_http._tcp.local", TYPE_PTR), false)
+            .unwrap();
+        assert_eq!(
+            response.answers[0].data,
+            Data::Ptr("Wesley Moore's badge._http._tcp.local".to_string())
+        );
+        assert!(response.additionals.iter().any(|record| record.data
+            == Data::Srv {
+                port: 8080,
+                target: "badge.local".to_string()
+            }));
+
+        let response = service
+            .respond(&query(0, "BADGE.local", TYPE_A), false)
+            .unwrap();
+        assert_eq!(
+            response.answers[0].data,
+            Data::A(Ipv4Addr::new(10, 0, 0, 18))
+        );
+
+        assert_eq!(service.respond(&query(0, "badge.local", TYPE_AAAA), false), None);
+        assert_eq!(service.respond(&query(0, "other.local", TYPE_A), false), None);
+    }
+
+    #[test]
+    fn test_loopback() {
+        let service = service();
+        let group = loopback();
+        let responder = Responder::new(loopback(), group.local_addr().unwrap());
+
+        responder.announce(&service).unwrap();
+        let announcement = receive(&group);
+        assert!(announcement.response);
+        assert!(announcement
+            .answers
+            .iter()
+            .any(|record| record.data == Data::Txt(service.txt.clone())));
+
+        // A query from a port other than 5353 gets a legacy unicast reply
+        let client = loopback();
+        let query = query(1234, "Wesley Moore's badge._http._tcp.local", TYPE_TXT);
+        client
+            .send_to(&query.to_bytes(), responder.socket.local_addr().unwrap())
+            .unwrap();
+        responder.poll(&service).unwrap();
+
+        let response = receive(&client);
+        assert_eq!(response.id, 1234);
+        assert_eq!(response.questions, query.questions);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].ttl, LEGACY_TTL);
+        assert_eq!(
+            response.answers[0].data,
+            Data::Txt(vec![
+                "owner=Wesley Moore".to_string(),
+                "hellos=3".to_string()
+            ])
+        );
+    }
+}
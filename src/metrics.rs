@@ -0,0 +1,359 @@
+//! Prometheus metrics, served from `GET /metrics`
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::app::State;
+use crate::display::RefreshKind;
+use crate::hellos::Source;
+use crate::wear::RefreshCount;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+const HTTP_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+/// Full tri-colour refreshes take around 15 seconds
+const REFRESH_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 15.0, 20.0, 30.0, 60.0];
+
+/// Routes that requests are counted under, anything else is "other"
+const ROUTES: &[&str] = &["/", "/hi", "/count", "/leaderboard", "/metrics", "/api/hellos"];
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    buckets: &'static [f64],
+    /// Observations in each bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.buckets.iter().position(|&le| value <= le) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (le, count) in self.buckets.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+/// What's recorded as it happens, outside of `State`
+pub struct Metrics {
+    started: Instant,
+    http_requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+    http_duration: Mutex<BTreeMap<&'static str, Histogram>>,
+    refresh_duration: Mutex<BTreeMap<&'static str, Histogram>>,
+    display_errors: AtomicU64,
+}
+
+/// What's copied out of `State` for a scrape, so the lock is only held briefly
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub hellos: Vec<(Source, usize)>,
+    pub dedup_rejections: u64,
+    pub dedup_entries: usize,
+    pub refreshes: RefreshCount,
+    /// Total and free bytes
+    pub memory: Option<(u64, u64)>,
+    /// Seconds since boot
+    pub uptime: u64,
+}
+
+impl Snapshot {
+    pub fn new(state: &State) -> Self {
+        Snapshot {
+            hellos: Source::ALL
+                .iter()
+                .map(|&source| (source, state.hello_counts().get(source)))
+                .collect(),
+            dedup_rejections: state.dedup_rejections,
+            dedup_entries: state.hellos.len(),
+            refreshes: state.refreshes,
+            memory: state
+                .memory
+                .as_ref()
+                .map(|memory| (memory.total.as_u64(), memory.free.as_u64())),
+            uptime: state.uptime.seconds(),
+        }
+    }
+}
+
+/// The label for requests to `path`
+pub fn route(path: &str) -> &'static str {
+    ROUTES
+        .iter()
+        .find(|&&route| route == path)
+        .cloned()
+        .unwrap_or("other")
+}
+
+fn kind_label(kind: RefreshKind) -> &'static str {
+    match kind {
+        RefreshKind::Full => "full",
+        RefreshKind::Partial => "partial",
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            started: Instant::now(),
+            http_requests: Mutex::new(BTreeMap::new()),
+            http_duration: Mutex::new(BTreeMap::new()),
+            refresh_duration: Mutex::new(BTreeMap::new()),
+            display_errors: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe_request(&self, route: &'static str, status: u16, duration: Duration) {
+        *self
+            .http_requests
+            .lock()
+            .expect("poisioned")
+            .entry((route, status))
+            .or_insert(0) += 1;
+        self.http_duration
+            .lock()
+            .expect("poisioned")
+            .entry(route)
+            .or_insert_with(|| Histogram::new(HTTP_BUCKETS))
+            .observe(seconds(duration));
+    }
+
+    /// Record a refresh that took `duration`, and whether it failed
+    pub fn observe_refresh(&self, kind: RefreshKind, duration: Duration, ok: bool) {
+        self.refresh_duration
+            .lock()
+            .expect("poisioned")
+            .entry(kind_label(kind))
+            .or_insert_with(|| Histogram::new(REFRESH_BUCKETS))
+            .observe(seconds(duration));
+        if !ok {
+            self.display_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self, snapshot: &Snapshot) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "badge_hellos_total",
+            "counter",
+            "Hellos counted, by where they came from",
+        );
+        for (source, count) in &snapshot.hellos {
+            let _ = writeln!(
+                out,
+                "badge_hellos_total{{source=\"{}\"}} {}",
+                source.name(),
+                count
+            );
+        }
+        header(
+            &mut out,
+            "badge_hello_duplicates_total",
+            "counter",
+            "Hellos not counted because the address said hello recently",
+        );
+        let _ = writeln!(out, "badge_hello_duplicates_total {}", snapshot.dedup_rejections);
+        header(
+            &mut out,
+            "badge_hello_dedup_entries",
+            "gauge",
+            "Addresses remembered to deduplicate hellos",
+        );
+        let _ = writeln!(out, "badge_hello_dedup_entries {}", snapshot.dedup_entries);
+
+        header(
+            &mut out,
+            "badge_http_requests_total",
+            "counter",
+            "HTTP requests, by route and status",
+        );
+        for ((route, status), count) in self.http_requests.lock().expect("poisioned").iter() {
+            let _ = writeln!(
+                out,
+                "badge_http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                route, status, count
+            );
+        }
+        header(
+            &mut out,
+            "badge_http_request_duration_seconds",
+            "histogram",
+            "Time taken to respond to HTTP requests",
+        );
+        for (route, histogram) in self.http_duration.lock().expect("poisioned").iter() {
+            histogram.write(
+                &mut out,
+                "badge_http_request_duration_seconds",
+                &format!("route=\"{}\"", route),
+            );
+        }
+
+        header(
+            &mut out,
+            "badge_display_refreshes_total",
+            "counter",
+            "Display refreshes over the life of the panel",
+        );
+        let _ = writeln!(
+            out,
+            "badge_display_refreshes_total{{kind=\"full\"}} {}",
+            snapshot.refreshes.full
+        );
+        let _ = writeln!(
+            out,
+            "badge_display_refreshes_total{{kind=\"partial\"}} {}",
+            snapshot.refreshes.partial
+        );
+        header(
+            &mut out,
+            "badge_display_refresh_duration_seconds",
+            "histogram",
+            "Time taken to refresh the display",
+        );
+        for (kind, histogram) in self.refresh_duration.lock().expect("poisioned").iter() {
+            histogram.write(
+                &mut out,
+                "badge_display_refresh_duration_seconds",
+                &format!("kind=\"{}\"", kind),
+            );
+        }
+        header(&mut out, "badge_display_errors_total", "counter", "Failed display refreshes");
+        let _ = writeln!(
+            out,
+            "badge_display_errors_total {}",
+            self.display_errors.load(Ordering::Relaxed)
+        );
+
+        if let Some((total, free)) = snapshot.memory {
+            header(&mut out, "badge_memory_total_bytes", "gauge", "Total memory");
+            let _ = writeln!(out, "badge_memory_total_bytes {}", total);
+            header(&mut out, "badge_memory_free_bytes", "gauge", "Free memory");
+            let _ = writeln!(out, "badge_memory_free_bytes {}", free);
+        }
+        header(&mut out, "badge_uptime_seconds", "gauge", "Seconds since the badge booted");
+        let _ = writeln!(out, "badge_uptime_seconds {}", snapshot.uptime);
+        header(
+            &mut out,
+            "badge_process_uptime_seconds",
+            "gauge",
+            "Seconds since the badge software started",
+        );
+        let _ = writeln!(
+            out,
+            "badge_process_uptime_seconds {}",
+            self.started.elapsed().as_secs()
+        );
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        assert_eq!(route("/hi"), "/hi");
+        assert_eq!(route("/"), "/");
+        assert_eq!(route("/wp-login.php"), "other");
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(0.05);
+        histogram.observe(0.5);
+        histogram.observe(2.0);
+
+        let mut out = String::new();
+        histogram.write(&mut out, "test_seconds", "route=\"/\"");
+        assert_eq!(
+            out,
+            "test_seconds_bucket{route=\"/\",le=\"0.1\"} 1\n\
+             test_seconds_bucket{route=\"/\",le=\"1\"} 2\n\
+             test_seconds_bucket{route=\"/\",le=\"+Inf\"} 3\n\
+             test_seconds_sum{route=\"/\"} 2.55\n\
+             test_seconds_count{route=\"/\"} 3\n"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.observe_request("/hi", 200, Duration::from_millis(3));
+        metrics.observe_request("/hi", 200, Duration::from_millis(20));
+        metrics.observe_request("other", 404, Duration::from_millis(1));
+        metrics.observe_refresh(RefreshKind::Full, Duration::from_secs(14), true);
+        metrics.observe_refresh(RefreshKind::Partial, Duration::from_secs(3), false);
+
+        let snapshot = Snapshot {
+            hellos: vec![(Source::Web, 3), (Source::InPerson, 1)],
+            dedup_rejections: 2,
+            dedup_entries: 1,
+            refreshes: RefreshCount {
+                full: 10,
+                partial: 25,
+            },
+            memory: Some((512 * 1024 * 1024, 300 * 1024 * 1024)),
+            uptime: 3600,
+        };
+        let out = metrics.render(&snapshot);
+        let lines = out.lines().collect::<Vec<_>>();
+
+        for expected in &[
+            "badge_hellos_total{source=\"web\"} 3",
+            "badge_hellos_total{source=\"in-person\"} 1",
+            "badge_hello_duplicates_total 2",
+            "badge_hello_dedup_entries 1",
+            "badge_http_requests_total{route=\"/hi\",status=\"200\"} 2",
+            "badge_http_requests_total{route=\"other\",status=\"404\"} 1",
+            "badge_http_request_duration_seconds_bucket{route=\"/hi\",le=\"0.005\"} 1",
+            "badge_http_request_duration_seconds_count{route=\"/hi\"} 2",
+            "badge_display_refreshes_total{kind=\"partial\"} 25",
+            "badge_display_refresh_duration_seconds_bucket{kind=\"full\",le=\"15\"} 1",
+            "badge_display_errors_total 1",
+            "badge_memory_free_bytes 314572800",
+            "badge_uptime_seconds 3600",
+            "# TYPE badge_http_request_duration_seconds histogram",
+        ] {
+            assert!(lines.contains(expected), "missing {}", expected);
+        }
+    }
+}
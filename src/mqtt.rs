@@ -0,0 +1,749 @@
+//! MQTT 3.1.1 client that publishes the state of the badge to retained topics and takes commands
+//! from `<topic>/command`
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use native_tls::{Certificate, TlsConnector};
+
+use crate::app::State;
+use crate::buttons::{self, Action};
+
+// Packet types, with the flags required by the spec
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+const RETAIN: u8 = 0x01;
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for a packet before checking if there's anything to publish
+const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Broker URL, `mqtt://host[:port]` or `mqtts://host[:port]` for TLS
+#[derive(Debug, Clone, PartialEq)]
+pub struct Broker {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+}
+
+impl FromStr for Broker {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tls, authority) = if let Some(authority) = s.strip_prefix("mqtts://") {
+            (true, authority)
+        } else if let Some(authority) = s.strip_prefix("mqtt://") {
+            (false, authority)
+        } else {
+            return Err(format!("'{}' isn't an mqtt:// or mqtts:// URL", s));
+        };
+        let authority = authority.trim_end_matches('/');
+
+        let (host, port) = match authority.rfind(':') {
+            Some(colon) if !authority.ends_with(']') => {
+                let port = authority[colon + 1..]
+                    .parse()
+                    .map_err(|_| format!("invalid port in '{}'", s))?;
+                (&authority[..colon], port)
+            }
+            _ => (authority, if tls { 8883 } else { 1883 }),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("no host in '{}'", s));
+        }
+
+        Ok(Broker {
+            host: host.to_string(),
+            port,
+            tls,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub broker: Broker,
+    /// CA certificate to trust for TLS, in addition to the system ones
+    pub ca: Option<PathBuf>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub client_id: String,
+    /// Prefix of the topics, E.g. badge/wes
+    pub topic: String,
+}
+
+impl Config {
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{}", self.topic, name)
+    }
+}
+
+/// Something sent to the command topic
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Anything a button can do
+    Action(Action),
+    /// Show a message on the display: `message <text>`
+    Message(String),
+    ClearMessage,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "clear-message" {
+            return Ok(Command::ClearMessage);
+        }
+        match s.strip_prefix("message ") {
+            Some(message) if !message.trim().is_empty() => {
+                Ok(Command::Message(message.trim().to_string()))
+            }
+            _ => s.parse().map(Command::Action),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Packet {
+    ConnAck {
+        return_code: u8,
+    },
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        packet_id: Option<u16>,
+    },
+    SubAck,
+    PingResp,
+    /// Packets we don't need to understand
+    Other {
+        kind: u8,
+        body: Vec<u8>,
+    },
+}
+
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// A connection to the broker
+pub struct Client {
+    stream: Box<dyn Stream>,
+    config: Config,
+    last_ping: Instant,
+    last_received: Instant,
+    next_packet_id: u16,
+}
+
+impl Client {
+    /// Connect, log in, and subscribe to the command topic
+    pub fn connect(config: &Config) -> io::Result<Self> {
+        let tcp = connect_tcp(&config.broker)?;
+        tcp.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        tcp.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+
+        let stream: Box<dyn Stream> = if config.broker.tls {
+            let mut builder = TlsConnector::builder();
+            if let Some(ref ca) = config.ca {
+                let pem = fs::read(ca)?;
+                builder.add_root_certificate(Certificate::from_pem(&pem).map_err(other)?);
+            }
+            let connector = builder.build().map_err(other)?;
+            let stream = connector
+                .connect(&config.broker.host, tcp.try_clone()?)
+                .map_err(other)?;
+            Box::new(stream)
+        } else {
+            Box::new(tcp.try_clone()?)
+        };
+
+        let mut client = Client {
+            stream,
+            config: config.clone(),
+            last_ping: Instant::now(),
+            last_received: Instant::now(),
+            next_packet_id: 1,
+        };
+
+        client.send(&connect_packet(config))?;
+        match read_packet(&mut client.stream)? {
+            Packet::ConnAck { return_code: 0 } => (),
+            Packet::ConnAck { return_code } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("broker refused connection: {}", connack_error(return_code)),
+                ))
+            }
+            packet => return Err(invalid_data(&format!("expected CONNACK, got {:?}", packet))),
+        }
+
+        let packet_id = client.packet_id();
+        client.send(&subscribe_packet(packet_id, &config.topic("command")))?;
+        client.publish("status", "online")?;
+
+        // Short timeout from now on so the client can publish between packets
+        tcp.set_read_timeout(Some(POLL_TIMEOUT))?;
+        Ok(client)
+    }
+
+    /// Publish `payload` to the retained topic `name` under the prefix
+    pub fn publish(&mut self, name: &str, payload: &str) -> io::Result<()> {
+        let packet = publish_packet(&self.config.topic(name), payload.as_bytes(), true);
+        self.send(&packet)
+    }
+
+    /// Wait up to the poll timeout for a command, pinging the broker to keep the connection up
+    pub fn poll(&mut self) -> io::Result<Option<Command>> {
+        if self.last_received.elapsed() > KEEP_ALIVE * 3 / 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "nothing heard from the broker",
+            ));
+        }
+        // Ping even when publishing, the broker doesn't reply to QoS 0 publishes so they don't show
+        // the connection is still up
+        if self.last_ping.elapsed() >= KEEP_ALIVE / 2 {
+            self.send(&[PINGREQ, 0])?;
+            self.last_ping = Instant::now();
+        }
+
+        let packet = match read_packet(&mut self.stream) {
+            Ok(packet) => packet,
+            Err(ref err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Ok(None)
+            }
+            Err(err) => return Err(err),
+        };
+        self.last_received = Instant::now();
+
+        match packet {
+            Packet::Publish {
+                topic,
+                payload,
+                packet_id,
+            } => {
+                if let Some(packet_id) = packet_id {
+                    let [high, low] = packet_id.to_be_bytes();
+                    self.send(&[PUBACK, 2, high, low])?;
+                }
+                if topic != self.config.topic("command") {
+                    return Ok(None);
+                }
+
+                let command = String::from_utf8_lossy(&payload);
+                match command.parse() {
+                    Ok(command) => Ok(Some(command)),
+                    Err(err) => {
+                        warn!("ignoring MQTT command '{}': {}", command, err);
+                        Ok(None)
+                    }
+                }
+            }
+            Packet::ConnAck { .. } | Packet::SubAck | Packet::PingResp | Packet::Other { .. } => {
+                Ok(None)
+            }
+        }
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.stream.write_all(packet)?;
+        self.stream.flush()
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        let packet_id = self.next_packet_id;
+        // 0 isn't a valid packet id
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        packet_id
+    }
+}
+
+/// The retained topics and their values
+fn status(state: &State) -> Vec<(&'static str, String)> {
+    vec![
+        ("hi_count", state.hi_count().to_string()),
+        (
+            "ip",
+            state
+                .address
+                .as_ref()
+                .map(|address| address.ip.to_string())
+                .unwrap_or_default(),
+        ),
+        ("uptime", state.uptime.seconds().to_string()),
+        (
+            "temperature",
+            state
+                .soc_temperature
+                .as_ref()
+                .map(|temperature| format!("{:.1}", temperature.0))
+                .unwrap_or_default(),
+        ),
+    ]
+}
+
+/// Publish the state of the badge and apply commands, reconnecting with backoff, forever
+pub fn run(config: Config, state: Arc<RwLock<State>>, wake: Sender<()>) {
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        match Client::connect(&config) {
+            Ok(client) => {
+                info!(
+                    "Connected to MQTT broker {}:{}",
+                    config.broker.host, config.broker.port
+                );
+                backoff = MIN_BACKOFF;
+                let err = session(client, &state, &wake);
+                warn!("MQTT connection lost: {}", err);
+            }
+            Err(err) => warn!(
+                "unable to connect to MQTT broker {}:{}: {}",
+                config.broker.host, config.broker.port, err
+            ),
+        }
+
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Run until the connection fails
+fn session(mut client: Client, state: &RwLock<State>, wake: &Sender<()>) -> io::Error {
+    let mut published: Vec<(&'static str, String)> = Vec::new();
+    let mut last_publish: Option<Instant> = None;
+
+    loop {
+        let due = last_publish
+            .map(|last| last.elapsed() >= PUBLISH_INTERVAL)
+            .unwrap_or(true);
+        if due {
+            let current = status(&state.read().expect("poisioned"));
+            // Only publish what's changed, the broker keeps the rest
+            for (name, value) in &current {
+                if !published.contains(&(*name, value.clone())) {
+                    if let Err(err) = client.publish(name, value) {
+                        return err;
+                    }
+                }
+            }
+            published = current;
+            last_publish = Some(Instant::now());
+        }
+
+        match client.poll() {
+            Ok(Some(command)) => {
+                info!("MQTT command: {:?}", command);
+                apply(command, state);
+                let _ = wake.send(());
+                // Publish the effect of the command straight away
+                last_publish = None;
+            }
+            Ok(None) => (),
+            Err(err) => return err,
+        }
+    }
+}
+
+pub fn apply(command: Command, state: &RwLock<State>) {
+    match command {
+        Command::Action(action) => buttons::apply(action, state),
+        Command::Message(message) => state.write().expect("poisioned").message = Some(message),
+        Command::ClearMessage => state.write().expect("poisioned").message = None,
+    }
+}
+
+fn connect_tcp(broker: &Broker) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in (broker.host.as_str(), broker.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no addresses for {}", broker.host),
+        )
+    }))
+}
+
+fn connect_packet(config: &Config) -> Vec<u8> {
+    // Clean session, with a will that marks the badge offline if the connection drops
+    let mut flags = 0x02 | 0x04 | 0x20;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+
+    let mut body = Vec::new();
+    push_str(&mut body, b"MQTT");
+    body.push(4); // Protocol level 3.1.1
+    body.push(flags);
+    body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    push_str(&mut body, config.client_id.as_bytes());
+    push_str(&mut body, config.topic("status").as_bytes());
+    push_str(&mut body, b"offline");
+    if let Some(ref username) = config.username {
+        push_str(&mut body, username.as_bytes());
+    }
+    if let Some(ref password) = config.password {
+        push_str(&mut body, password.as_bytes());
+    }
+
+    packet(CONNECT, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_str(&mut body, topic.as_bytes());
+    body.extend_from_slice(payload);
+
+    packet(if retain { PUBLISH | RETAIN } else { PUBLISH }, &body)
+}
+
+fn subscribe_packet(packet_id: u16, topic: &str) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    push_str(&mut body, topic.as_bytes());
+    body.push(0); // QoS 0
+
+    packet(SUBSCRIBE, &body)
+}
+
+/// Prefix `body` with the fixed header
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    // Remaining length, 7 bits at a time with the top bit set if there's more
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn push_str(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s);
+}
+
+fn read_packet<R: Read + ?Sized>(reader: &mut R) -> io::Result<Packet> {
+    let mut kind = [0; 1];
+    if reader.read(&mut kind)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "broker closed the connection",
+        ));
+    }
+
+    // A timeout part way through a packet can't be picked up from, unlike one before it starts
+    let truncated = |err: io::Error| match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "truncated packet")
+        }
+        _ => err,
+    };
+    let mut length = 0;
+    for shift in 0..4 {
+        let mut byte = [0; 1];
+        reader.read_exact(&mut byte).map_err(truncated)?;
+        length |= usize::from(byte[0] & 0x7f) << (7 * shift);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(truncated)?;
+
+    decode(kind[0], body)
+}
+
+fn decode(kind: u8, body: Vec<u8>) -> io::Result<Packet> {
+    match kind & 0xf0 {
+        CONNACK if body.len() == 2 => Ok(Packet::ConnAck {
+            return_code: body[1],
+        }),
+        PUBLISH => {
+            let qos = (kind >> 1) & 0x03;
+            let topic_length = body
+                .get(..2)
+                .map(|length| usize::from(u16::from_be_bytes([length[0], length[1]])))
+                .ok_or_else(|| invalid_data("truncated PUBLISH"))?;
+            let topic = body
+                .get(2..2 + topic_length)
+                .ok_or_else(|| invalid_data("truncated PUBLISH"))?;
+            let topic = String::from_utf8_lossy(topic).into_owned();
+            let mut rest = &body[2 + topic_length..];
+            let packet_id = if qos > 0 {
+                let packet_id = rest
+                    .get(..2)
+                    .map(|id| u16::from_be_bytes([id[0], id[1]]))
+                    .ok_or_else(|| invalid_data("truncated PUBLISH"))?;
+                rest = &rest[2..];
+                Some(packet_id)
+            } else {
+                None
+            };
+
+            Ok(Packet::Publish {
+                topic,
+                payload: rest.to_vec(),
+                packet_id,
+            })
+        }
+        SUBACK => Ok(Packet::SubAck),
+        PINGRESP => Ok(Packet::PingResp),
+        _ => Ok(Packet::Other { kind, body }),
+    }
+}
+
+fn connack_error(return_code: u8) -> &'static str {
+    match return_code {
+        1 => "unacceptable protocol version",
+        2 => "client identifier rejected",
+        3 => "server unavailable",
+        4 => "bad user name or password",
+        5 => "not authorised",
+        _ => "unknown error",
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn other<E: std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn config(port: u16) -> Config {
+        Config {
+            broker: Broker {
+                host: "127.0.0.1".to_string(),
+                port,
+                tls: false,
+            },
+            ca: None,
+            username: Some("badge".to_string()),
+            password: Some("hunter2".to_string()),
+            client_id: "lca2019-test".to_string(),
+            topic: "badge/test".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_broker() {
+        assert_eq!(
+            Broker::from_str("mqtt://10.0.0.5").unwrap(),
+            Broker {
+                host: "10.0.0.5".to_string(),
+                port: 1883,
+                tls: false
+            }
+        );
+        let broker = Broker::from_str("mqtts://broker.local:8884/").unwrap();
+        assert_eq!((broker.host.as_str(), broker.port, broker.tls), ("broker.local", 8884, true));
+        let broker = Broker::from_str("mqtt://[2001:db8::5]").unwrap();
+        assert_eq!((broker.host.as_str(), broker.port), ("2001:db8::5", 1883));
+        assert!(Broker::from_str("http://10.0.0.5").is_err());
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            Command::from_str("next-screen"),
+            Ok(Command::Action(Action::NextScreen))
+        );
+        assert_eq!(
+            Command::from_str("message Back at 2pm\n"),
+            Ok(Command::Message("Back at 2pm".to_string()))
+        );
+        assert_eq!(Command::from_str("clear-message"), Ok(Command::ClearMessage));
+        assert!(Command::from_str("message ").is_err());
+        assert!(Command::from_str("self-destruct").is_err());
+    }
+
+    #[test]
+    fn test_long_packet() {
+        // Remaining lengths over 127 take more than one byte
+        let payload = vec![b'x'; 300];
+        let packet = publish_packet("badge/test/hi_count", &payload, false);
+        assert_eq!(&packet[..3], &[PUBLISH, 0xc1, 0x02]);
+
+        let decoded = read_packet(&mut &packet[..]).unwrap();
+        assert_eq!(
+            decoded,
+            Packet::Publish {
+                topic: "badge/test/hi_count".to_string(),
+                payload,
+                packet_id: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_local_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Just enough of a broker to talk to the client
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let connect = read_packet(&mut stream).unwrap();
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+            let subscribe = read_packet(&mut stream).unwrap();
+            stream.write_all(&[SUBACK, 3, 0, 1, 0]).unwrap();
+            let online = read_packet(&mut stream).unwrap();
+            let hi_count = read_packet(&mut stream).unwrap();
+
+            let command = publish_packet("badge/test/command", b"message Back at 2pm", false);
+            stream.write_all(&command).unwrap();
+            (connect, subscribe, online, hi_count)
+        });
+
+        let mut client = Client::connect(&config(port)).unwrap();
+        client.publish("hi_count", "42").unwrap();
+        let command = loop {
+            if let Some(command) = client.poll().unwrap() {
+                break command;
+            }
+        };
+        assert_eq!(command, Command::Message("Back at 2pm".to_string()));
+
+        let (connect, subscribe, online, hi_count) = broker.join().unwrap();
+        match connect {
+            Packet::Other { kind: CONNECT, body } => {
+                assert_eq!(&body[..7], b"\x00\x04MQTT\x04");
+                // User name, password, will retain, will, and clean session
+                assert_eq!(body[7], 0xe6);
+                assert!(body.ends_with(b"\x00\x05badge\x00\x07hunter2"));
+            }
+            packet => panic!("expected CONNECT, got {:?}", packet),
+        }
+        match subscribe {
+            Packet::Other { kind: SUBSCRIBE, body } => {
+                assert_eq!(&body[..4], b"\x00\x01\x00\x12");
+                assert_eq!(&body[4..], b"badge/test/command\x00");
+            }
+            packet => panic!("expected SUBSCRIBE, got {:?}", packet),
+        }
+        assert_eq!(
+            online,
+            Packet::Publish {
+                topic: "badge/test/status".to_string(),
+                payload: b"online".to_vec(),
+                packet_id: None
+            }
+        );
+        assert_eq!(
+            hi_count,
+            Packet::Publish {
+                topic: "badge/test/hi_count".to_string(),
+                payload: b"42".to_vec(),
+                packet_id: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_ping_while_publishing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Count the publishes until a ping, then answer it
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            read_packet(&mut stream).unwrap();
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+            read_packet(&mut stream).unwrap();
+            stream.write_all(&[SUBACK, 3, 0, 1, 0]).unwrap();
+
+            let mut publishes = 0;
+            loop {
+                match read_packet(&mut stream).unwrap() {
+                    Packet::Publish { .. } => publishes += 1,
+                    Packet::Other { kind: PINGREQ, .. } => break,
+                    packet => panic!("expected PUBLISH or PINGREQ, got {:?}", packet),
+                }
+            }
+            stream.write_all(&[PINGRESP, 0]).unwrap();
+            publishes
+        });
+
+        let mut client = Client::connect(&config(port)).unwrap();
+        // Half the keep alive passes with the status being published all the while and nothing
+        // heard from the broker
+        client.last_ping -= KEEP_ALIVE / 2;
+        client.last_received -= KEEP_ALIVE;
+        for uptime in 1..=3 {
+            client.publish("uptime", &uptime.to_string()).unwrap();
+        }
+        for _ in 0..5 {
+            assert_eq!(client.poll().unwrap(), None);
+            if client.last_received.elapsed() < KEEP_ALIVE / 2 {
+                break;
+            }
+        }
+
+        // The ping was answered, so the connection isn't considered dead
+        assert!(client.last_received.elapsed() < KEEP_ALIVE / 2);
+        assert_eq!(broker.join().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_refused_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_packet(&mut stream).unwrap();
+            stream.write_all(&[CONNACK, 2, 0, 4]).unwrap();
+        });
+
+        let err = Client::connect(&config(port)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("bad user name or password"));
+    }
+}
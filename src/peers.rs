@@ -0,0 +1,717 @@
+//! Badges on the same network find each other with UDP broadcasts and exchange signed hellos.
+//!
+//! A hello is UTF-8 text: a `badge-hello/VERSION` line followed by `key=value` lines, the last of
+//! which is `sig`, the hex Ed25519 signature of everything before it. `id` is the hex public key
+//! that made the signature, and `time` when it was signed, so a hello can't be replayed for long.
+//! Unknown keys are ignored so fields can be added without a new version.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use hyper::Uri;
+use log::{debug, error, info, warn};
+
+use crate::app::{write_file_atomic, State};
+use crate::display::OWNER;
+use crate::http::Url;
+use crate::system;
+
+const PROTOCOL: &str = "badge-hello";
+pub const VERSION: u32 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+/// More badges than there are people at the conference
+pub const MAX_PEERS: usize = 1000;
+/// Each address can only introduce one new badge this often
+const NEW_PEER_INTERVAL: Duration = Duration::from_secs(60);
+/// Hellos signed longer ago than this, or this far in the future, are rejected as replays
+const MAX_HELLO_AGE: u64 = 5 * 60;
+/// Changed names and URLs are saved together, at most this often
+const UPDATE_SAVE_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Kind {
+    /// Broadcast periodically to find other badges
+    Announce,
+    /// Sent back to a badge we've just met
+    Reply,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Announce => "announce",
+            Kind::Reply => "reply",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub kind: Kind,
+    /// Hex public key of the badge
+    pub id: String,
+    pub name: String,
+    pub url: Option<String>,
+    /// Seconds since the Unix epoch when it was signed
+    pub time: u64,
+}
+
+impl Hello {
+    /// Check the signature and age of a received hello and parse it. `now` is seconds since the
+    /// Unix epoch.
+    pub fn verify(bytes: &[u8], now: u64) -> io::Result<Self> {
+        let text = std::str::from_utf8(bytes).map_err(|_| invalid_data("hello isn't UTF-8"))?;
+        let sig_start = text
+            .rfind("\nsig=")
+            .ok_or_else(|| invalid_data("hello isn't signed"))?
+            + 1;
+        let (signed, sig) = text.split_at(sig_start);
+
+        let mut lines = signed.lines();
+        let version = lines
+            .next()
+            .and_then(|line| line.strip_prefix(PROTOCOL))
+            .and_then(|version| version.strip_prefix('/'))
+            .ok_or_else(|| invalid_data("not a badge hello"))?;
+        if version.parse::<u32>().ok() != Some(VERSION) {
+            return Err(invalid_data(&format!("unsupported version {}", version)));
+        }
+
+        let (mut kind, mut id, mut name, mut url, mut time) = (None, None, None, None, None);
+        for line in lines {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("kind"), Some("announce")) => kind = Some(Kind::Announce),
+                (Some("kind"), Some("reply")) => kind = Some(Kind::Reply),
+                (Some("id"), Some(value)) => id = Some(value.to_string()),
+                (Some("name"), Some(value)) => name = Some(clean(value)),
+                (Some("url"), Some(value)) => url = link(&clean(value)),
+                (Some("time"), Some(value)) => time = value.parse::<u64>().ok(),
+                _ => (),
+            }
+        }
+        let hello = match (kind, id, name, time) {
+            (Some(kind), Some(id), Some(name), Some(time)) => Hello {
+                kind,
+                id,
+                name,
+                url,
+                time,
+            },
+            _ => return Err(invalid_data("hello is missing kind, id, name, or time")),
+        };
+
+        let public = from_hex(&hello.id)
+            .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+            .ok_or_else(|| invalid_data("invalid id"))?;
+        let signature = from_hex(sig.trim_start_matches("sig=").trim())
+            .and_then(|bytes| Signature::try_from(&bytes[..]).ok())
+            .ok_or_else(|| invalid_data("invalid signature"))?;
+        public
+            .verify(signed.as_bytes(), &signature)
+            .map_err(|_| invalid_data("bad signature"))?;
+
+        let age = hello.time.abs_diff(now);
+        if age > MAX_HELLO_AGE {
+            return Err(invalid_data(&format!("hello is {}s out of date", age)));
+        }
+
+        Ok(hello)
+    }
+}
+
+/// Remove characters that would break the message or the saved peer list
+fn clean(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+/// The URL if it's http or https, it's shown as a link so anything else (`javascript:`...) is dropped
+fn link(url: &str) -> Option<String> {
+    let uri = url.parse::<Uri>().ok()?;
+    let valid = match uri.scheme_str() {
+        Some("http") => url.parse::<Url>().is_ok(),
+        Some("https") => uri.host().is_some(),
+        _ => false,
+    };
+
+    if valid {
+        Some(url.to_string())
+    } else {
+        None
+    }
+}
+
+/// The key this badge signs its hellos with
+pub struct Identity {
+    keypair: Keypair,
+}
+
+impl Identity {
+    /// Load the secret key from `path`, generating and saving one if it doesn't exist
+    pub fn load_or_generate(path: &Path) -> io::Result<Self> {
+        let secret = match fs::read_to_string(path) {
+            Ok(hex) => from_hex(hex.trim()).ok_or_else(|| invalid_data("invalid badge key"))?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                let mut secret = vec![0; 32];
+                File::open("/dev/urandom")?.read_exact(&mut secret)?;
+                write_file_atomic(path, to_hex(&secret) + "\n")?;
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+                info!("Generated badge key");
+                secret
+            }
+            Err(err) => return Err(err),
+        };
+
+        Self::from_secret(&secret)
+    }
+
+    pub fn from_secret(secret: &[u8]) -> io::Result<Self> {
+        let secret = SecretKey::from_bytes(secret).map_err(|_| invalid_data("invalid badge key"))?;
+        let public = PublicKey::from(&secret);
+
+        Ok(Identity {
+            keypair: Keypair { secret, public },
+        })
+    }
+
+    pub fn id(&self) -> String {
+        to_hex(self.keypair.public.as_bytes())
+    }
+
+    /// Make a hello message signed at `time`, seconds since the Unix epoch
+    pub fn hello(&self, kind: Kind, name: &str, url: Option<&str>, time: u64) -> Vec<u8> {
+        let mut text = format!(
+            "{}/{}\nkind={}\nid={}\nname={}\ntime={}\n",
+            PROTOCOL,
+            VERSION,
+            kind.name(),
+            self.id(),
+            clean(name),
+            time
+        );
+        if let Some(url) = url {
+            text.push_str(&format!("url={}\n", clean(url)));
+        }
+        let signature = self.keypair.sign(text.as_bytes());
+        text.push_str(&format!("sig={}\n", to_hex(&signature.to_bytes())));
+
+        text.into_bytes()
+    }
+}
+
+/// A badge we've exchanged hellos with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peer {
+    pub id: String,
+    pub name: String,
+    pub url: Option<String>,
+}
+
+impl Peer {
+    /// Short form of the id for display
+    pub fn short_id(&self) -> &str {
+        &self.id[..self.id.len().min(8)]
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Greeting {
+    /// A badge we haven't met before
+    New,
+    /// A badge we've met whose name or URL has changed
+    Updated,
+    Known,
+    /// A badge we haven't met before that wasn't added because the list is full
+    Ignored,
+}
+
+/// The badges that have been greeted, saved as a line of `id<TAB>name<TAB>url` for each
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Peers {
+    peers: Vec<Peer>,
+}
+
+impl Peers {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn parse(text: &str) -> Self {
+        let peers = text
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                match (fields.next(), fields.next(), fields.next()) {
+                    (Some(id), Some(name), url) if !id.is_empty() => Some(Peer {
+                        id: id.to_string(),
+                        name: name.to_string(),
+                        url: url.and_then(link),
+                    }),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        Peers { peers }
+    }
+
+    fn serialize(&self) -> String {
+        self.peers
+            .iter()
+            .map(|peer| {
+                let name = peer.name.replace('\t', " ");
+                let url = peer.url.as_ref().map(|url| url.replace('\t', " "));
+                format!("{}\t{}\t{}\n", peer.id, name, url.unwrap_or_default())
+            })
+            .collect()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        write_file_atomic(path, self.serialize())
+    }
+
+    /// Record a hello from a badge
+    pub fn greet(&mut self, hello: &Hello) -> Greeting {
+        let peer = Peer {
+            id: hello.id.clone(),
+            name: hello.name.clone(),
+            url: hello.url.as_deref().and_then(link),
+        };
+
+        let full = self.peers.len() >= MAX_PEERS;
+        match self.peers.iter_mut().find(|known| known.id == hello.id) {
+            Some(ref known) if **known == peer => Greeting::Known,
+            Some(known) => {
+                *known = peer;
+                Greeting::Updated
+            }
+            None if full => Greeting::Ignored,
+            None => {
+                self.peers.push(peer);
+                Greeting::New
+            }
+        }
+    }
+
+    pub fn knows(&self, id: &str) -> bool {
+        self.peers.iter().any(|peer| peer.id == id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn list(&self) -> &[Peer] {
+        &self.peers
+    }
+}
+
+/// When each address last introduced a new badge, so one host can't fill the peer list
+#[derive(Debug, Default)]
+struct NewPeerLimit {
+    last_new: HashMap<IpAddr, Instant>,
+}
+
+impl NewPeerLimit {
+    /// Whether `from` can introduce a new badge now, counting it if so
+    fn allow(&mut self, from: IpAddr, now: Instant) -> bool {
+        self.last_new
+            .retain(|_, last| now.duration_since(*last) < NEW_PEER_INTERVAL);
+        if self.last_new.contains_key(&from) {
+            return false;
+        }
+
+        self.last_new.insert(from, now);
+        true
+    }
+}
+
+pub struct Exchange {
+    socket: UdpSocket,
+    identity: Identity,
+    broadcast: SocketAddr,
+}
+
+impl Exchange {
+    /// Listen for hellos on `port` and announce by broadcasting to it
+    pub fn bind(identity: Identity, port: u16) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        socket.set_broadcast(true)?;
+
+        Ok(Exchange::new(
+            socket,
+            identity,
+            SocketAddr::from((Ipv4Addr::BROADCAST, port)),
+        ))
+    }
+
+    pub fn new(socket: UdpSocket, identity: Identity, broadcast: SocketAddr) -> Self {
+        Exchange {
+            socket,
+            identity,
+            broadcast,
+        }
+    }
+
+    pub fn announce(&self, url: Option<&str>) -> io::Result<()> {
+        let hello = self.identity.hello(Kind::Announce, OWNER, url, now());
+        self.socket.send_to(&hello, self.broadcast).map(|_| ())
+    }
+
+    pub fn reply(&self, to: SocketAddr, url: Option<&str>) -> io::Result<()> {
+        let hello = self.identity.hello(Kind::Reply, OWNER, url, now());
+        self.socket.send_to(&hello, to).map(|_| ())
+    }
+
+    /// Receive a hello from another badge. Invalid hellos and our own broadcasts are None.
+    pub fn receive(&self) -> io::Result<Option<(Hello, SocketAddr)>> {
+        let mut packet = [0; 2048];
+        let (len, from) = self.socket.recv_from(&mut packet)?;
+
+        match Hello::verify(&packet[..len], now()) {
+            Ok(ref hello) if hello.id == self.identity.id() => Ok(None),
+            Ok(hello) => Ok(Some((hello, from))),
+            Err(err) => {
+                debug!("Ignoring hello from {}: {}", from, err);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Announce periodically and greet the badges that reply, forever
+    pub fn run(self, state: Arc<RwLock<State>>, peers_path: PathBuf) {
+        if let Err(err) = self.socket.set_read_timeout(Some(Duration::from_secs(1))) {
+            warn!("unable to set hello read timeout: {}", err);
+        }
+        let mut last_announce: Option<Instant> = None;
+        let mut limit = NewPeerLimit::default();
+        // When a badge's details changed without being saved
+        let mut unsaved: Option<Instant> = None;
+
+        loop {
+            let save_due = unsaved
+                .map(|since| since.elapsed() >= UPDATE_SAVE_DELAY)
+                .unwrap_or(false);
+            if save_due {
+                save_peers(&state, &peers_path);
+                unsaved = None;
+            }
+
+            let url = state
+                .read()
+                .expect("poisioned")
+                .host(false)
+                .map(|host| host.url());
+
+            let due = last_announce
+                .map(|last| last.elapsed() >= ANNOUNCE_INTERVAL)
+                .unwrap_or(true);
+            if due {
+                if let Err(err) = self.announce(url.as_ref().map(String::as_str)) {
+                    warn!("unable to announce badge: {}", err);
+                }
+                last_announce = Some(Instant::now());
+            }
+
+            let (hello, from) = match self.receive() {
+                Ok(Some(received)) => received,
+                Ok(None) => continue,
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::WouldBlock
+                        || err.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(err) => {
+                    warn!("hello receive error: {}", err);
+                    continue;
+                }
+            };
+
+            let greeting = {
+                let mut state = state.write().expect("poisioned");
+                if state.peers.knows(&hello.id) || limit.allow(from.ip(), Instant::now()) {
+                    state.peers.greet(&hello)
+                } else {
+                    Greeting::Ignored
+                }
+            };
+            // Only new badges are saved straight away, a flood of hellos mustn't wear the SD card
+            match greeting {
+                Greeting::New => {
+                    save_peers(&state, &peers_path);
+                    unsaved = None;
+                }
+                Greeting::Updated => {
+                    unsaved.get_or_insert_with(Instant::now);
+                }
+                Greeting::Ignored => debug!("Ignoring new badge from {}", from),
+                Greeting::Known => {}
+            }
+            if greeting == Greeting::New {
+                info!("Met {}'s badge ({})", hello.name, from);
+                if hello.kind == Kind::Announce {
+                    if let Err(err) = self.reply(from, url.as_ref().map(String::as_str)) {
+                        warn!("unable to reply to {}: {}", from, err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    system::unix_time(SystemTime::now())
+}
+
+fn save_peers(state: &RwLock<State>, path: &Path) {
+    if let Err(err) = state.read().expect("poisioned").peers.save(path) {
+        error!("unable to save peers: {}", err);
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 == 1 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_548_032_400;
+
+    fn identity(seed: u8) -> Identity {
+        Identity::from_secret(&[seed; 32]).unwrap()
+    }
+
+    fn loopback() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket
+    }
+
+    #[test]
+    fn test_hello_round_trip() {
+        let identity = identity(1);
+        let bytes = identity.hello(
+            Kind::Announce,
+            "Wes\nley",
+            Some("http://10.0.0.18/"),
+            NOW,
+        );
+
+        assert_eq!(
+            Hello::verify(&bytes, NOW + 2).unwrap(),
+            Hello {
+                kind: Kind::Announce,
+                id: identity.id(),
+                name: "Wes ley".to_string(),
+                url: Some("http://10.0.0.18/".to_string()),
+                time: NOW,
+            }
+        );
+    }
+
+    #[test]
+    fn test_stale_hello() {
+        let bytes = identity(1).hello(Kind::Announce, "Wesley", None, NOW);
+
+        assert!(Hello::verify(&bytes, NOW + MAX_HELLO_AGE).is_ok());
+        // Replayed later
+        assert!(Hello::verify(&bytes, NOW + MAX_HELLO_AGE + 1).is_err());
+        // From a badge whose clock is well ahead
+        assert!(Hello::verify(&bytes, NOW - MAX_HELLO_AGE - 1).is_err());
+    }
+
+    #[test]
+    fn test_tampered_hello() {
+        let bytes = identity(1).hello(Kind::Announce, "Wesley", None, NOW);
+        let text = String::from_utf8(bytes).unwrap();
+        let renamed = text.replace("name=Wesley", "name=Mallory");
+        let refreshed = text.replace(&format!("time={}", NOW), &format!("time={}", NOW + 600));
+
+        assert!(Hello::verify(renamed.as_bytes(), NOW).is_err());
+        assert!(Hello::verify(refreshed.as_bytes(), NOW + 600).is_err());
+    }
+
+    #[test]
+    fn test_versions() {
+        let identity = identity(2);
+        let sign = |text: String| {
+            let signature = identity.keypair.sign(text.as_bytes());
+            format!("{}sig={}\n", text, to_hex(&signature.to_bytes()))
+        };
+
+        // Unknown fields are ignored
+        let newer_fields = sign(format!(
+            "badge-hello/2\nkind=reply\nid={}\nname=Future\ntime={}\nemoji=👋\n",
+            identity.id(),
+            NOW
+        ));
+        assert_eq!(
+            Hello::verify(newer_fields.as_bytes(), NOW).unwrap().name,
+            "Future"
+        );
+
+        let next_version = sign(format!(
+            "badge-hello/3\nkind=reply\nid={}\nname=Future\ntime={}\n",
+            identity.id(),
+            NOW
+        ));
+        assert!(Hello::verify(next_version.as_bytes(), NOW).is_err());
+
+        // Version 1 hellos weren't timestamped, so could be replayed
+        let untimed = sign(format!(
+            "badge-hello/1\nkind=reply\nid={}\nname=Past\n",
+            identity.id()
+        ));
+        assert!(Hello::verify(untimed.as_bytes(), NOW).is_err());
+    }
+
+    #[test]
+    fn test_peers_round_trip() {
+        let mut peers = Peers::default();
+        let hello = Hello {
+            kind: Kind::Announce,
+            id: "abcdef0123".to_string(),
+            name: "Someone".to_string(),
+            url: None,
+            time: NOW,
+        };
+        assert_eq!(peers.greet(&hello), Greeting::New);
+        assert_eq!(peers.greet(&hello), Greeting::Known);
+        let moved = Hello {
+            url: Some("http://10.0.0.7/".to_string()),
+            ..hello.clone()
+        };
+        assert_eq!(peers.greet(&moved), Greeting::Updated);
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers.list()[0].short_id(), "abcdef01");
+
+        assert_eq!(Peers::parse(&peers.serialize()), peers);
+    }
+
+    #[test]
+    fn test_only_web_links() {
+        let url = |url: &str| {
+            let bytes = identity(3).hello(Kind::Reply, "Someone", Some(url), NOW);
+            Hello::verify(&bytes, NOW).unwrap().url
+        };
+
+        assert_eq!(url("http://10.0.0.18/"), Some("http://10.0.0.18/".to_string()));
+        assert_eq!(
+            url("https://badge.example/hi"),
+            Some("https://badge.example/hi".to_string())
+        );
+        assert_eq!(url("javascript:alert(1)"), None);
+        assert_eq!(url("data:text/html,hi"), None);
+        assert_eq!(url("http://"), None);
+        assert_eq!(url("/relative"), None);
+
+        // Saved before URLs were checked
+        let peers = Peers::parse("abcdef0123\tSomeone\tjavascript:alert(1)\n");
+        assert_eq!(peers.list()[0].url, None);
+    }
+
+    #[test]
+    fn test_peers_full() {
+        let mut peers = Peers::default();
+        let hello = |n: usize| Hello {
+            kind: Kind::Announce,
+            id: format!("{:010x}", n),
+            name: "Someone".to_string(),
+            url: None,
+            time: NOW,
+        };
+        for n in 0..MAX_PEERS {
+            assert_eq!(peers.greet(&hello(n)), Greeting::New);
+        }
+
+        assert_eq!(peers.greet(&hello(MAX_PEERS)), Greeting::Ignored);
+        assert!(!peers.knows(&hello(MAX_PEERS).id));
+        // Badges already met can still change their details
+        let renamed = Hello {
+            name: "Someone Else".to_string(),
+            ..hello(0)
+        };
+        assert_eq!(peers.greet(&renamed), Greeting::Updated);
+        assert_eq!(peers.len(), MAX_PEERS);
+    }
+
+    #[test]
+    fn test_new_peer_limit() {
+        let mut limit = NewPeerLimit::default();
+        let (a, b) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        let start = Instant::now();
+
+        assert!(limit.allow(a, start));
+        assert!(!limit.allow(a, start + Duration::from_secs(1)));
+        assert!(limit.allow(b, start + Duration::from_secs(1)));
+        assert!(!limit.allow(a, start + NEW_PEER_INTERVAL - Duration::from_secs(1)));
+        assert!(limit.allow(a, start + NEW_PEER_INTERVAL));
+    }
+
+    #[test]
+    fn test_exchange_on_loopback() {
+        let (socket_a, socket_b) = (loopback(), loopback());
+        let (addr_a, addr_b) = (socket_a.local_addr().unwrap(), socket_b.local_addr().unwrap());
+        // Each badge's broadcast goes to the other
+        let badge_a = Exchange::new(socket_a, identity(1), addr_b);
+        let badge_b = Exchange::new(socket_b, identity(2), addr_a);
+        let (mut peers_a, mut peers_b) = (Peers::default(), Peers::default());
+
+        badge_a.announce(Some("http://127.0.0.1:8001/")).unwrap();
+        let (hello, from) = badge_b.receive().unwrap().unwrap();
+        assert_eq!(hello.kind, Kind::Announce);
+        assert_eq!(hello.id, badge_a.identity.id());
+        assert_eq!(from, addr_a);
+        assert_eq!(peers_b.greet(&hello), Greeting::New);
+
+        badge_b.reply(from, None).unwrap();
+        let (hello, _) = badge_a.receive().unwrap().unwrap();
+        assert_eq!(hello.kind, Kind::Reply);
+        assert_eq!(hello.id, badge_b.identity.id());
+        assert_eq!(peers_a.greet(&hello), Greeting::New);
+
+        assert_eq!(peers_a.list()[0].name, OWNER);
+        assert_eq!(
+            peers_b.list()[0].url,
+            Some("http://127.0.0.1:8001/".to_string())
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//! Saving the hello count and history when they change, whether or not the display is running
+
+use std::cmp;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use log::{debug, error};
+
+use crate::app::{write_file_atomic, State};
+use crate::history;
+
+pub struct Persister {
+    path: PathBuf,
+    history_path: PathBuf,
+    debounce: Duration,
+    flush_interval: Duration,
+    /// What was last written, held while saving so two saves can't overlap
+    saved: Mutex<Saved>,
+}
+
+struct Saved {
+    counts: String,
+    /// Number of hellos in the history file
+    hellos: usize,
+    /// `History::truncations` when the history file was last written
+    truncations: usize,
+}
+
+/// When to save next
+#[derive(Debug)]
+struct Schedule {
+    debounce: Duration,
+    flush_interval: Duration,
+    /// The first and latest change since the last save
+    pending: Option<(Instant, Instant)>,
+    last_save: Instant,
+}
+
+impl Schedule {
+    fn changed(&mut self, now: Instant) {
+        self.pending = Some(match self.pending {
+            Some((first, _)) => (first, now),
+            None => (now, now),
+        });
+    }
+
+    fn next(&self) -> Instant {
+        match self.pending {
+            // Wait for the changes to stop, but not for longer than the flush interval
+            Some((first, last)) => cmp::min(last + self.debounce, first + self.flush_interval),
+            None => self.last_save + self.flush_interval,
+        }
+    }
+
+    fn saved(&mut self, now: Instant) {
+        self.pending = None;
+        self.last_save = now;
+    }
+}
+
+impl Persister {
+    /// `state` is what's in the files at `path` and `history_path` now, so they're not written
+    /// again until it changes
+    pub fn new(
+        path: PathBuf,
+        history_path: PathBuf,
+        state: &State,
+        debounce: Duration,
+        flush_interval: Duration,
+    ) -> Self {
+        Persister {
+            path,
+            history_path,
+            debounce,
+            flush_interval,
+            saved: Mutex::new(Saved {
+                counts: state.hello_counts().serialize(),
+                hellos: state.history.records().len(),
+                truncations: state.history.truncations(),
+            }),
+        }
+    }
+
+    /// Save the hello count if it's changed since it was last saved, and add new hellos to the
+    /// history. The history is rewritten if hellos have been removed from it.
+    pub fn save(&self, state: &RwLock<State>) -> io::Result<()> {
+        let mut saved = self.saved.lock().expect("poisioned");
+        let (contents, hellos, truncations) = {
+            let state = state.read().expect("poisioned");
+            let records = state.history.records();
+            let truncations = state.history.truncations();
+            let hellos = if truncations == saved.truncations {
+                records[saved.hellos..].to_vec()
+            } else {
+                records.to_vec()
+            };
+            (state.hello_counts().serialize(), hellos, truncations)
+        };
+
+        if truncations != saved.truncations {
+            history::save(&self.history_path, &hellos)?;
+            saved.hellos = hellos.len();
+            saved.truncations = truncations;
+            debug!("Rewrote the history with {} hellos", hellos.len());
+        } else if !hellos.is_empty() {
+            history::append(&self.history_path, &hellos)?;
+            saved.hellos += hellos.len();
+            debug!("Saved {} hellos to the history", hellos.len());
+        }
+        if contents != saved.counts {
+            write_file_atomic(&self.path, &contents)?;
+            saved.counts = contents;
+            debug!("Saved hi count");
+        }
+
+        Ok(())
+    }
+
+    /// Save `debounce` after changes stop, and every `flush_interval` regardless
+    pub fn run(&self, state: &RwLock<State>, changes: Receiver<()>) {
+        let mut schedule = Schedule {
+            debounce: self.debounce,
+            flush_interval: self.flush_interval,
+            pending: None,
+            last_save: Instant::now(),
+        };
+
+        loop {
+            let wait = schedule.next().saturating_duration_since(Instant::now());
+            match changes.recv_timeout(wait) {
+                Ok(()) => {
+                    schedule.changed(Instant::now());
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            if let Err(err) = self.save(state) {
+                error!("unable to save hi count: {}", err);
+            }
+            schedule.saved(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::hellos::HelloCounts;
+    use crate::history::History;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    #[test]
+    fn test_schedule() {
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        let mut schedule = Schedule {
+            debounce: Duration::from_secs(2),
+            flush_interval: Duration::from_secs(30),
+            pending: None,
+            last_save: start,
+        };
+        assert_eq!(schedule.next(), secs(30));
+
+        schedule.changed(secs(1));
+        assert_eq!(schedule.next(), secs(3));
+        schedule.changed(secs(2));
+        assert_eq!(schedule.next(), secs(4));
+
+        // A steady stream of changes still gets saved
+        schedule.changed(secs(30));
+        assert_eq!(schedule.next(), secs(31));
+
+        schedule.saved(secs(31));
+        assert_eq!(schedule.next(), secs(61));
+    }
+
+    // What happens with --nodisplay, where there's no display thread to save the count
+    #[test]
+    fn test_saves_changes() {
+        let path = env::temp_dir().join(format!("lca2019-{}-hi_count.txt", process::id()));
+        let history_path =
+            path.with_file_name(format!("lca2019-{}-persist-history.jsonl", process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&history_path);
+
+        let config = Config {
+            interfaces: vec!["lo".to_string()],
+            dedup_window: Duration::from_secs(60),
+            show_hostname: false,
+            show_sources: false,
+        };
+        let state = State::new(HelloCounts::default(), config, None);
+        let (changed_tx, changed_rx) = mpsc::channel();
+        let persister = Arc::new(Persister::new(
+            path.clone(),
+            history_path.clone(),
+            &state,
+            Duration::from_millis(10),
+            Duration::from_secs(60),
+        ));
+        let state = Arc::new(RwLock::new(state));
+        state.write().unwrap().changed_tx = Some(changed_tx);
+        {
+            let persister = persister.clone();
+            let state = state.clone();
+            thread::spawn(move || persister.run(&state, changed_rx));
+        }
+
+        state.write().unwrap().inc_hi_count_in_person();
+        state.write().unwrap().inc_hi_count_in_person();
+        thread::sleep(Duration::from_millis(200));
+
+        let saved = HelloCounts::parse(&fs::read_to_string(&path).unwrap());
+        assert_eq!(saved.total(), 2);
+        let history = History::load(&history_path).unwrap();
+        assert_eq!(history.records(), state.read().unwrap().history.records());
+
+        // `lca2019 count set 1`
+        state
+            .write()
+            .unwrap()
+            .set_hello_counts(HelloCounts::with_total(1));
+        state.write().unwrap().inc_hi_count_in_person();
+        thread::sleep(Duration::from_millis(200));
+
+        let history = History::load(&history_path).unwrap();
+        assert_eq!(history.records().len(), 2);
+        assert_eq!(history.records(), state.read().unwrap().history.records());
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&history_path);
+    }
+}
@@ -0,0 +1,433 @@
+//! Checking the display hardware when a badge misbehaves: whether SPI and the GPIO pins can be set
+//! up, whether the controller responds, then drawing test patterns and timing each refresh
+
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use embedded_graphics::coord::Coord;
+use embedded_graphics::prelude::*;
+use embedded_graphics::Drawing;
+use linux_embedded_hal::Delay;
+use profont::{ProFont12Point, ProFont14Point, ProFont7Point, ProFont9Point};
+use qrcode::{EcLevel, QrCode};
+use ssd1675::interface::DisplayInterface;
+use ssd1675::{Color, GraphicDisplay, Interface};
+use structopt::StructOpt;
+
+use crate::decoder::{self, Recorder};
+use crate::display::{self, QrCodeIterator, COLS, ROWS};
+use crate::hardware::{self, Board};
+use crate::lut::{Lut, Preset};
+
+/// How long the controller may take to come out of reset
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const QR_URL: &str = "https://github.com/wezm/linux-conf-au-2019-epaper-badge";
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct SelftestOptions {
+    /// Run the patterns against fake SPI and GPIO, checking what would be sent to the panel
+    #[structopt(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Pattern {
+    Checkerboard,
+    Red,
+    Black,
+    Gradient,
+    QrCode,
+}
+
+impl Pattern {
+    pub const ALL: [Pattern; 5] = [
+        Pattern::Checkerboard,
+        Pattern::Red,
+        Pattern::Black,
+        Pattern::Gradient,
+        Pattern::QrCode,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Pattern::Checkerboard => "checkerboard",
+            Pattern::Red => "all red",
+            Pattern::Black => "all black",
+            Pattern::Gradient => "gradient text",
+            Pattern::QrCode => "QR code",
+        }
+    }
+
+    pub fn draw<D: Drawing<Color>>(self, display: &mut D) {
+        match self {
+            Pattern::Checkerboard => display.draw(pixels(|x, y| {
+                if (x / 8 + y / 8) % 2 == 0 {
+                    Color::Black
+                } else {
+                    Color::White
+                }
+            })),
+            Pattern::Red => display.draw(pixels(|_, _| Color::Red)),
+            Pattern::Black => display.draw(pixels(|_, _| Color::Black)),
+            Pattern::Gradient => draw_gradient(display),
+            Pattern::QrCode => {
+                let qrcode = QrCode::with_error_correction_level(QR_URL, EcLevel::L).unwrap();
+                let size = qrcode.width() as i32 * 2;
+                let top_left = Coord::new(
+                    (i32::from(ROWS) - size) / 2,
+                    (i32::from(COLS) - size) / 2,
+                );
+                display.draw(QrCodeIterator::new(qrcode, top_left, 2));
+            }
+        }
+    }
+}
+
+/// Every pixel of the display, coloured by `colour`
+fn pixels<F>(colour: F) -> impl Iterator<Item = Pixel<Color>>
+where
+    F: Fn(i32, i32) -> Color + Copy,
+{
+    (0..i32::from(COLS)).flat_map(move |y| {
+        (0..i32::from(ROWS)).map(move |x| Pixel(Coord::new(x, y).to_unsigned(), colour(x, y)))
+    })
+}
+
+/// A band shading from black to white, dithered, above text in each of the smaller font sizes
+fn draw_gradient<D: Drawing<Color>>(display: &mut D) {
+    // 4x4 ordered dither thresholds
+    const BAYER: [[i32; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+    let width = i32::from(ROWS);
+    display.draw((0..32).flat_map(move |y| {
+        (0..width).map(move |x| {
+            let shade = x * 16 / width;
+            let colour = if shade > BAYER[y as usize % 4][x as usize % 4] {
+                Color::White
+            } else {
+                Color::Black
+            };
+            Pixel(Coord::new(x, y).to_unsigned(), colour)
+        })
+    }));
+
+    let text = "The quick brown fox";
+    display.draw(
+        ProFont7Point::render_str(text)
+            .with_stroke(Some(Color::Black))
+            .with_fill(Some(Color::White))
+            .translate(Coord::new(1, 34))
+            .into_iter(),
+    );
+    display.draw(
+        ProFont9Point::render_str(text)
+            .with_stroke(Some(Color::Red))
+            .with_fill(Some(Color::White))
+            .translate(Coord::new(1, 44))
+            .into_iter(),
+    );
+    display.draw(
+        ProFont12Point::render_str(text)
+            .with_stroke(Some(Color::Black))
+            .with_fill(Some(Color::White))
+            .translate(Coord::new(1, 56))
+            .into_iter(),
+    );
+    display.draw(
+        ProFont14Point::render_str(text)
+            .with_stroke(Some(Color::Red))
+            .with_fill(Some(Color::White))
+            .translate(Coord::new(1, 72))
+            .into_iter(),
+    );
+}
+
+/// What a pattern should look like, to compare with what would be sent to the panel
+struct Canvas {
+    pixels: Vec<Color>,
+}
+
+impl Canvas {
+    fn new() -> Self {
+        Canvas {
+            pixels: vec![Color::White; ROWS as usize * COLS as usize],
+        }
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> Color {
+        self.pixels[y as usize * ROWS as usize + x as usize]
+    }
+}
+
+impl Drawing<Color> for Canvas {
+    fn draw<T>(&mut self, pixels: T)
+    where
+        T: Iterator<Item = Pixel<Color>>,
+    {
+        for Pixel(coord, colour) in pixels {
+            if coord.0 < u32::from(ROWS) && coord.1 < u32::from(COLS) {
+                self.pixels[coord.1 as usize * ROWS as usize + coord.0 as usize] = colour;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Outcome {
+    Pass(String),
+    Fail(String),
+}
+
+#[derive(Debug, Clone)]
+struct Check {
+    name: String,
+    elapsed: Duration,
+    outcome: Outcome,
+}
+
+/// The checks that have been run, in order
+#[derive(Debug, Default)]
+struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    /// Time `check`, recording `hint` with the error if it fails. Returns its value if it passed.
+    fn run<T, F>(&mut self, name: &str, hint: &str, check: F) -> Option<T>
+    where
+        F: FnOnce() -> io::Result<(T, String)>,
+    {
+        let started = Instant::now();
+        let result = check();
+        let elapsed = started.elapsed();
+
+        let (value, outcome) = match result {
+            Ok((value, detail)) => (Some(value), Outcome::Pass(detail)),
+            Err(err) => (None, Outcome::Fail(format!("{}, {}", err, hint))),
+        };
+        self.checks.push(Check {
+            name: name.to_string(),
+            elapsed,
+            outcome,
+        });
+
+        value
+    }
+
+    fn failures(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| matches!(check.outcome, Outcome::Fail(_)))
+            .count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            let (status, detail) = match check.outcome {
+                Outcome::Pass(ref detail) => ("PASS", detail),
+                Outcome::Fail(ref detail) => ("FAIL", detail),
+            };
+            let elapsed = format!("{:.1}s", check.elapsed.as_secs_f32());
+            writeln!(f, "{}  {:<18} {:>6}  {}", status, check.name, elapsed, detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the display hardware and print a report, failing if any check did
+pub fn selftest(options: &SelftestOptions) -> io::Result<()> {
+    let lut = Lut::preset(Preset::Default);
+    let mut report = Report::default();
+    if options.dry_run {
+        dry_run(&mut report, lut.as_bytes());
+    } else {
+        panel(&mut report, lut.as_bytes());
+    }
+
+    print!("{}", report);
+    match report.failures() {
+        0 => Ok(()),
+        failures => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} of {} checks failed", failures, report.checks.len()),
+        )),
+    }
+}
+
+/// Set up the SPI device and pins of `board` step by step, stopping at the first step that fails
+fn setup<B: Board>(
+    report: &mut Report,
+    board: &B,
+) -> Option<Interface<B::Spi, B::Pin, B::Pin, B::Pin, B::Pin>> {
+    let mut spi = report.run("SPI open", "is SPI enabled in raspi-config?", || {
+        board
+            .open_spi()
+            .map(|spi| (spi, hardware::SPI_DEVICE.to_string()))
+    })?;
+    report.run("SPI configuration", "is SPI enabled in raspi-config?", || {
+        board
+            .configure_spi(&mut spi)
+            .map(|()| ((), "mode 0 at 4MHz".to_string()))
+    })?;
+
+    let hint = "is another process using the pin?";
+    let exported = |number| move |pin| (pin, format!("BCM{}", number));
+    let cs = report.run("CS pin", hint, || {
+        board
+            .output_pin(hardware::CS_PIN, 1)
+            .map(exported(hardware::CS_PIN))
+    })?;
+    let busy = report.run("busy pin", hint, || {
+        board
+            .input_pin(hardware::BUSY_PIN)
+            .map(exported(hardware::BUSY_PIN))
+    })?;
+    let dc = report.run("D/C pin", hint, || {
+        board
+            .output_pin(hardware::DC_PIN, 1)
+            .map(exported(hardware::DC_PIN))
+    })?;
+    let mut reset = report.run("reset pin", hint, || {
+        board
+            .output_pin(hardware::RESET_PIN, 1)
+            .map(exported(hardware::RESET_PIN))
+    })?;
+
+    report.run("busy line", "is the display seated on the header?", || {
+        hardware::reset_and_wait(&mut reset, &busy, BUSY_TIMEOUT)
+            .map(|wait| ((), format!("ready {}ms after reset", wait.as_millis())))
+    })?;
+
+    Some(Interface::new(spi, cs, busy, dc, reset))
+}
+
+/// Set up the real hardware, then draw the patterns on the panel
+fn panel(report: &mut Report, lut: &[u8]) -> Option<()> {
+    let controller = setup(report, &hardware::Pi)?;
+    let mut black_buffer = [0u8; ROWS as usize * COLS as usize / 8];
+    let mut red_buffer = [0u8; ROWS as usize * COLS as usize / 8];
+    let mut display = GraphicDisplay::new(
+        hardware::display_with(controller, COLS, ROWS, lut),
+        &mut black_buffer,
+        &mut red_buffer,
+    );
+    for &pattern in &Pattern::ALL {
+        report.run(pattern.name(), "the panel may be faulty", || {
+            draw_pattern(&mut display, pattern).map(|()| ((), String::new()))
+        });
+    }
+
+    Some(())
+}
+
+/// Set up fake hardware and draw the patterns through the recorder, checking the decoded frames
+/// match what was drawn
+fn dry_run(report: &mut Report, lut: &[u8]) -> Option<()> {
+    let recorder = Recorder::new();
+    let controller = setup(report, &recorder)?;
+    let mut black_buffer = [0u8; ROWS as usize * COLS as usize / 8];
+    let mut red_buffer = [0u8; ROWS as usize * COLS as usize / 8];
+    let mut display = GraphicDisplay::new(
+        hardware::display_with(controller, COLS, ROWS, lut),
+        &mut black_buffer,
+        &mut red_buffer,
+    );
+    for &pattern in &Pattern::ALL {
+        recorder.clear();
+        report.run(pattern.name(), "the display code is broken", || {
+            draw_pattern(&mut display, pattern)?;
+            let frame = decoder::decode(&recorder.transfers(), COLS, ROWS)
+                .pop()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "nothing was drawn"))?;
+
+            let mut canvas = Canvas::new();
+            pattern.draw(&mut canvas);
+            let (mut black, mut red, mut wrong) = (0, 0, 0);
+            for y in 0..u32::from(COLS) {
+                for x in 0..u32::from(ROWS) {
+                    let colour = frame.pixel(x, y, hardware::ROTATION);
+                    match colour {
+                        Some(Color::Black) => black += 1,
+                        Some(Color::Red) => red += 1,
+                        _ => {}
+                    }
+                    if colour != Some(canvas.pixel(x, y)) {
+                        wrong += 1;
+                    }
+                }
+            }
+
+            if wrong > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} pixels differ from the pattern", wrong),
+                ));
+            }
+            Ok(((), format!("{} black, {} red pixels", black, red)))
+        });
+    }
+
+    Some(())
+}
+
+fn draw_pattern<I>(display: &mut GraphicDisplay<I>, pattern: Pattern) -> io::Result<()>
+where
+    I: DisplayInterface,
+    I::Error: fmt::Debug,
+{
+    display::show(display, &mut Delay {}, |display| pattern.draw(display)).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("unable to draw the pattern: {:?}", err),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dry_run() {
+        let lut = Lut::preset(Preset::Default);
+        let mut report = Report::default();
+        dry_run(&mut report, lut.as_bytes());
+
+        assert_eq!(report.failures(), 0, "{}", report);
+        let busy_line = report
+            .checks
+            .iter()
+            .find(|check| check.name == "busy line")
+            .unwrap();
+        // The fake controller is busy for a moment after the reset
+        assert!(matches!(busy_line.outcome, Outcome::Pass(_)));
+        let all_red = report
+            .checks
+            .iter()
+            .find(|check| check.name == "all red")
+            .unwrap();
+        assert_eq!(
+            all_red.outcome,
+            Outcome::Pass(format!("0 black, {} red pixels", ROWS as usize * COLS as usize))
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::default();
+        assert_eq!(report.run("one", "hint", || Ok((1, "fine".to_string()))), Some(1));
+        let failed: Option<()> = report.run("two", "check the wiring", || {
+            Err(io::Error::new(io::ErrorKind::Other, "broken"))
+        });
+        assert!(failed.is_none());
+
+        assert_eq!(report.failures(), 1);
+        let lines = report.to_string();
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("PASS  one                  0.0s  fine"));
+        assert!(lines[1].ends_with("broken, check the wiring"));
+    }
+}
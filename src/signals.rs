@@ -0,0 +1,48 @@
+//! Waiting for the signals that ask the badge to shut down or reload its config
+
+use std::io;
+
+use nix::sys::signal::{SigSet, Signal};
+
+/// Signals that are blocked so that a thread can wait for them
+pub struct Signals(SigSet);
+
+impl Signals {
+    /// Block SIGTERM, SIGINT, and SIGHUP. Call this before starting any threads, they inherit it.
+    pub fn block() -> io::Result<Self> {
+        Self::block_set(&[Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP])
+    }
+
+    fn block_set(signals: &[Signal]) -> io::Result<Self> {
+        let mut set = SigSet::empty();
+        for &signal in signals {
+            set.add(signal);
+        }
+        set.thread_block().map_err(nix_error)?;
+
+        Ok(Signals(set))
+    }
+
+    /// Wait for one of the signals to arrive
+    pub fn wait(&self) -> io::Result<Signal> {
+        self.0.wait().map_err(nix_error)
+    }
+}
+
+fn nix_error(err: nix::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::signal::raise;
+
+    #[test]
+    fn test_wait() {
+        // SIGUSR2 so the test harness isn't stopped if it goes wrong
+        let signals = Signals::block_set(&[Signal::SIGUSR2]).unwrap();
+        raise(Signal::SIGUSR2).unwrap();
+        assert_eq!(signals.wait().unwrap(), Signal::SIGUSR2);
+    }
+}
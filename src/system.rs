@@ -1,4 +1,13 @@
 use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nix::libc;
+use systemstat::{ByteSize, Filesystem};
 
 const ONE_DAY: u64 = 24 * 60 * 60;
 const ONE_HOUR: u64 = 60 * 60;
@@ -10,6 +19,10 @@ impl Uptime {
     pub fn new(seconds: u64) -> Self {
         Uptime(seconds)
     }
+
+    pub fn seconds(&self) -> u64 {
+        self.0
+    }
 }
 
 impl fmt::Display for Uptime {
@@ -36,3 +49,324 @@ impl fmt::Display for Uptime {
         f.write_str(&fragments.join(" "))
     }
 }
+
+/// Displays "unknown" if the value is missing
+pub struct Metric<T>(pub Option<T>);
+
+impl<T: fmt::Display> fmt::Display for Metric<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.0 {
+            Some(ref value) => value.fmt(f),
+            None => f.write_str("unknown"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LoadAverage {
+    pub one: f32,
+    pub five: f32,
+    pub fifteen: f32,
+}
+
+impl LoadAverage {
+    pub fn read() -> io::Result<Self> {
+        Self::parse(&fs::read_to_string("/proc/loadavg")?)
+    }
+
+    fn parse(loadavg: &str) -> io::Result<Self> {
+        let mut fields = loadavg
+            .split_whitespace()
+            .map(|field| f32::from_str(field).map_err(|_| invalid_data("invalid load average")));
+        let mut next = || {
+            fields
+                .next()
+                .unwrap_or_else(|| Err(invalid_data("missing load average")))
+        };
+
+        Ok(LoadAverage {
+            one: next()?,
+            five: next()?,
+            fifteen: next()?,
+        })
+    }
+}
+
+impl fmt::Display for LoadAverage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{:.2} {:.2} {:.2}", self.one, self.five, self.fifteen)
+    }
+}
+
+#[derive(Debug)]
+pub struct DiskUsage {
+    pub avail: ByteSize,
+    pub total: ByteSize,
+}
+
+impl From<Filesystem> for DiskUsage {
+    fn from(filesystem: Filesystem) -> Self {
+        DiskUsage {
+            avail: filesystem.avail,
+            total: filesystem.total,
+        }
+    }
+}
+
+impl fmt::Display for DiskUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{} free of {}", self.avail, self.total)
+    }
+}
+
+/// Bytes received and sent by a network interface
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NetworkTraffic {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+impl NetworkTraffic {
+    pub fn read(interface: &str) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string("/proc/net/dev")?, interface)
+    }
+
+    /// Parse the statistics for `interface` out of the contents of /proc/net/dev
+    fn parse(net_dev: &str, interface: &str) -> io::Result<Self> {
+        for line in net_dev.lines() {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(stats)) if name.trim() == interface => {
+                    // Receive bytes is the first field, transmit bytes the ninth
+                    let fields = stats
+                        .split_whitespace()
+                        .map(u64::from_str)
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| invalid_data("invalid network statistics"))?;
+                    return match (fields.first(), fields.get(8)) {
+                        (Some(&rx_bytes), Some(&tx_bytes)) => {
+                            Ok(NetworkTraffic { rx_bytes, tx_bytes })
+                        }
+                        _ => Err(invalid_data("missing network statistics")),
+                    };
+                }
+                _ => (),
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no statistics for {}", interface),
+        ))
+    }
+}
+
+impl fmt::Display for NetworkTraffic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "{} received, {} sent",
+            ByteSize::b(self.rx_bytes),
+            ByteSize::b(self.tx_bytes)
+        )
+    }
+}
+
+/// Battery charge percentage
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Battery(pub u8);
+
+impl Battery {
+    /// Read the capacity of a power supply directory, E.g. /sys/class/power_supply/BAT0, or a
+    /// file containing the percentage, like the one a UPS HAT's fuel gauge driver provides.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let capacity = if path.is_dir() {
+            fs::read_to_string(path.join("capacity"))?
+        } else {
+            fs::read_to_string(path)?
+        };
+        f32::from_str(capacity.trim())
+            .map(|percent| Battery(percent.clamp(0., 100.).round() as u8))
+            .map_err(|_| invalid_data("invalid battery capacity"))
+    }
+}
+
+impl fmt::Display for Battery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}%", self.0)
+    }
+}
+
+/// Wifi link quality percentage
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WifiQuality(pub u8);
+
+impl WifiQuality {
+    // Link quality is reported out of 70 by most drivers, including the Pi's
+    const MAX_LINK_QUALITY: f32 = 70.;
+
+    pub fn read(interface: &str) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string("/proc/net/wireless")?, interface)
+    }
+
+    /// Parse the link quality of `interface` out of the contents of /proc/net/wireless
+    fn parse(wireless: &str, interface: &str) -> io::Result<Self> {
+        for line in wireless.lines() {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(stats)) if name.trim() == interface => {
+                    // Status is the first field, link quality the second, E.g. "54."
+                    let link = stats
+                        .split_whitespace()
+                        .nth(1)
+                        .and_then(|link| f32::from_str(link.trim_end_matches('.')).ok())
+                        .ok_or_else(|| invalid_data("invalid wifi link quality"))?;
+                    let percent = link / Self::MAX_LINK_QUALITY * 100.;
+                    return Ok(WifiQuality(percent.clamp(0., 100.).round() as u8));
+                }
+                _ => (),
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not a wireless interface", interface),
+        ))
+    }
+}
+
+impl fmt::Display for WifiQuality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}%", self.0)
+    }
+}
+
+/// The local time of day as HH:MM, None if the clock hasn't been set yet
+pub fn clock_time(time: SystemTime) -> Option<String> {
+    let secs = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    let result = unsafe { libc::localtime_r(&secs, &mut tm) };
+
+    // Without an RTC the Pi starts in 1970 until NTP sets the clock
+    if result.is_null() || tm.tm_year + 1900 < 2019 {
+        None
+    } else {
+        Some(format!("{:02}:{:02}", tm.tm_hour, tm.tm_min))
+    }
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// How many seconds the local time zone is ahead of UTC at `time`
+pub fn utc_offset(time: SystemTime) -> i64 {
+    let secs = unix_time(time) as libc::time_t;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    let result = unsafe { libc::localtime_r(&secs, &mut tm) };
+
+    if result.is_null() {
+        0
+    } else {
+        tm.tm_gmtoff as i64
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    const NET_DEV: &str = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:    2776      36    0    0    0     0          0         0     2776      36    0    0    0     0       0          0
+ wlan0: 1843721    3095    0    0    0     0          0        22   412330    1874    0    0    0     0       0          0
+";
+
+    #[test]
+    fn test_parse_load_average() {
+        let load = LoadAverage::parse("0.12 0.34 1.50 1/123 4567\n").unwrap();
+        assert_eq!(
+            load,
+            LoadAverage {
+                one: 0.12,
+                five: 0.34,
+                fifteen: 1.5
+            }
+        );
+        assert_eq!(load.to_string(), "0.12 0.34 1.50");
+        assert!(LoadAverage::parse("0.12").is_err());
+    }
+
+    #[test]
+    fn test_parse_network_traffic() {
+        let traffic = NetworkTraffic::parse(NET_DEV, "wlan0").unwrap();
+        assert_eq!(
+            traffic,
+            NetworkTraffic {
+                rx_bytes: 1843721,
+                tx_bytes: 412330
+            }
+        );
+        assert_eq!(
+            NetworkTraffic::parse(NET_DEV, "eth0").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_read_battery() {
+        let power_supply = env::temp_dir().join(format!("lca2019-{}-BAT0", process::id()));
+        fs::create_dir_all(&power_supply).unwrap();
+        fs::write(power_supply.join("capacity"), "87\n").unwrap();
+
+        assert_eq!(Battery::read(&power_supply).unwrap(), Battery(87));
+        fs::remove_dir_all(power_supply).unwrap();
+    }
+
+    #[test]
+    fn test_read_fuel_gauge() {
+        let fuel_gauge = env::temp_dir().join(format!("lca2019-{}-fuel_gauge", process::id()));
+        fs::write(&fuel_gauge, "64.6\n").unwrap();
+
+        assert_eq!(Battery::read(&fuel_gauge).unwrap(), Battery(65));
+        fs::remove_file(fuel_gauge).unwrap();
+    }
+
+    #[test]
+    fn test_parse_wifi_quality() {
+        let wireless = "\
+Inter-| sta-|   Quality        |   Discarded packets               | Missed | WE
+ face | tus | link level noise |  nwid  crypt   frag  retry   misc | beacon | 22
+ wlan0: 0000   56.  -54.  -256        0      0      0      0     12        0
+";
+        assert_eq!(
+            WifiQuality::parse(wireless, "wlan0").unwrap(),
+            WifiQuality(80)
+        );
+        assert_eq!(
+            WifiQuality::parse(wireless, "eth0").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_clock_time_unset() {
+        assert_eq!(clock_time(UNIX_EPOCH), None);
+    }
+
+    #[test]
+    fn test_metric_unknown() {
+        assert_eq!(Metric::<Battery>(None).to_string(), "unknown");
+        assert_eq!(Metric(Some(Battery(5))).to_string(), "5%");
+    }
+}
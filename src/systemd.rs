@@ -0,0 +1,169 @@
+//! Telling systemd when the badge is ready and still working, and taking a listening socket from it
+//!
+//! See sd_notify(3), sd_watchdog_enabled(3), and sd_listen_fds(3).
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+/// The first file descriptor passed by socket activation
+const LISTEN_FDS_START: i32 = 3;
+
+/// Send `state` to the service manager. Does nothing when not started by systemd with
+/// `Type=notify`.
+pub fn notify(state: &str) -> io::Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let path = path.to_string_lossy();
+
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path.as_ref())?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+
+    Ok(())
+}
+
+/// How often the service manager wants to hear that all is well, if it's watching
+pub fn watchdog_interval() -> Option<Duration> {
+    if !for_this_process("WATCHDOG_PID") {
+        return None;
+    }
+    env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .filter(|&usec| usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// The listening socket passed by socket activation, if there is one
+pub fn listener() -> io::Result<Option<TcpListener>> {
+    if !for_this_process("LISTEN_PID") {
+        return Ok(None);
+    }
+    match env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<i32>().ok()) {
+        None | Some(0) => Ok(None),
+        Some(1) => {
+            debug!("Using the socket passed by systemd");
+            Ok(Some(unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) }))
+        }
+        Some(fds) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("expected one socket from systemd, got {}", fds),
+        )),
+    }
+}
+
+/// Whether the variable `name` is unset or set to the PID of this process. It's passed on to child
+/// processes along with the rest of the environment.
+fn for_this_process(name: &str) -> bool {
+    match env::var(name) {
+        Ok(pid) => pid.parse() == Ok(process::id()),
+        Err(_) => true,
+    }
+}
+
+/// Pings the service manager's watchdog as long as every part of the badge is making progress
+pub struct Watchdog {
+    interval: Duration,
+    /// When each part last reported in
+    beats: Mutex<BTreeMap<&'static str, Instant>>,
+}
+
+impl Watchdog {
+    /// Watch `parts`, which must each call `beat` more often than `interval`
+    pub fn new(interval: Duration, parts: &[&'static str]) -> Self {
+        let now = Instant::now();
+        Watchdog {
+            interval,
+            beats: Mutex::new(parts.iter().map(|&part| (part, now)).collect()),
+        }
+    }
+
+    /// Report that `part` is still working
+    pub fn beat(&self, part: &'static str) {
+        self.beats
+            .lock()
+            .expect("poisioned")
+            .insert(part, Instant::now());
+    }
+
+    /// Parts that haven't reported in within the interval
+    fn late(&self, now: Instant) -> Vec<&'static str> {
+        self.beats
+            .lock()
+            .expect("poisioned")
+            .iter()
+            .filter(|&(_, &beat)| now.saturating_duration_since(beat) > self.interval)
+            .map(|(&part, _)| part)
+            .collect()
+    }
+
+    /// Ping the watchdog at half the interval, systemd's recommendation. Stops pinging when a part
+    /// is late, so that systemd restarts the badge.
+    pub fn run(&self) {
+        loop {
+            thread::sleep(self.interval / 2);
+
+            let late = self.late(Instant::now());
+            if !late.is_empty() {
+                warn!("Not pinging the watchdog, waiting on {}", late.join(", "));
+                continue;
+            }
+            if let Err(err) = notify("WATCHDOG=1") {
+                warn!("unable to ping the watchdog: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_late() {
+        let watchdog = Watchdog::new(Duration::from_secs(60), &["display", "http"]);
+        let start = Instant::now();
+        assert!(watchdog.late(start).is_empty());
+
+        watchdog
+            .beats
+            .lock()
+            .unwrap()
+            .insert("http", start + Duration::from_secs(30));
+        assert_eq!(watchdog.late(start + Duration::from_secs(61)), vec!["display"]);
+    }
+
+    #[test]
+    fn test_notify() {
+        let path = env::temp_dir().join(format!("lca2019-{}-notify", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        env::set_var("NOTIFY_SOCKET", &path);
+        notify("READY=1").unwrap();
+        env::remove_var("NOTIFY_SOCKET");
+
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        let _ = std::fs::remove_file(&path);
+        // Without the socket it does nothing
+        notify("READY=1").unwrap();
+    }
+}
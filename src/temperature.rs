@@ -0,0 +1,139 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::lut::Preset;
+
+/// The SoC temperature, which runs well above the panel's so isn't used for it by default
+pub const SOC_SENSOR: &str = "/sys/class/thermal/thermal_zone0/temp";
+
+// Below this the panel needs the longer cold waveform
+const COLD_BELOW: f32 = 12.0;
+// Above this the particles are quick enough for the fast waveform
+const WARM_ABOVE: f32 = 28.0;
+
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub struct Celsius(pub f32);
+
+impl fmt::Display for Celsius {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{:.1}°C", self.0)
+    }
+}
+
+/// Reads the temperature that the display waveform is chosen for
+pub struct Compensation {
+    sensor: PathBuf,
+    offset: f32,
+    min: Celsius,
+    max: Celsius,
+}
+
+impl Compensation {
+    /// `offset` is added to the sensor reading, for sensors such as the SoC temperature that read
+    /// warmer than the panel.
+    pub fn new(sensor: PathBuf, offset: f32, min: Celsius, max: Celsius) -> Self {
+        Compensation {
+            sensor,
+            offset,
+            min,
+            max,
+        }
+    }
+
+    pub fn read(&self) -> io::Result<Celsius> {
+        read_sensor(&self.sensor).map(|Celsius(temp)| Celsius(temp + self.offset))
+    }
+
+    /// Is `temp` within the rated operating range of the panel?
+    pub fn in_range(&self, temp: Celsius) -> bool {
+        temp >= self.min && temp <= self.max
+    }
+}
+
+/// Read a temperature from a file.
+///
+/// Values are in degrees unless they are too large to be, in which case they're treated as
+/// millidegrees like the sysfs thermal zones.
+pub fn read_sensor(path: &Path) -> io::Result<Celsius> {
+    let text = fs::read_to_string(path)?;
+    let value = f32::from_str(text.trim()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid temperature in {}: '{}'", path.display(), text.trim()),
+        )
+    })?;
+
+    if value.abs() > 200. {
+        Ok(Celsius(value / 1000.))
+    } else {
+        Ok(Celsius(value))
+    }
+}
+
+/// The waveform preset that suits the panel at `temp`
+pub fn preset_for(temp: Celsius) -> Preset {
+    if temp.0 < COLD_BELOW {
+        Preset::Cold
+    } else if temp.0 > WARM_ABOVE {
+        Preset::Fast
+    } else {
+        Preset::Default
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn fake_sensor(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("lca2019-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_read_sysfs_millidegrees() {
+        let path = fake_sensor("thermal_zone0", "43312\n");
+        assert_eq!(read_sensor(&path).unwrap(), Celsius(43.312));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_degrees_with_offset() {
+        let path = fake_sensor("ambient", "21.5\n");
+        let compensation = Compensation::new(path.clone(), -1.5, Celsius(0.), Celsius(40.));
+        assert_eq!(compensation.read().unwrap(), Celsius(20.));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_invalid() {
+        let path = fake_sensor("invalid", "hot\n");
+        assert_eq!(
+            read_sensor(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_in_range() {
+        let compensation = Compensation::new(PathBuf::new(), 0., Celsius(0.), Celsius(40.));
+        assert!(compensation.in_range(Celsius(0.)));
+        assert!(compensation.in_range(Celsius(40.)));
+        assert!(!compensation.in_range(Celsius(-0.5)));
+        assert!(!compensation.in_range(Celsius(45.)));
+    }
+
+    #[test]
+    fn test_preset_for() {
+        assert_eq!(preset_for(Celsius(5.)), Preset::Cold);
+        assert_eq!(preset_for(Celsius(20.)), Preset::Default);
+        assert_eq!(preset_for(Celsius(32.)), Preset::Fast);
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::app::write_file_atomic;
+use crate::display::RefreshKind;
+
+const ONE_HOUR: Duration = Duration::from_secs(60 * 60);
+
+/// Lifetime count of display refreshes
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct RefreshCount {
+    pub full: u64,
+    pub partial: u64,
+}
+
+impl RefreshCount {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err),
+        };
+
+        let counts = contents
+            .split_whitespace()
+            .map(u64::from_str)
+            .collect::<Result<Vec<_>, _>>();
+        match counts.as_ref().map(Vec::as_slice) {
+            Ok([full, partial]) => Ok(RefreshCount {
+                full: *full,
+                partial: *partial,
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "invalid refresh counts in {}: '{}'",
+                    path.display(),
+                    contents.trim()
+                ),
+            )),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        write_file_atomic(path, format!("{} {}\n", self.full, self.partial))
+    }
+
+    pub fn record(&mut self, kind: RefreshKind) {
+        match kind {
+            RefreshKind::Full => self.full += 1,
+            RefreshKind::Partial => self.partial += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.full + self.partial
+    }
+}
+
+impl fmt::Display for RefreshCount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{} refreshes ({} full)", self.total(), self.full)
+    }
+}
+
+/// Limits how often the panel is refreshed
+#[derive(Debug)]
+pub struct RefreshBudget {
+    max_per_hour: usize,
+    min_interval: Duration,
+    recent: VecDeque<Instant>,
+}
+
+impl RefreshBudget {
+    pub fn new(max_per_hour: usize, min_interval: Duration) -> Self {
+        RefreshBudget {
+            max_per_hour,
+            min_interval,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// How long until a refresh is allowed, None if one is allowed now
+    pub fn wait(&mut self, now: Instant) -> Option<Duration> {
+        while let Some(&oldest) = self.recent.front() {
+            if now.duration_since(oldest) >= ONE_HOUR {
+                self.recent.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let cooldown = self.recent.back().and_then(|&last| {
+            self.min_interval
+                .checked_sub(now.duration_since(last))
+                .filter(|wait| *wait > Duration::from_secs(0))
+        });
+        let hourly = if self.recent.len() >= self.max_per_hour {
+            self.recent
+                .front()
+                .map(|&oldest| ONE_HOUR - now.duration_since(oldest))
+        } else {
+            None
+        };
+
+        match (cooldown, hourly) {
+            (Some(cooldown), Some(hourly)) => Some(cooldown.max(hourly)),
+            (cooldown, hourly) => cooldown.or(hourly),
+        }
+    }
+
+    pub fn record(&mut self, now: Instant) {
+        self.recent.push_back(now);
+    }
+}
+
+/// Parse a maximum number of refreshes in an hour, which must allow at least one
+pub fn parse_max_per_hour(s: &str) -> Result<usize, String> {
+    match usize::from_str(s) {
+        Ok(0) => Err("at least one refresh an hour is needed".to_string()),
+        Ok(max) => Ok(max),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_min_interval() {
+        let mut budget = RefreshBudget::new(10, Duration::from_secs(30));
+        let now = Instant::now();
+        assert_eq!(budget.wait(now), None);
+
+        budget.record(now);
+        assert_eq!(
+            budget.wait(now + Duration::from_secs(10)),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(budget.wait(now + Duration::from_secs(30)), None);
+    }
+
+    #[test]
+    fn test_max_per_hour() {
+        let mut budget = RefreshBudget::new(2, Duration::from_secs(0));
+        let now = Instant::now();
+        budget.record(now);
+        budget.record(now + Duration::from_secs(60));
+
+        let later = now + Duration::from_secs(120);
+        assert_eq!(budget.wait(later), Some(Duration::from_secs(60 * 58)));
+        assert_eq!(budget.wait(now + ONE_HOUR), None);
+    }
+
+    #[test]
+    fn test_parse_max_per_hour() {
+        assert_eq!(parse_max_per_hour("30"), Ok(30));
+        assert!(parse_max_per_hour("0").is_err());
+        assert!(parse_max_per_hour("lots").is_err());
+    }
+
+    #[test]
+    fn test_refresh_count_round_trip() {
+        let path = env::temp_dir().join(format!("lca2019-{}-refresh_count.txt", process::id()));
+        let mut count = RefreshCount::default();
+        count.record(RefreshKind::Full);
+        count.record(RefreshKind::Partial);
+        count.record(RefreshKind::Partial);
+        count.save(&path).unwrap();
+
+        let loaded = RefreshCount::load(&path).unwrap();
+        assert_eq!(loaded, count);
+        assert_eq!(loaded.to_string(), "3 refreshes (1 full)");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_refresh_count_invalid() {
+        let path = env::temp_dir().join(format!("lca2019-{}-refresh_invalid.txt", process::id()));
+        for contents in &["", "12 lots\n", "1 2 3\n"] {
+            fs::write(&path, contents).unwrap();
+            assert_eq!(
+                RefreshCount::load(&path).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,522 @@
+//! JSON webhooks for each hello and when the count crosses thresholds.
+//!
+//! Deliveries are queued in a file and retried with backoff until the target accepts them, so
+//! events survive the wifi dropping out and the badge restarting.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, SystemTime};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::app::write_file_atomic;
+use crate::display::OWNER;
+use crate::hellos::Source;
+use crate::http::{self, Url};
+use crate::system::unix_time;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Seconds before the first retry, doubled for each failed attempt
+const RETRY_DELAY: u64 = 5;
+const MAX_RETRY_DELAY: u64 = 10 * 60;
+/// Deliveries are dropped after failing for this long
+const MAX_AGE: u64 = 24 * 60 * 60;
+/// The oldest deliveries are dropped when there are more than this many queued
+const MAX_QUEUED: usize = 1000;
+
+/// A hello that was counted, sent by `State`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Hello {
+    pub source: Source,
+    pub hi_count: usize,
+}
+
+/// Where to POST events, in the form `[thresholds=]URL`. With the `thresholds=` prefix only
+/// threshold events are sent, otherwise every hello is too.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub url: Url,
+    pub hellos: bool,
+}
+
+impl FromStr for Target {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("thresholds=") {
+            Some(url) => Ok(Target {
+                url: url.parse()?,
+                hellos: false,
+            }),
+            None => Ok(Target {
+                url: s.parse()?,
+                hellos: true,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Hello,
+    Threshold,
+}
+
+/// Body of the POST
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub event: Kind,
+    pub name: String,
+    pub hi_count: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<usize>,
+    /// Seconds since the Unix epoch
+    pub time: u64,
+}
+
+/// An event waiting to be sent to a target
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Delivery {
+    url: String,
+    event: Event,
+    attempts: u32,
+    /// Seconds since the Unix epoch
+    next_attempt: u64,
+}
+
+pub struct Webhooks {
+    targets: Vec<Target>,
+    thresholds: Vec<usize>,
+    last_count: usize,
+    queue: Vec<Delivery>,
+    path: PathBuf,
+}
+
+impl Webhooks {
+    /// Load the queue left from last time from `path`. `hi_count` is the current count, so
+    /// thresholds already passed aren't sent again.
+    pub fn load(
+        path: &Path,
+        targets: Vec<Target>,
+        thresholds: Vec<usize>,
+        hi_count: usize,
+    ) -> io::Result<Self> {
+        let queue = match fs::read_to_string(path) {
+            Ok(contents) => parse_queue(&contents),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        if !queue.is_empty() {
+            info!("Loaded {} queued webhook deliveries", queue.len());
+        }
+
+        Ok(Webhooks {
+            targets,
+            thresholds,
+            last_count: hi_count,
+            queue,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// The events for `hello`: the hello itself and any thresholds it reached
+    fn events(&mut self, hello: Hello, now: u64) -> Vec<Event> {
+        let event = |kind, threshold| Event {
+            event: kind,
+            name: OWNER.to_string(),
+            hi_count: hello.hi_count,
+            source: Some(hello.source.name().to_string()),
+            threshold,
+            time: now,
+        };
+
+        let mut events = vec![event(Kind::Hello, None)];
+        let last_count = self.last_count;
+        events.extend(
+            self.thresholds
+                .iter()
+                .filter(|&&threshold| last_count < threshold && threshold <= hello.hi_count)
+                .map(|&threshold| event(Kind::Threshold, Some(threshold))),
+        );
+        self.last_count = hello.hi_count;
+
+        events
+    }
+
+    /// Queue the events for `hello` for each target that wants them
+    pub fn enqueue(&mut self, hello: Hello, now: u64) {
+        for event in self.events(hello, now) {
+            for target in &self.targets {
+                if event.event == Kind::Hello && !target.hellos {
+                    continue;
+                }
+                self.queue.push(Delivery {
+                    url: target.url.to_string(),
+                    event: event.clone(),
+                    attempts: 0,
+                    next_attempt: now,
+                });
+            }
+        }
+
+        if self.queue.len() > MAX_QUEUED {
+            let excess = self.queue.len() - MAX_QUEUED;
+            warn!("webhook queue full, dropping {} oldest deliveries", excess);
+            self.queue.drain(..excess);
+        }
+    }
+
+    /// Try to send the deliveries that are due. Returns true if the queue changed.
+    ///
+    /// Once a target can't be reached its other deliveries wait for the next retry, so a pass
+    /// with the network down doesn't wait for every delivery in the queue to time out.
+    pub fn deliver(&mut self, now: u64) -> bool {
+        let mut unreachable = HashSet::new();
+        let results = self
+            .queue
+            .iter()
+            .map(|delivery| {
+                if delivery.next_attempt > now {
+                    None
+                } else if expired(delivery, now) {
+                    Some(Attempt::Finished)
+                } else if unreachable.contains(&delivery.url) {
+                    Some(Attempt::Retry)
+                } else {
+                    let result = attempt(delivery);
+                    if result == Attempt::Unreachable {
+                        unreachable.insert(delivery.url.clone());
+                    }
+                    Some(result)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let changed = results.iter().any(Option::is_some);
+        let queue = mem::take(&mut self.queue);
+        self.queue = queue
+            .into_iter()
+            .zip(results)
+            .filter_map(|(mut delivery, result)| match result {
+                None => Some(delivery),
+                Some(Attempt::Finished) => None,
+                Some(Attempt::Retry) | Some(Attempt::Unreachable) => {
+                    delivery.attempts += 1;
+                    delivery.next_attempt = now + retry_delay(delivery.attempts);
+                    Some(delivery)
+                }
+            })
+            .collect();
+
+        changed
+    }
+
+    /// Seconds until the next delivery is due
+    fn next_due(&self, now: u64) -> Option<u64> {
+        self.queue
+            .iter()
+            .map(|delivery| delivery.next_attempt.saturating_sub(now))
+            .min()
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let mut contents = String::new();
+        for delivery in &self.queue {
+            let line = serde_json::to_string(delivery)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            contents.push_str(&line);
+            contents.push('\n');
+        }
+
+        write_file_atomic(&self.path, contents)
+    }
+
+    /// Deliver the events for hellos received on `hellos`, forever
+    pub fn run(mut self, hellos: Receiver<Hello>) {
+        loop {
+            let now = unix_time(SystemTime::now());
+            let wait = self.next_due(now).unwrap_or(60).max(1);
+            let mut changed = match hellos.recv_timeout(Duration::from_secs(wait)) {
+                Ok(hello) => {
+                    self.enqueue(hello, unix_time(SystemTime::now()));
+                    // Pick up any others that arrived at the same time
+                    while let Ok(hello) = hellos.try_recv() {
+                        self.enqueue(hello, unix_time(SystemTime::now()));
+                    }
+                    true
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            // Save before sending so nothing is lost if the badge goes flat mid-delivery
+            if changed {
+                if let Err(err) = self.save() {
+                    error!("unable to save webhook queue: {}", err);
+                }
+            }
+
+            changed = self.deliver(unix_time(SystemTime::now()));
+            if changed {
+                if let Err(err) = self.save() {
+                    error!("unable to save webhook queue: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// What became of a delivery in a pass
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Attempt {
+    /// Delivered or given up on
+    Finished,
+    Retry,
+    /// The target couldn't be reached, so its other deliveries aren't tried in this pass
+    Unreachable,
+}
+
+/// Has `delivery` been failing for too long to keep trying?
+fn expired(delivery: &Delivery, now: u64) -> bool {
+    if now.saturating_sub(delivery.event.time) > MAX_AGE {
+        warn!(
+            "giving up on webhook delivery to {} after {} attempts",
+            delivery.url, delivery.attempts
+        );
+        true
+    } else {
+        false
+    }
+}
+
+/// Try to send `delivery`
+fn attempt(delivery: &Delivery) -> Attempt {
+    let result = delivery.url.parse().and_then(|url: Url| {
+        let body = serde_json::to_vec(&delivery.event)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        http::post(&url, "application/json", &body, TIMEOUT)
+    });
+    match result {
+        Ok(ref response) if response.is_success() => Attempt::Finished,
+        // Other client errors won't go away by trying again
+        Ok(ref response)
+            if response.status >= 400
+                && response.status < 500
+                && response.status != 408
+                && response.status != 429 =>
+        {
+            warn!(
+                "webhook {} rejected {:?} event with HTTP status {}, dropping it",
+                delivery.url, delivery.event.event, response.status
+            );
+            Attempt::Finished
+        }
+        Ok(response) => {
+            warn!(
+                "webhook {} failed with HTTP status {}, will retry",
+                delivery.url, response.status
+            );
+            Attempt::Retry
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::InvalidInput => {
+            error!("invalid webhook URL {}: {}", delivery.url, err);
+            Attempt::Finished
+        }
+        Err(err) => {
+            warn!("unable to send webhook to {}: {}, will retry", delivery.url, err);
+            Attempt::Unreachable
+        }
+    }
+}
+
+/// Seconds to wait after `attempts` failed attempts
+fn retry_delay(attempts: u32) -> u64 {
+    RETRY_DELAY
+        .saturating_mul(1 << attempts.min(16).saturating_sub(1))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Lines that can't be parsed are skipped, the rest of the queue is still worth delivering
+fn parse_queue(contents: &str) -> Vec<Delivery> {
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(delivery) => Some(delivery),
+            Err(err) => {
+                warn!("skipping invalid queued webhook delivery: {}", err);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::{ok, serve};
+    use std::env;
+    use std::net::TcpListener;
+    use std::process;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn queue_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("lca2019-{}-{}", process::id(), name))
+    }
+
+    fn hello(hi_count: usize) -> Hello {
+        Hello {
+            source: Source::Web,
+            hi_count,
+        }
+    }
+
+    #[test]
+    fn test_parse_target() {
+        let target = Target::from_str("http://10.0.0.5:8080/hook").unwrap();
+        assert!(target.hellos);
+        assert_eq!(target.url.path, "/hook");
+
+        let target = Target::from_str("thresholds=http://chat.local/badge").unwrap();
+        assert!(!target.hellos);
+        assert_eq!(target.url.host, "chat.local");
+
+        assert!(Target::from_str("thresholds=chat.local").is_err());
+    }
+
+    #[test]
+    fn test_threshold_events() {
+        let targets = vec![
+            Target::from_str("http://dashboard.local/").unwrap(),
+            Target::from_str("thresholds=http://chat.local/").unwrap(),
+        ];
+        let path = queue_path("thresholds.jsonl");
+        let mut webhooks = Webhooks::load(&path, targets, vec![10, 12], 9).unwrap();
+
+        webhooks.enqueue(hello(10), 100);
+        // Counts can jump past a threshold, E.g. when hellos arrive while the thread is busy
+        webhooks.enqueue(hello(13), 101);
+        webhooks.enqueue(hello(14), 102);
+
+        let sent = webhooks
+            .queue
+            .iter()
+            .map(|delivery| {
+                (
+                    delivery.url.as_str(),
+                    delivery.event.event,
+                    delivery.event.hi_count,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sent,
+            vec![
+                ("http://dashboard.local:80/", Kind::Hello, 10),
+                ("http://dashboard.local:80/", Kind::Threshold, 10),
+                ("http://chat.local:80/", Kind::Threshold, 10),
+                ("http://dashboard.local:80/", Kind::Hello, 13),
+                ("http://dashboard.local:80/", Kind::Threshold, 13),
+                ("http://chat.local:80/", Kind::Threshold, 13),
+                ("http://dashboard.local:80/", Kind::Hello, 14),
+            ]
+        );
+        assert_eq!(webhooks.queue[5].event.threshold, Some(12));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), 5);
+        assert_eq!(retry_delay(2), 10);
+        assert_eq!(retry_delay(5), 80);
+        assert_eq!(retry_delay(100), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_delivery_is_retried_and_persisted() {
+        let (url, requests) = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_string(),
+            ok(""),
+        ]);
+        let target = Target::from_str(&format!("{}hook", url)).unwrap();
+        let path = queue_path("webhook_queue.jsonl");
+        let _ = fs::remove_file(&path);
+
+        let mut webhooks = Webhooks::load(&path, vec![target.clone()], vec![], 0).unwrap();
+        webhooks.enqueue(hello(1), 1000);
+        assert!(webhooks.deliver(1000));
+        assert_eq!(webhooks.queued(), 1);
+        assert_eq!(webhooks.next_due(1000), Some(RETRY_DELAY));
+        webhooks.save().unwrap();
+
+        let received = requests.recv().unwrap();
+        assert_eq!(received.request_line, "POST /hook HTTP/1.1");
+        let event: Event = serde_json::from_slice(&received.body).unwrap();
+        assert_eq!(event.event, Kind::Hello);
+        assert_eq!(event.hi_count, 1);
+        assert_eq!(event.source, Some("web".to_string()));
+
+        // Pick up where we left off after a restart
+        let mut webhooks = Webhooks::load(&path, vec![target], vec![], 1).unwrap();
+        assert_eq!(webhooks.queued(), 1);
+        // Not due yet
+        assert!(!webhooks.deliver(1001));
+        assert!(webhooks.deliver(1000 + RETRY_DELAY));
+        assert_eq!(webhooks.queued(), 0);
+        assert_eq!(requests.recv().unwrap().body, received.body);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_unreachable_target_is_tried_once_a_pass() {
+        // Accepts connections and closes them without a response, counting them
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unreachable = format!("http://{}/", listener.local_addr().unwrap());
+        let (connected, connections) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                drop(stream);
+                let _ = connected.send(());
+            }
+        });
+        let (reachable, requests) = serve(vec![ok(""), ok("")]);
+
+        let targets = vec![
+            Target::from_str(&unreachable).unwrap(),
+            Target::from_str(&reachable).unwrap(),
+        ];
+        let path = queue_path("unreachable_queue.jsonl");
+        let mut webhooks = Webhooks::load(&path, targets, vec![], 0).unwrap();
+        webhooks.enqueue(hello(1), 1000);
+        webhooks.enqueue(hello(2), 1000);
+        assert_eq!(webhooks.queued(), 4);
+
+        // Both hellos reach the other target, but the second isn't tried on the unreachable one
+        assert!(webhooks.deliver(1000));
+        assert_eq!(requests.iter().take(2).count(), 2);
+        connections.recv().unwrap();
+        assert!(connections
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+        assert_eq!(webhooks.queued(), 2);
+        assert!(webhooks.queue.iter().all(|delivery| {
+            delivery.url == unreachable
+                && delivery.attempts == 1
+                && delivery.next_attempt == 1000 + RETRY_DELAY
+        }));
+    }
+}
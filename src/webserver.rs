@@ -1,43 +1,136 @@
 use askama::Template;
 use futures::{future, Future, Stream};
 use hyper::{header, Body, Method, Request, Response, StatusCode};
+use log::info;
 use memmem::{Searcher, TwoWaySearcher};
 use nix::sys::utsname::UtsName;
+use serde_json::json;
 use std::fmt;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, RwLock};
+use std::time::{Instant, SystemTime};
 use systemstat::Memory;
 
+use crate::activity::{self, Bar};
 use crate::app::State;
-use crate::system::Uptime;
+use crate::display::OWNER;
+use crate::hellos::{HelloCounts, Source, SourceCount};
+use crate::leaderboard::{Count, Standing};
+use crate::logging;
+use crate::metrics::{self, Metrics, Snapshot};
+use crate::peers::Peer;
+use crate::system::{
+    self, Battery, DiskUsage, LoadAverage, Metric, NetworkTraffic, Uptime, WifiQuality,
+};
+use crate::temperature::Celsius;
+use crate::wear::RefreshCount;
 
 static NOT_FOUND: &[u8] = b"Not found\n";
+static FORBIDDEN: &[u8] = b"Forbidden\n";
+static BAD_REQUEST: &[u8] = b"Bad request\n";
+
+/// Widest bar of the histogram on the text page
+const HISTOGRAM_WIDTH: usize = 40;
+/// Size of the bars of the sparkline on the HTML page, the SVG in hi.html is sized to fit
+const SPARKLINE_BAR_WIDTH: usize = 8;
+const SPARKLINE_HEIGHT: usize = 40;
 
 #[derive(Template)]
 #[template(path = "hi.txt")]
 pub struct HelloTextTemplate<'a> {
     hi_count: usize,
+    hello_counts: &'a HelloCounts,
+    /// Hellos by hour of the day
+    histogram: Vec<String>,
+    /// Hellos in each of the last 24 hours, blank if there weren't any
+    sparkline: String,
     ip: &'a str,
     os_name: &'a str,
     uname: &'a UtsName,
     memory: &'a Option<Memory>,
     uptime: &'a Uptime,
+    refreshes: &'a RefreshCount,
+    soc_temperature: Metric<&'a Celsius>,
+    load: Metric<&'a LoadAverage>,
+    disk: Metric<&'a DiskUsage>,
+    network: Metric<&'a NetworkTraffic>,
+    wifi: Metric<&'a WifiQuality>,
+    battery: Metric<&'a Battery>,
 }
 
 #[derive(Template)]
 #[template(path = "hi.html")]
 pub struct HelloHtmlTemplate<'a> {
     hi_count: usize,
+    sources: Vec<SourceCount>,
+    /// Hellos in each of the last 24 hours, empty if there weren't any
+    activity: Vec<Bar>,
+    peers: &'a [Peer],
     os_name: &'a str,
     uname: &'a UtsName,
     memory: &'a Option<Memory>,
     uptime: &'a Uptime,
+    refreshes: &'a RefreshCount,
+    soc_temperature: Metric<&'a Celsius>,
+    load: Metric<&'a LoadAverage>,
+    disk: Metric<&'a DiskUsage>,
+    network: Metric<&'a NetworkTraffic>,
+    wifi: Metric<&'a WifiQuality>,
+    battery: Metric<&'a Battery>,
+}
+
+#[derive(Template)]
+#[template(path = "leaderboard.txt")]
+pub struct LeaderboardTextTemplate<'a> {
+    standings: &'a [Standing],
+}
+
+#[derive(Template)]
+#[template(path = "leaderboard.html")]
+pub struct LeaderboardHtmlTemplate<'a> {
+    standings: &'a [Standing],
 }
 
 pub fn handle_request(
     state: Arc<RwLock<State>>,
+    metrics: Arc<Metrics>,
     remote_addr: SocketAddr,
     req: Request<Body>,
+) -> Box<Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+    let start = Instant::now();
+    let route = metrics::route(req.uri().path());
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let client = client_ip(remote_addr);
+
+    Box::new(
+        respond(state, &metrics, client, req).map(move |response| {
+            let status = response.status().as_u16();
+            let elapsed = start.elapsed();
+            metrics.observe_request(route, status, elapsed);
+            logging::access(client, method.as_str(), &path, status, elapsed);
+            response
+        }),
+    )
+}
+
+/// The address of the client. On a dual-stack socket IPv4 clients arrive as IPv4-mapped IPv6
+/// addresses, these are turned back into IPv4 so they're the same client however they connected.
+fn client_ip(remote_addr: SocketAddr) -> IpAddr {
+    match remote_addr.ip() {
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(ip)),
+        ip => ip,
+    }
+}
+
+fn respond(
+    state: Arc<RwLock<State>>,
+    metrics: &Metrics,
+    client: IpAddr,
+    req: Request<Body>,
 ) -> Box<Future<Item = Response<Body>, Error = hyper::Error> + Send> {
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/")
@@ -45,20 +138,30 @@ pub fn handle_request(
         | (&Method::HEAD, "/")
         | (&Method::HEAD, "/hi") => {
             let state = state.read().expect("poisioned"); // FIXME: Deal with this
+            let buckets = state.activity_buckets();
+            let any_activity = buckets.iter().any(|&count| count > 0);
 
-            // See if the user agent supports html
-            let accepts_html = req
-                .headers()
-                .get(header::ACCEPT)
-                .and_then(|accept| TwoWaySearcher::new(b"text/html").search_in(accept.as_bytes()))
-                .is_some();
-            let response_data = if accepts_html {
+            let response_data = if accepts_html(&req) {
                 let template = HelloHtmlTemplate {
                     hi_count: state.hi_count(),
+                    sources: state.hello_counts().by_source(),
+                    activity: if any_activity {
+                        activity::bars(&buckets, SPARKLINE_BAR_WIDTH, SPARKLINE_HEIGHT)
+                    } else {
+                        Vec::new()
+                    },
+                    peers: state.peers.list(),
                     memory: &state.memory,
                     uptime: &state.uptime,
                     os_name: &state.os_name,
                     uname: &state.uname,
+                    refreshes: &state.refreshes,
+                    soc_temperature: Metric(state.soc_temperature.as_ref()),
+                    load: Metric(state.load.as_ref()),
+                    disk: Metric(state.disk.as_ref()),
+                    network: Metric(state.network.as_ref()),
+                    wifi: Metric(state.wifi.as_ref()),
+                    battery: Metric(state.battery.as_ref()),
                 };
 
                 template
@@ -68,17 +171,33 @@ pub fn handle_request(
             } else {
                 // FIXME: Don't do this everytime
                 let ip_string = state
-                    .ip
-                    .map(|ip| ip.to_string())
+                    .host(false)
+                    .map(|host| host.to_string())
                     .unwrap_or_else(|| "?.?.?.?".to_string());
 
                 let template = HelloTextTemplate {
                     hi_count: state.hi_count(),
+                    hello_counts: state.hello_counts(),
+                    histogram: state
+                        .history
+                        .histogram(system::utc_offset(SystemTime::now()), HISTOGRAM_WIDTH),
+                    sparkline: if any_activity {
+                        activity::sparkline(&buckets)
+                    } else {
+                        String::new()
+                    },
                     ip: &ip_string,
                     memory: &state.memory,
                     uptime: &state.uptime,
                     os_name: &state.os_name,
                     uname: &state.uname,
+                    refreshes: &state.refreshes,
+                    soc_temperature: Metric(state.soc_temperature.as_ref()),
+                    load: Metric(state.load.as_ref()),
+                    disk: Metric(state.disk.as_ref()),
+                    network: Metric(state.network.as_ref()),
+                    wifi: Metric(state.wifi.as_ref()),
+                    battery: Metric(state.battery.as_ref()),
                 };
 
                 template
@@ -90,7 +209,88 @@ pub fn handle_request(
             // FIXME: Set HTTP status
             Box::new(future::ok(Response::new(response_data.into())))
         }
+        (&Method::GET, "/count") => Box::new(future::ok(count_response(
+            state.read().expect("poisioned").hi_count(),
+        ))),
+        // Only from the badge itself, for `lca2019 count set`
+        (&Method::PUT, "/count") if !client.is_loopback() => {
+            Box::new(future::ok(forbidden()))
+        }
+        (&Method::PUT, "/count") => Box::new(req.into_body().concat2().map(move |body| {
+            let hi_count = match std::str::from_utf8(&body)
+                .ok()
+                .and_then(|body| body.trim().parse().ok())
+            {
+                Some(hi_count) => hi_count,
+                None => return bad_request(),
+            };
+
+            state
+                .write()
+                .expect("poisioned")
+                .set_hello_counts(HelloCounts::with_total(hi_count));
+            info!("Hi count set to {}", hi_count);
+            count_response(hi_count)
+        })),
+        // Only from the badge itself, as it has the address of everyone who said hello
+        (&Method::GET, "/api/hellos") if !client.is_loopback() => {
+            Box::new(future::ok(forbidden()))
+        }
+        (&Method::GET, "/api/hellos") => {
+            let body = {
+                let state = state.read().expect("poisioned");
+                let summary = state.history.summary(system::utc_offset(SystemTime::now()));
+                json!({ "summary": summary, "hellos": state.history.records() })
+            };
+
+            Box::new(future::ok(
+                Response::builder()
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(body.to_string().into())
+                    .unwrap(),
+            ))
+        }
+        (&Method::GET, "/leaderboard") => {
+            let standings = {
+                let state = state.read().expect("poisioned");
+                state.leaderboard.standings(state.hi_count(), Instant::now())
+            };
+
+            let response_data = if accepts_html(&req) {
+                LeaderboardHtmlTemplate {
+                    standings: &standings,
+                }
+                .render()
+            } else {
+                LeaderboardTextTemplate {
+                    standings: &standings,
+                }
+                .render()
+            };
+            let response_data = response_data
+                .ok()
+                .unwrap_or_else(|| "Internal Server Error\n".to_string());
+
+            Box::new(future::ok(Response::new(response_data.into())))
+        }
+        (&Method::GET, "/metrics") => {
+            // Only hold the lock long enough to copy what's needed
+            let snapshot = Snapshot::new(&state.read().expect("poisioned"));
+            let body = metrics.render(&snapshot);
+
+            Box::new(future::ok(
+                Response::builder()
+                    .header(header::CONTENT_TYPE, metrics::CONTENT_TYPE)
+                    .body(body.into())
+                    .unwrap(),
+            ))
+        }
         (&Method::POST, "/hi") => {
+            let source = match hello_source(&req) {
+                Some(source) => source,
+                None => return Box::new(future::ok(bad_request())),
+            };
+
             Box::new(
                 req.into_body()
                     .fold((), |_, _chunk| future::ok::<_, hyper::Error>(()))
@@ -98,7 +298,7 @@ pub fn handle_request(
                         // Increment the hi count
                         let body = {
                             let mut state = state.write().expect("poisioned");
-                            state.inc_hi_count(remote_addr.ip());
+                            state.inc_hi_count(client, source);
 
                             format!(
                                 "Hello! You're the {} person to say hi.\n",
@@ -118,6 +318,81 @@ pub fn handle_request(
     }
 }
 
+/// The JSON that other badges fetch for the leaderboard
+fn count_response(hi_count: usize) -> Response<Body> {
+    let count = Count {
+        name: OWNER.to_string(),
+        hi_count,
+    };
+    let body = serde_json::to_string(&count).expect("unable to serialise count");
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.into())
+        .unwrap()
+}
+
+fn forbidden() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(FORBIDDEN.into())
+        .unwrap()
+}
+
+fn bad_request() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(BAD_REQUEST.into())
+        .unwrap()
+}
+
+/// Does the user agent support html?
+fn accepts_html(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| TwoWaySearcher::new(b"text/html").search_in(accept.as_bytes()))
+        .is_some()
+}
+
+/// Work out where a hello came from. An explicit `source` query parameter is used if present,
+/// otherwise form submissions are from the web page and everything else is from the API.
+///
+/// None if the request claims to be in person, only the buttons can say hello in person.
+fn hello_source(req: &Request<Body>) -> Option<Source> {
+    let requested = req.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some("source"), Some(value)) => value.parse().ok(),
+                    _ => None,
+                }
+            })
+            .next()
+    });
+    match requested {
+        Some(Source::InPerson) => return None,
+        Some(source) => return Some(source),
+        None => {}
+    }
+
+    let is_form = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|content_type| {
+            content_type
+                .as_bytes()
+                .starts_with(b"application/x-www-form-urlencoded")
+        })
+        .unwrap_or(false);
+    if is_form {
+        Some(Source::Web)
+    } else {
+        Some(Source::Api)
+    }
+}
+
 struct Ordinal(usize);
 
 impl fmt::Display for Ordinal {
@@ -155,6 +430,77 @@ impl<I: std::cmp::PartialOrd> Includes for std::ops::RangeInclusive<I> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::Config;
+
+    fn post_hi(uri: &str, content_type: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder();
+        builder.method(Method::POST).uri(uri);
+        if let Some(content_type) = content_type {
+            builder.header(header::CONTENT_TYPE, content_type);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_hello_source() {
+        let form = post_hi("/hi", Some("application/x-www-form-urlencoded"));
+        assert_eq!(hello_source(&form), Some(Source::Web));
+        assert_eq!(hello_source(&post_hi("/hi", None)), Some(Source::Api));
+        assert_eq!(
+            hello_source(&post_hi("/hi?source=script", None)),
+            Some(Source::Script)
+        );
+        assert_eq!(
+            hello_source(&post_hi("/hi?a=b&source=in-person", None)),
+            None
+        );
+        assert_eq!(
+            hello_source(&post_hi("/hi?source=nope", None)),
+            Some(Source::Api)
+        );
+    }
+
+    #[test]
+    fn test_admin_from_dual_stack_socket() {
+        let state = Arc::new(RwLock::new(State::new(
+            HelloCounts::default(),
+            Config::default(),
+            None,
+        )));
+        let metrics = Metrics::new();
+        let request = |method: Method, uri: &str, body: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let status = |client: IpAddr, request: Request<Body>| {
+            respond(state.clone(), &metrics, client, request)
+                .wait()
+                .unwrap()
+                .status()
+        };
+
+        // `lca2019 count` connecting to the systemd socket, which listens on [::]
+        let local = "[::ffff:127.0.0.1]:41000".parse().unwrap();
+        assert_eq!(client_ip(local), IpAddr::from([127, 0, 0, 1]));
+        let remote = client_ip("[::ffff:10.0.0.5]:41000".parse().unwrap());
+        assert_eq!(remote, IpAddr::from([10, 0, 0, 5]));
+
+        let local = client_ip(local);
+        assert_eq!(status(local, request(Method::PUT, "/count", "5")), StatusCode::OK);
+        assert_eq!(state.read().unwrap().hi_count(), 5);
+        assert_eq!(status(local, request(Method::GET, "/api/hellos", "")), StatusCode::OK);
+        assert_eq!(
+            status(remote, request(Method::PUT, "/count", "6")),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(remote, request(Method::GET, "/api/hellos", "")),
+            StatusCode::FORBIDDEN
+        );
+    }
 
     #[test]
     fn test_ordinal() {